@@ -1,16 +1,15 @@
-use std::f32::consts::PI;
-
-use crate::cart::Cart;
+use crate::blip::BlipBuf;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+use crate::vgm::VgmLog;
 
 // https://www.nesdev.org/wiki/Cycle_reference_chart
 //
-// PPU clock speed = 21.477272 MHz ÷ 4
-//
-// This is roughly 3x the CPU clock speed.
-const NTSC_PPU_CLOCK_FREQ: f32 = (21.477272 / 4.0) * 1_000_000.0;
-const NTSC_CPU_CLOCK_FREQ: f32 = (21.477272 / 12.0) * 1_000_000.0;
+// CPU clock speed = 21.477272 MHz ÷ 12
+const NTSC_CPU_CLOCK_FREQ: f64 = (21.477272 / 12.0) * 1_000_000.0;
 
-const TIME_PER_PPU_CLOCK: f32 = 1.0 / NTSC_PPU_CLOCK_FREQ;
+/// The most `adjust_rate` will stretch or squeeze the output by. Half a percent
+/// is about a twelfth of a semitone, which nobody's going to notice.
+const MAX_RATE_DELTA: f64 = 0.005;
 
 /// The audio processing unit.
 ///
@@ -27,22 +26,41 @@ pub struct Apu {
   // the same thing for others (esp. noise which has the same problem):
   dmc_sequencer: Sequencer,
 
-  time_until_next_sample: f32,
-  sample_clock: f32,
   clock_counter: u32,
   frame_clock_counter: u32,
   five_step_mode: bool,
+  frame_interrupt_inhibit: bool,
   frame_interrupt_flag: bool,
   frame_counter_reset_timer: u8,
-  global_clock: f64,
+
+  /// Turns the mixed output, which changes at CPU rate, into samples at the
+  /// host's rate
+  blip: BlipBuf,
+  /// The mixed output as of the last change we told `blip` about
+  level: f32,
+
+  /// The analog filters the console runs its mixed output through
+  filter_profile: FilterProfile,
+  filters: Vec<Filter>,
+
+  /// What the cart's sound chip is putting out, if it has one; see
+  /// `Mapper::clock_audio`
+  expansion: Option<ExpansionAudio>,
+
+  /// Per-channel mute/solo/volume, indexed by `Channel`
+  channel_mix: [ChannelMix; 6],
+  stems: Option<Box<Stems>>,
+
+  /// The last value written to each of $4000-$4017, so a VGM log that starts
+  /// mid-song can bring its player up to speed
+  last_writes: [u8; 0x18],
+  vgm_log: Option<VgmLog>,
 
   system_sample_rate: f32,
-  time_per_sample: f32,
 }
 
 impl Apu {
   pub fn new(system_sample_rate: f32) -> Self {
-    let time_per_sample = 1.0 / system_sample_rate;
     Apu {
       pulse: [Pulse::new(), Pulse::new()],
       triangle: Triangle::new(),
@@ -51,19 +69,28 @@ impl Apu {
       dmc_sequencer: Sequencer::new(),
       sample_ready: false,
 
-      time_until_next_sample: time_per_sample,
-      sample_clock: 0.0,
-
       clock_counter: 0,
       frame_clock_counter: 0,
       five_step_mode: false,
+      frame_interrupt_inhibit: false,
       frame_interrupt_flag: false,
       frame_counter_reset_timer: 0,
 
-      global_clock: 0.0,
+      blip: BlipBuf::new(NTSC_CPU_CLOCK_FREQ, system_sample_rate as f64),
+      level: 0.0,
+
+      filter_profile: FilterProfile::Nes,
+      filters: FilterProfile::Nes.filters(system_sample_rate),
+
+      expansion: None,
+
+      channel_mix: [ChannelMix::default(); 6],
+      stems: None,
+
+      last_writes: [0x00; 0x18],
+      vgm_log: None,
 
       system_sample_rate,
-      time_per_sample,
     }
   }
 
@@ -72,19 +99,193 @@ impl Apu {
       panic!("No sample ready!");
     }
 
-    self.sample_ready = false;
+    let mut sample = self.blip.read_sample().unwrap_or(self.level);
+    self.sample_ready = self.blip.samples_avail() > 0;
+
+    for filter in self.filters.iter_mut() {
+      sample = filter.process(sample);
+    }
 
-    let mut sample: f32 = 0.0;
-    for i in 0..self.pulse.len() {
-      sample += self.pulse[i].sample * 0.45;
+    if let Some(stems) = &mut self.stems {
+      stems.read_samples();
     }
 
-    sample += self.triangle.sample * 0.35;
-    sample += self.noise.sample * 0.15;
-    sample += self.dmc.sample * 0.5;
     sample
   }
 
+  /// Starts or stops producing stems: each channel's output on its own,
+  /// alongside the mix.
+  pub fn set_stems_enabled(&mut self, enabled: bool) {
+    self.stems = if enabled {
+      Some(Box::new(Stems::new(
+        &self.blip,
+        self.filter_profile,
+        self.system_sample_rate,
+      )))
+    } else {
+      None
+    };
+  }
+
+  /// Each channel's sample, in `Channel` order, to go with the last one
+  /// `sample` returned; if stems are enabled.
+  ///
+  /// Stems leave out mute, solo and volume; they're each channel just as the
+  /// console would play it by itself, filters and all.
+  pub fn stem_sample(&self) -> Option<[f32; 6]> {
+    self.stems.as_ref().map(|stems| stems.samples)
+  }
+
+  /// Makes samples come out a hair faster or slower, to keep the host's audio
+  /// buffer from running dry or overflowing without dropping or repeating
+  /// anything.
+  ///
+  /// The emulator runs at the console's frame rate and the sound card at its
+  /// own rate, and their clocks never quite agree; `buffer_fill`, from 0.0
+  /// (empty) to 1.0 (full), says which way things are drifting. We steer it
+  /// towards half full, by up to `MAX_RATE_DELTA`. This is byuu's "dynamic
+  /// rate control".
+  pub fn adjust_rate(&mut self, buffer_fill: f32) {
+    let adjustment = 1.0 + (1.0 - 2.0 * buffer_fill.clamp(0.0, 1.0) as f64) * MAX_RATE_DELTA;
+    let rate = self.system_sample_rate as f64 * adjustment;
+    self.blip.set_rates(NTSC_CPU_CLOCK_FREQ, rate);
+    if let Some(stems) = &mut self.stems {
+      for blip in stems.blips.iter_mut() {
+        blip.set_rates(NTSC_CPU_CLOCK_FREQ, rate);
+      }
+    }
+  }
+
+  pub fn sample_rate(&self) -> f32 {
+    self.system_sample_rate
+  }
+
+  pub fn filter_profile(&self) -> FilterProfile {
+    self.filter_profile
+  }
+
+  /// Switches to a different set of output filters. The new filters start out
+  /// settled at nothing, so expect a click.
+  pub fn set_filter_profile(&mut self, profile: FilterProfile) {
+    self.filter_profile = profile;
+    self.filters = profile.filters(self.system_sample_rate);
+    if let Some(stems) = &mut self.stems {
+      for filters in stems.filters.iter_mut() {
+        *filters = profile.filters(self.system_sample_rate);
+      }
+    }
+  }
+
+  pub fn channel_mix(&self, channel: Channel) -> ChannelMix {
+    self.channel_mix[channel as usize]
+  }
+
+  /// Mutes, solos or sets the volume of a channel. Takes effect from the next
+  /// CPU cycle on, so it's fine to fiddle with while playing.
+  pub fn set_channel_mix(&mut self, channel: Channel, mix: ChannelMix) {
+    self.channel_mix[channel as usize] = mix;
+  }
+
+  /// How much of a channel's DAC level makes it into the mixer. Soloing any
+  /// channel silences every channel that isn't soloed.
+  fn channel_gain(&self, channel: Channel) -> f32 {
+    let mix = self.channel_mix[channel as usize];
+    let any_solo = self.channel_mix.iter().any(|m| m.solo);
+    if (any_solo && !mix.solo) || (!any_solo && mix.muted) {
+      0.0
+    } else {
+      mix.volume
+    }
+  }
+
+  /// Takes the cart's sound chip output for this CPU cycle, to mix in with
+  /// the APU's own channels.
+  pub fn set_expansion_audio(&mut self, expansion: Option<ExpansionAudio>) {
+    self.expansion = expansion;
+  }
+
+  /// Mixes every channel's current output into one level, from 0.0 to about
+  /// 1.0, or more with expansion audio.
+  ///
+  /// https://www.nesdev.org/wiki/APU_Mixer
+  ///
+  /// The channels' DACs aren't linear, and they aren't independent of each
+  /// other either: the pulses share one, and the triangle, noise and DMC share
+  /// another. So e.g. a loud DMC sample audibly ducks the triangle, which some
+  /// games' sound engines count on. Expansion audio comes back in through the
+  /// cart connector, after all that, so it just adds on.
+  fn mix(&self) -> f32 {
+    pulse_out(
+      self.pulse[0].get_sample() * self.channel_gain(Channel::Pulse1)
+        + self.pulse[1].get_sample() * self.channel_gain(Channel::Pulse2),
+    ) + tnd_out(
+      self.triangle.get_sample() * self.channel_gain(Channel::Triangle),
+      self.noise.get_sample() * self.channel_gain(Channel::Noise),
+      self.dmc.get_sample() * self.channel_gain(Channel::Dmc),
+    ) + self.expansion_out() * self.channel_gain(Channel::Expansion)
+  }
+
+  /// The cart's sound chip output, scaled to sit at the right level next to
+  /// the APU's channels.
+  fn expansion_out(&self) -> f32 {
+    match self.expansion {
+      Some(expansion) => expansion.level * expansion.chip.full_scale() * pulse_out(15.0),
+      None => 0.0,
+    }
+  }
+
+  /// What a channel would sound like if it were the only one playing.
+  fn channel_alone(&self, channel: Channel) -> f32 {
+    match channel {
+      Channel::Pulse1 => pulse_out(self.pulse[0].get_sample()),
+      Channel::Pulse2 => pulse_out(self.pulse[1].get_sample()),
+      Channel::Triangle => tnd_out(self.triangle.get_sample(), 0.0, 0.0),
+      Channel::Noise => tnd_out(0.0, self.noise.get_sample(), 0.0),
+      Channel::Dmc => tnd_out(0.0, 0.0, self.dmc.get_sample()),
+      Channel::Expansion => self.expansion_out(),
+    }
+  }
+
+  /// Starts logging every register write to `log`, along with whatever the
+  /// registers were last set to, since the player won't have seen that.
+  pub fn start_vgm_log(&mut self, mut log: VgmLog) {
+    // Enable the channels first, or writing the length counters won't stick:
+    log.write_register(0x4015, self.last_writes[0x15]);
+    for addr in (0x4000..=0x4013).chain([0x4017]) {
+      log.write_register(addr, self.last_writes[(addr - 0x4000) as usize]);
+    }
+    self.vgm_log = Some(log);
+  }
+
+  /// Logs a write to one of the cart's sound chip's registers, if there's a
+  /// log going; the chip itself gets the write through the mapper.
+  pub fn log_expansion_write(&mut self, chip: ExpansionChip, register: u8, data: u8) {
+    if let Some(log) = &mut self.vgm_log {
+      log.write_expansion_register(chip, register, data);
+    }
+  }
+
+  pub fn take_vgm_log(&mut self) -> Option<VgmLog> {
+    self.vgm_log.take()
+  }
+
+  pub fn is_vgm_logging(&self) -> bool {
+    self.vgm_log.is_some()
+  }
+
+  /// Hands the DMC the sample byte it asked for through `Dmc::dma_request`.
+  pub fn dmc_dma_load(&mut self, addr: u16, data: u8) {
+    if let Some(log) = &mut self.vgm_log {
+      log.write_memory(addr, &[data]);
+    }
+    self.dmc.dma_load(data);
+  }
+
+  /// Whether the frame counter or the DMC is holding the CPU's IRQ line.
+  pub fn irq_active(&self) -> bool {
+    self.frame_interrupt_flag || self.dmc.interrupt_flag
+  }
+
   pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
     if addr == 0x4015 {
       let mut data: u8 = 0x00;
@@ -152,6 +353,11 @@ impl Apu {
 
   pub fn cpu_write(&mut self, addr: u16, data: u8) -> Option<()> {
     if (addr >= 0x4000 && addr <= 0x4013) || addr == 0x4015 || addr == 0x4017 {
+      self.last_writes[(addr - 0x4000) as usize] = data;
+      if let Some(log) = &mut self.vgm_log {
+        log.write_register(addr, data);
+      }
+
       // Technically as-is this will break controller input since 4017 conflicts
       // with controller's 4017... that's okay because this should just be
       // temporary.
@@ -205,7 +411,8 @@ impl Apu {
           self.five_step_mode = (data & 0b1000_0000) != 0;
           // Interrupt inhibit flag. If set, the frame interrupt flag is
           // cleared, otherwise it is unaffected.
-          if (data & 0b1000_0000) != 0 {
+          self.frame_interrupt_inhibit = (data & 0b0100_0000) != 0;
+          if self.frame_interrupt_inhibit {
             self.frame_interrupt_flag = false;
           }
 
@@ -230,26 +437,9 @@ impl Apu {
         0x4000 | 0x4004 => {
           let i = if addr == 0x4000 { 0 } else { 1 };
 
-          // Duty Cycle
-          match (data & 0b1100_0000) >> 6 {
-            0x00 => {
-              self.pulse[i].sequencer.sequence = 0b0000_0001;
-              self.pulse[i].osc.duty_cycle = 1.0 / 8.0;
-            }
-            0x01 => {
-              self.pulse[i].sequencer.sequence = 0b0000_0011;
-              self.pulse[i].osc.duty_cycle = 2.0 / 8.0;
-            }
-            0x02 => {
-              self.pulse[i].sequencer.sequence = 0b0000_1111;
-              self.pulse[i].osc.duty_cycle = 4.0 / 8.0;
-            }
-            0x03 => {
-              self.pulse[i].sequencer.sequence = 0b1111_1100;
-              self.pulse[i].osc.duty_cycle = 6.0 / 8.0;
-            }
-            _ => {}
-          };
+          // Duty Cycle; this doesn't move the sequencer, so changing it
+          // mid-note doesn't click:
+          self.pulse[i].duty = (data & 0b1100_0000) >> 6;
 
           // Constant Volume flag
           self.pulse[i].envelope.constant_volume_flag = (data & 0b0001_0000) != 0;
@@ -295,7 +485,8 @@ impl Apu {
           self.pulse[i].sequencer.reload =
             (((data as u16) & 0x07) << 8) | (self.pulse[i].sequencer.reload & 0x00FF);
 
-          self.pulse[i].sequencer.timer = self.pulse[i].sequencer.reload;
+          // The sequencer restarts, but the timer doesn't:
+          self.pulse[i].sequencer.sequence = 0;
 
           // Length Counter/Envelope start flag
           //
@@ -344,8 +535,8 @@ impl Apu {
 
         0x400E => {
           self.noise.mode_flag = (0b1000_0000 & data) != 0;
-          self.noise.sequencer.reload = get_noise_sequencer_period(data & 0b0000_1111) as u16;
-          self.noise.sequencer.timer = self.noise.sequencer.reload;
+          // The table gives whole periods, in CPU cycles:
+          self.noise.sequencer.reload = get_noise_sequencer_period(data & 0b0000_1111) - 1;
         }
 
         0x400F => {
@@ -356,9 +547,13 @@ impl Apu {
         // DMC
         0x4010 => {
           self.dmc.irq_enabled_flag = (data & 0b1000_0000) != 0;
+          // Clearing the IRQ enabled flag also clears the interrupt flag:
+          if !self.dmc.irq_enabled_flag {
+            self.dmc.interrupt_flag = false;
+          }
           self.dmc.loop_flag = (data & 0b0100_0000) != 0;
-          self.dmc_sequencer.reload = get_dmc_rate(data & 0b0000_1111);
-          self.dmc_sequencer.timer = self.dmc_sequencer.reload;
+          // The table gives whole periods, in CPU cycles:
+          self.dmc_sequencer.reload = get_dmc_rate(data & 0b0000_1111) - 1;
         }
 
         0x4011 => {
@@ -384,25 +579,7 @@ impl Apu {
     None
   }
 
-  pub fn clock(&mut self, cart: &mut Cart) {
-    // Sampling timing stuff:
-    {
-      self.time_until_next_sample -= TIME_PER_PPU_CLOCK;
-      if self.time_until_next_sample < 0.0 {
-        // Simple sin wave for now:
-        self.sample_clock = (self.sample_clock + 1.0) % self.system_sample_rate;
-        self.sample_ready = true;
-        self.time_until_next_sample += self.time_per_sample;
-      }
-    }
-
-    // https://www.nesdev.org/wiki/APU_Frame_Counter
-    // self.global_clock += TIME_PER_PPU_CLOCK;
-    self.global_clock += (0.33333333333 / NTSC_CPU_CLOCK_FREQ) as f64;
-    if self.global_clock == 4.0 {
-      self.global_clock = 0.0;
-    }
-
+  pub fn clock(&mut self) {
     let mut quarter_frame: bool = false;
     let mut half_frame: bool = false;
 
@@ -443,6 +620,14 @@ impl Apu {
         quarter_frame = true;
       }
 
+      // The 4-step sequence raises the frame interrupt around its last step,
+      // unless it's inhibited. The flag is set on a couple of cycles in a row
+      // there, so reading $4015 right as it's set doesn't clear it for good:
+      if !self.five_step_mode && !self.frame_interrupt_inhibit && self.frame_clock_counter >= 14914
+      {
+        self.frame_interrupt_flag = true;
+      }
+
       if (!self.five_step_mode && self.frame_clock_counter == 14915)
         || (self.five_step_mode && self.frame_clock_counter == 18641)
       {
@@ -473,13 +658,6 @@ impl Apu {
           if !self.pulse[i].length_counter_halt && self.pulse[i].length_counter > 0 {
             self.pulse[i].length_counter -= 1;
           }
-
-          // Set amplitude
-          if self.pulse[i].length_counter == 0 || self.pulse[i].sweep.muting {
-            self.pulse[i].osc.amplitude = 0.0;
-          } else {
-            self.pulse[i].osc.amplitude = self.pulse[i].envelope.volume_level() * 0.25;
-          }
         }
 
         // Update length counters
@@ -492,61 +670,50 @@ impl Apu {
         }
       }
 
-      // Nasty raw 1-bit sound:
-      //
-      // for i in 0..self.pulse.len() {
-      //   self.pulse[i].sequencer.clock(
-      //     self.pulse[i].enable,
-      //     // Shift right by 1 bit, wrapping around.
-      //     //
-      //     // ```
-      //     // 0b0000_0010_... -> 0b0000_0001_...
-      //     // 0b0000_..._0001 -> 0b1000_..._0000
-      //     // ```
-      //     |s| ((s & 0x0000_0001) << 7) | ((s & 0x0000_00FE) >> 1),
-      //   );
-      //   self.pulse[i].sample = if self.pulse[i].sequencer.output == 0 {
-      //     0.0
-      //   } else {
-      //     self.pulse[i].envelope.volume_level()
-      //   };
-      // }
-
-      // Nicer simulated oscillator as a sum of sin-waves:
+      // The pulse timers count APU cycles; each time one runs out, its
+      // sequencer steps through the duty cycle:
       for i in 0..self.pulse.len() {
-        if self.pulse[i].enable {
-          // Calculate frequency from `reload` which is sometimes referred to as
-          // the "period" of the pulse wave. Should I rename this? Maybe. I got
-          // started from the OLC youtube tutorial which used these names which
-          // I found really confusing, especially since ultimately the sequencer
-          // approach to generating samples was replaced with an oscillator.
-          self.pulse[i].osc.frequency = period_to_frequency(self.pulse[i].sequencer.reload);
-          self.pulse[i].sample = self.pulse[i].osc.sample(self.global_clock as f32);
-        }
+        self.pulse[i].sequencer.clock(true, &mut |s| (s + 1) % 8);
       }
-
-      self.noise.sequencer.clock(self.noise.enable, &mut |_| {
-        Noise::clock(&mut self.noise.lfsr, self.noise.mode_flag) as u32
-      });
-      self.noise.sample = self.noise.get_sample();
     }
 
-    // The triangle's sequencer runs at twice the rate of the pulse sequencers:
+    // Everything else counts CPU cycles:
     if self.clock_counter % 3 == 0 {
-      // Triangle 4-bit sound:
+      if let Some(log) = &mut self.vgm_log {
+        log.clock();
+      }
+
+      // The triangle's sequencer only moves while both its counters are
+      // non-zero; otherwise it holds wherever it was:
       if self.triangle.length_counter != 0 && self.triangle.linear_counter != 0 {
-        self
-          .triangle
-          .sequencer
-          .clock(self.triangle.enable, &mut |s| (s + 1) % 32);
-        self.triangle.sample = self.triangle.get_sample();
+        self.triangle.sequencer.clock(true, &mut |s| (s + 1) % 32);
       }
 
-      self.dmc_sequencer.clock(self.dmc.enable, &mut |_| {
-        self.dmc.clock(cart);
+      self.noise.sequencer.clock(true, &mut |_| {
+        Noise::clock(&mut self.noise.lfsr, self.noise.mode_flag) as u32
+      });
+
+      self.dmc_sequencer.clock(true, &mut |_| {
+        self.dmc.clock();
         0
       });
-      self.dmc.sample = self.dmc.get_sample();
+
+      // Every change to the output, however small or fast, goes to `blip` as a
+      // step at the cycle it happened on:
+      let level = self.mix();
+      if level != self.level {
+        self.blip.add_delta(level - self.level);
+        self.level = level;
+      }
+      self.blip.clock();
+      self.sample_ready = self.blip.samples_avail() > 0;
+
+      if self.stems.is_some() {
+        let levels = Channel::ALL.map(|channel| self.channel_alone(channel));
+        if let Some(stems) = &mut self.stems {
+          stems.clock(levels);
+        }
+      }
     }
 
     self.clock_counter = self.clock_counter.wrapping_add(1);
@@ -557,8 +724,271 @@ impl Apu {
   }
 }
 
-fn period_to_frequency(period: u16) -> f32 {
-  NTSC_CPU_CLOCK_FREQ / (16.0 * ((period as u32) + 1) as f32)
+/// The APU's sound channels, for muting and the like.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+  Pulse1,
+  Pulse2,
+  Triangle,
+  Noise,
+  Dmc,
+  /// Whatever sound chip the cart has, if any
+  Expansion,
+}
+
+impl Channel {
+  pub const ALL: [Channel; 6] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+    Channel::Expansion,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Channel::Pulse1 => "Pulse 1",
+      Channel::Pulse2 => "Pulse 2",
+      Channel::Triangle => "Triangle",
+      Channel::Noise => "Noise",
+      Channel::Dmc => "DMC",
+      Channel::Expansion => "Expansion",
+    }
+  }
+}
+
+/// How a channel goes into the mix; see `Apu::set_channel_mix`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelMix {
+  pub muted: bool,
+  pub solo: bool,
+  /// Scales the channel's DAC level, before the nonlinear mixing; 1.0 is as
+  /// loud as the console plays it
+  pub volume: f32,
+}
+
+impl Default for ChannelMix {
+  fn default() -> Self {
+    ChannelMix {
+      muted: false,
+      solo: false,
+      volume: 1.0,
+    }
+  }
+}
+
+/// Sound chips that carts can bring along; see `Mapper::clock_audio`.
+///
+/// https://www.nesdev.org/wiki/Expansion_audio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpansionChip {
+  Fds,
+  Mmc5,
+  Namco163,
+  Sunsoft5B,
+  Vrc6,
+  Vrc7,
+}
+
+impl ExpansionChip {
+  /// Roughly how loud the chip is with everything at full volume, next to one
+  /// of the APU's pulses at full volume.
+  ///
+  /// These are ballpark figures from measurements posted on nesdev; real carts
+  /// vary, the N163's and VRC7's especially. The Expansion channel's volume is
+  /// there for anyone who disagrees.
+  fn full_scale(&self) -> f32 {
+    match self {
+      // "About 2.4 times as loud as a 2A03 pulse at full volume"
+      ExpansionChip::Fds => 2.4,
+      // Two more of the APU's pulses; the PCM channel fits in the same range
+      ExpansionChip::Mmc5 => 2.0,
+      ExpansionChip::Namco163 => 4.0,
+      // Three squares, a bit louder than the APU's pulses
+      ExpansionChip::Sunsoft5B => 3.0,
+      // Two pulses and a saw, with the same step size as the APU's pulses
+      ExpansionChip::Vrc6 => (15.0 + 15.0 + 31.0) / 15.0,
+      ExpansionChip::Vrc7 => 2.0,
+    }
+  }
+}
+
+/// A sound chip's output for one CPU cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExpansionAudio {
+  pub chip: ExpansionChip,
+  /// From 0.0 (silent) to 1.0 (everything at full volume)
+  pub level: f32,
+}
+
+/// https://www.nesdev.org/wiki/APU_Mixer
+///
+/// Takes the sum of both pulse channels' DAC levels.
+fn pulse_out(pulse: f32) -> f32 {
+  if pulse == 0.0 {
+    0.0
+  } else {
+    95.88 / (8128.0 / pulse + 100.0)
+  }
+}
+
+/// https://www.nesdev.org/wiki/APU_Mixer
+///
+/// Takes the triangle, noise and DMC's DAC levels.
+fn tnd_out(triangle: f32, noise: f32, dmc: f32) -> f32 {
+  let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+  if tnd == 0.0 {
+    0.0
+  } else {
+    159.79 / (1.0 / tnd + 100.0)
+  }
+}
+
+/// Each channel's output on its own; see `Apu::set_stems_enabled`.
+struct Stems {
+  blips: Vec<BlipBuf>,
+  levels: [f32; 6],
+  filters: Vec<Vec<Filter>>,
+  samples: [f32; 6],
+}
+
+impl Stems {
+  fn new(blip: &BlipBuf, profile: FilterProfile, sample_rate: f32) -> Self {
+    // Starting from the mix's `BlipBuf` keeps the stems' samples lined up with
+    // its samples:
+    let mut blip = blip.clone();
+    blip.clear();
+    Stems {
+      blips: vec![blip; 6],
+      levels: [0.0; 6],
+      filters: (0..6).map(|_| profile.filters(sample_rate)).collect(),
+      samples: [0.0; 6],
+    }
+  }
+
+  fn clock(&mut self, levels: [f32; 6]) {
+    let stems = self.levels.iter_mut().zip(self.blips.iter_mut());
+    for (level, (last_level, blip)) in levels.into_iter().zip(stems) {
+      if level != *last_level {
+        blip.add_delta(level - *last_level);
+        *last_level = level;
+      }
+      blip.clock();
+    }
+  }
+
+  fn read_samples(&mut self) {
+    for i in 0..6 {
+      let mut sample = self.blips[i].read_sample().unwrap_or(self.levels[i]);
+      for filter in self.filters[i].iter_mut() {
+        sample = filter.process(sample);
+      }
+      self.samples[i] = sample;
+    }
+  }
+}
+
+/// Which analog filters to run the mixed output through.
+///
+/// https://www.nesdev.org/wiki/APU_Mixer#Emulation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterProfile {
+  /// Straight out of the mixer, DC offset and all
+  Raw,
+  /// A front-loading NES: high-pass filters at 90Hz and 440Hz, then a
+  /// low-pass at 14kHz
+  Nes,
+  /// A Famicom: only a high-pass at 37Hz; what the RF modulator does after
+  /// that varies too much from unit to unit to bother with
+  Famicom,
+}
+
+impl FilterProfile {
+  pub const ALL: [FilterProfile; 3] = [
+    FilterProfile::Raw,
+    FilterProfile::Nes,
+    FilterProfile::Famicom,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      FilterProfile::Raw => "raw",
+      FilterProfile::Nes => "nes",
+      FilterProfile::Famicom => "famicom",
+    }
+  }
+
+  fn filters(&self, sample_rate: f32) -> Vec<Filter> {
+    match self {
+      FilterProfile::Raw => vec![],
+      FilterProfile::Nes => vec![
+        Filter::high_pass(90.0, sample_rate),
+        Filter::high_pass(440.0, sample_rate),
+        Filter::low_pass(14_000.0, sample_rate),
+      ],
+      FilterProfile::Famicom => vec![Filter::high_pass(37.0, sample_rate)],
+    }
+  }
+}
+
+impl std::str::FromStr for FilterProfile {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    FilterProfile::ALL
+      .iter()
+      .find(|profile| profile.name() == name.to_lowercase())
+      .copied()
+      .ok_or_else(|| {
+        format!(
+          "Unknown filter profile \"{}\"; try raw, nes or famicom",
+          name
+        )
+      })
+  }
+}
+
+/// A first-order RC filter, run at the host's sample rate.
+struct Filter {
+  high_pass: bool,
+  alpha: f32,
+  last_input: f32,
+  last_output: f32,
+}
+
+impl Filter {
+  fn high_pass(cutoff: f32, sample_rate: f32) -> Self {
+    let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+    let dt = 1.0 / sample_rate;
+    Filter {
+      high_pass: true,
+      alpha: rc / (rc + dt),
+      last_input: 0.0,
+      last_output: 0.0,
+    }
+  }
+
+  fn low_pass(cutoff: f32, sample_rate: f32) -> Self {
+    let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+    let dt = 1.0 / sample_rate;
+    Filter {
+      high_pass: false,
+      alpha: dt / (rc + dt),
+      last_input: 0.0,
+      last_output: 0.0,
+    }
+  }
+
+  fn process(&mut self, input: f32) -> f32 {
+    self.last_output = if self.high_pass {
+      self.alpha * (self.last_output + input - self.last_input)
+    } else {
+      self.last_output + self.alpha * (input - self.last_output)
+    };
+    self.last_input = input;
+    self.last_output
+  }
 }
 
 fn get_length_counter(pattern: u8) -> u8 {
@@ -647,7 +1077,7 @@ fn get_length_counter(pattern: u8) -> u8 {
 /// Takes a 4-bit number (top 4 bits ignored) and produces a length for the
 /// period of the noise channel's sequencer.
 ///
-/// ```
+/// ```text
 /// Rate  $0 $1  $2  $3  $4  $5   $6   $7   $8   $9   $A   $B   $C    $D    $E    $F
 ///       --------------------------------------------------------------------------
 /// NTSC   4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
@@ -678,7 +1108,7 @@ fn get_noise_sequencer_period(data: u8) -> u16 {
 /// Takes a 4-bit number (top 4 bits ignored) and produces a length for the
 /// period of the DMC channel's sequencer.
 ///
-/// ```
+/// ```text
 /// Rate   $0   $1   $2   $3   $4   $5   $6   $7   $8   $9   $A   $B   $C   $D   $E   $F
 ///       ------------------------------------------------------------------------------
 /// NTSC  428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106,  84,  72,  54
@@ -726,9 +1156,10 @@ impl Sequencer {
 
   pub fn clock(&mut self, enable: bool, manipulate_sequence: &mut dyn FnMut(u32) -> u32) -> u8 {
     if enable {
+      // Like a `Divider`, the period is `reload + 1` clocks:
       self.timer = self.timer.wrapping_sub(1);
       if self.timer == 0xFFFF {
-        self.timer = self.reload.wrapping_add(1);
+        self.timer = self.reload;
         self.sequence = manipulate_sequence(self.sequence);
         // The output of our sequencer during this clock is just the lowest bit
         // of our sequence after the sequence has been manipulated.
@@ -833,12 +1264,12 @@ impl Envelope {
     }
   }
 
-  // Why the heck does this need to be `&mut self`?
-  pub fn volume_level(&mut self) -> f32 {
+  /// The volume, from 0 to 15.
+  pub fn volume(&self) -> u8 {
     if self.constant_volume_flag {
-      (self.divider.reload as f32) / 15.0
+      self.divider.reload as u8
     } else {
-      (self.decay_level as f32) / 15.0
+      self.decay_level
     }
   }
 }
@@ -941,9 +1372,10 @@ impl Sweep {
 
 pub struct Pulse {
   pub enable: bool,
-  pub sample: f32,
+  /// Which of the four `PULSE_DUTY_CYCLES` to play
+  pub duty: u8,
+  /// Counts through the duty cycle's 8 steps in `sequence`
   pub sequencer: Sequencer,
-  pub osc: PulseOscillator,
   pub length_counter: u8,
   pub length_counter_halt: bool,
   pub envelope: Envelope,
@@ -954,70 +1386,33 @@ impl Pulse {
   fn new() -> Self {
     Pulse {
       enable: false,
-      sample: 0.0,
-      sequencer: Sequencer {
-        // Not sure why this is 32 bits; seems we only care about the lower 8
-        // bits:
-        sequence: 0b0000_0000_0000_0000_0000_0000__0000_0000,
-        timer: 0x0000,
-        reload: 0x0000,
-        output: 0x00,
-      },
-      osc: PulseOscillator::new(),
+      duty: 0,
+      sequencer: Sequencer::new(),
       length_counter: 0x00,
       length_counter_halt: false,
       envelope: Envelope::new(),
       sweep: Sweep::new(),
     }
   }
-}
-
-pub struct PulseOscillator {
-  pub frequency: f32,
-  pub duty_cycle: f32,
-  pub amplitude: f32,
-  pub harmonics: u8,
-}
-
-impl PulseOscillator {
-  pub fn new() -> Self {
-    PulseOscillator {
-      frequency: 0.0,
-      duty_cycle: 0.0,
-      amplitude: 1.0,
-      harmonics: 60,
-    }
-  }
 
-  // Why the heck does this need to be `&mut self`?
-  pub fn sample(&mut self, t: f32) -> f32 {
-    let mut a = 0.0;
-    let mut b = 0.0;
-    let p = self.duty_cycle * 2.0 * PI;
-    for n in 1..self.harmonics {
-      let n = n as f32;
-      let c = n * self.frequency * 2.0 * PI * t;
-      a += -(c).qsin() / n;
-      b += -(c - p * n).qsin() / n;
+  /// The channel's DAC level, from 0 to 15.
+  pub fn get_sample(&self) -> f32 {
+    let step = PULSE_DUTY_CYCLES[self.duty as usize][(self.sequencer.sequence % 8) as usize];
+    if step == 0 || self.length_counter == 0 || self.sweep.muting {
+      0.0
+    } else {
+      self.envelope.volume() as f32
     }
-
-    return (2.0 * self.amplitude / PI) * (a - b);
   }
 }
 
-trait QuickSin {
-  fn qsin(self) -> Self;
-}
-
-impl QuickSin for f32 {
-  /// Cheap implementation of sin; approximation appropriate for audio
-  /// synthesis.
-  fn qsin(self) -> f32 {
-    let mut j = self * 0.15915;
-    j = j - (j.floor());
-    20.785 * j * (j - 0.5) * (j - 1.0)
-  }
-}
+#[rustfmt::skip]
+const PULSE_DUTY_CYCLES: [[u8; 8]; 4] = [
+  [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+  [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+  [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+  [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
 
 pub struct Triangle {
   enable: bool,
@@ -1027,13 +1422,12 @@ pub struct Triangle {
   linear_counter_reload_value: u8,
   linear_counter_reload: bool,
   control: bool,
-  sample: f32,
 }
 
 #[rustfmt::skip]
-const TRIANGLE_SEQUENCE: [f32; 32] = [
-  15.0 / 15.0, 14.0 / 15.0, 13.0 / 15.0, 12.0 / 15.0, 11.0 / 15.0, 10.0 / 15.0, 9.0 / 15.0, 8.0 / 15.0, 7.0 / 15.0, 6.0 / 15.0, 5.0 / 15.0, 4.0 / 15.0, 3.0 / 15.0, 2.0 / 15.0, 1.0 / 15.0, 0.0 / 15.0,
-  0.0 / 15.0, 1.0 / 15.0, 2.0 / 15.0, 3.0 / 15.0, 4.0 / 15.0, 5.0 / 15.0, 6.0 / 15.0, 7.0 / 15.0, 8.0 / 15.0, 9.0 / 15.0, 10.0 / 15.0, 11.0 / 15.0, 12.0 / 15.0, 13.0 / 15.0, 14.0 / 15.0, 15.0 / 15.0,
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+  15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+  0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
 ];
 
 impl Triangle {
@@ -1046,7 +1440,6 @@ impl Triangle {
       linear_counter_reload_value: 0x00,
       linear_counter_reload: false,
       control: false,
-      sample: 0.0,
     }
   }
 
@@ -1073,9 +1466,10 @@ impl Triangle {
     }
   }
 
-  pub fn get_sample(&mut self) -> f32 {
+  /// The channel's DAC level, from 0 to 15.
+  pub fn get_sample(&self) -> f32 {
     // We (mis)use the sequencer's sequence value to loop through 32 steps.
-    TRIANGLE_SEQUENCE[(self.sequencer.sequence % 32) as usize]
+    TRIANGLE_SEQUENCE[(self.sequencer.sequence % 32) as usize] as f32
   }
 }
 
@@ -1092,8 +1486,6 @@ pub struct Noise {
   length_counter: u8,
 
   lfsr: LinearFeedbackShiftRegister,
-
-  sample: f32,
 }
 
 impl Noise {
@@ -1110,8 +1502,6 @@ impl Noise {
 
       // On power-up, the shift register is loaded with the value 1.
       lfsr: LinearFeedbackShiftRegister(0b0000_0000_0000_0001),
-
-      sample: 0.0,
     }
   }
   fn clock(lfsr: &mut LinearFeedbackShiftRegister, mode_flag: bool) -> u16 {
@@ -1138,14 +1528,15 @@ impl Noise {
     lfsr.0 |= feedback << 14;
     lfsr.0 & 0b0000_0000_0000_0001
   }
-  fn get_sample(&mut self) -> f32 {
+  /// The channel's DAC level, from 0 to 15.
+  pub fn get_sample(&self) -> f32 {
     // The mixer receives the current envelope volume except when
     // - Bit 0 of the shift register is set, or
     // - The length counter is zero
     if (self.lfsr.0 & 0b0000_0000_0000_0001) != 0 || self.length_counter == 0 {
       0.0
     } else {
-      self.envelope.volume_level()
+      self.envelope.volume() as f32
     }
   }
 }
@@ -1168,8 +1559,6 @@ pub struct Dmc {
   output_level: u8,
   output_shift_register: u8,
   output_bits_remaining: u8,
-
-  sample: f32,
 }
 
 impl Dmc {
@@ -1189,71 +1578,76 @@ impl Dmc {
       output_shift_register: 0x00,
       output_bits_remaining: 0,
       silence_flag: false,
-
-      sample: 0.0,
     }
   }
 
-  pub fn clock(&mut self, cart: &mut Cart) {
-    // Any time the sample buffer is in an empty state and bytes remaining is
-    // not zero (including just after a write to $4015 that enables the channel,
-    // regardless of where that write occurs relative to the bit counter
-    // mentioned below), the following occur:
+  /// The address of the next sample byte, if the DMC wants it fetched.
+  ///
+  /// Any time the sample buffer is in an empty state and bytes remaining is
+  /// not zero (including just after a write to $4015 that enables the channel,
+  /// regardless of where that write occurs relative to the bit counter
+  /// mentioned below), the DMC asks for the next byte. It doesn't read it
+  /// itself; the fetch is a DMA that stalls the CPU, and that's up to `Nes`.
+  ///
+  /// - The CPU is stalled for up to 4 CPU cycles[2] to allow the longest
+  ///   possible write (the return address and write after an IRQ) to finish.
+  ///   If OAM DMA is in progress, it is paused for two cycles.[3] The sample
+  ///   fetch always occurs on an even CPU cycle due to its alignment with the
+  ///   APU. Specific delay cases:
+  ///   - 4 cycles if it falls on a CPU read cycle.
+  ///   - 3 cycles if it falls on a single CPU write cycle (or the second write
+  ///     of a double CPU write).
+  ///   - 4 cycles if it falls on the first write of a double CPU write
+  ///     cycle.[4]
+  ///   - 2 cycles if it occurs during an OAM DMA, or on the $4014 write cycle
+  ///     that triggers the OAM DMA.
+  ///   - 1 cycle if it occurs on the second-last OAM DMA cycle.
+  ///   - 3 cycles if it occurs on the last OAM DMA cycle.
+  pub fn dma_request(&self) -> Option<u16> {
     if self.sample_buffer == None && self.bytes_remaining != 0 {
-      // - The CPU is stalled for up to 4 CPU cycles[2] to allow the longest
-      //   possible write (the return address and write after an IRQ) to finish.
-      //   If OAM DMA is in progress, it is paused for two cycles.[3] The sample
-      //   fetch always occurs on an even CPU cycle due to its alignment with
-      //   the APU. Specific delay cases:
-      //   - 4 cycles if it falls on a CPU read cycle.
-      //   - 3 cycles if it falls on a single CPU write cycle (or the second
-      //     write of a double CPU write).
-      //   - 4 cycles if it falls on the first write of a double CPU write
-      //     cycle.[4]
-      //   - 2 cycles if it occurs during an OAM DMA, or on the $4014 write
-      //     cycle that triggers the OAM DMA.
-      //   - 1 cycle if it occurs on the second-last OAM DMA cycle.
-      //   - 3 cycles if it occurs on the last OAM DMA cycle.
-
-      // TODO: LOL, yeah not right now.
-
-      // - The sample buffer is filled with the next sample byte read from the
-      // current address, subject to whatever mapping hardware is present.
-      self.sample_buffer = match cart.cpu_read(self.current_addr) {
-        Some(d) => Some(d),
-        None => Some(0x00),
-      };
-
-      // println!("byr {} a ${:04X}", self.bytes_remaining, self.current_addr);
-
-      // - The address is incremented; if it exceeds $FFFF, it is wrapped around
-      // to $8000.
-      if self.current_addr == 0xFFFF {
-        self.current_addr = 0x8000;
-      } else {
-        self.current_addr += 1;
-      }
+      Some(self.current_addr)
+    } else {
+      None
+    }
+  }
 
-      // - The bytes remaining counter is decremented; if it becomes zero and
-      // the loop flag is set, the sample is restarted (see above); otherwise,
-      // if the bytes remaining counter becomes zero and the IRQ enabled flag is
-      // set, the interrupt flag is set.
-      self.bytes_remaining -= 1;
-      if self.bytes_remaining == 0 {
-        if self.loop_flag {
-          // When a sample is (re)started, the current address is set to the
-          // sample address, and bytes remaining is set to the sample length.
-          self.current_addr = self.sample_addr;
-          self.bytes_remaining = self.sample_len;
-        } else {
-          // self.sample_buffer = None;
-          if self.irq_enabled_flag {
-            self.interrupt_flag = true;
-          }
+  /// Hands the DMC the byte it asked for through `dma_request`.
+  pub fn dma_load(&mut self, data: u8) {
+    // - The sample buffer is filled with the next sample byte read from the
+    // current address, subject to whatever mapping hardware is present.
+    self.sample_buffer = Some(data);
+
+    // println!("byr {} a ${:04X}", self.bytes_remaining, self.current_addr);
+
+    // - The address is incremented; if it exceeds $FFFF, it is wrapped around
+    // to $8000.
+    if self.current_addr == 0xFFFF {
+      self.current_addr = 0x8000;
+    } else {
+      self.current_addr += 1;
+    }
+
+    // - The bytes remaining counter is decremented; if it becomes zero and
+    // the loop flag is set, the sample is restarted (see above); otherwise,
+    // if the bytes remaining counter becomes zero and the IRQ enabled flag is
+    // set, the interrupt flag is set.
+    self.bytes_remaining -= 1;
+    if self.bytes_remaining == 0 {
+      if self.loop_flag {
+        // When a sample is (re)started, the current address is set to the
+        // sample address, and bytes remaining is set to the sample length.
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+      } else {
+        // self.sample_buffer = None;
+        if self.irq_enabled_flag {
+          self.interrupt_flag = true;
         }
       }
     }
+  }
 
+  pub fn clock(&mut self) {
     // https://www.nesdev.org/wiki/APU_DMC#Output_unit
     //
     // The bits-remaining counter is updated whenever the timer outputs a clock,
@@ -1330,11 +1724,546 @@ impl Dmc {
     }
   }
 
-  pub fn get_sample(&mut self) -> f32 {
-    let sample = (self.output_level as f32) / 127.0;
-    if sample != self.sample {
-      // println!("o {}", self.output_level);
+  /// The channel's DAC level, from 0 to 127.
+  pub fn get_sample(&self) -> f32 {
+    self.output_level as f32
+  }
+}
+
+// Save states. `blip`, `level`, the filters, `channel_mix`, `stems` and
+// `system_sample_rate` describe the host's audio output rather than the
+// console, so they're left alone.
+impl SaveState for Apu {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.sample_ready.save_state(w);
+    self.pulse.save_state(w);
+    self.triangle.save_state(w);
+    self.noise.save_state(w);
+    self.dmc.save_state(w);
+    self.dmc_sequencer.save_state(w);
+    self.clock_counter.save_state(w);
+    self.frame_clock_counter.save_state(w);
+    self.five_step_mode.save_state(w);
+    self.frame_interrupt_inhibit.save_state(w);
+    self.frame_interrupt_flag.save_state(w);
+    self.frame_counter_reset_timer.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.sample_ready.load_state(r)?;
+    self.pulse.load_state(r)?;
+    self.triangle.load_state(r)?;
+    self.noise.load_state(r)?;
+    self.dmc.load_state(r)?;
+    self.dmc_sequencer.load_state(r)?;
+    self.clock_counter.load_state(r)?;
+    self.frame_clock_counter.load_state(r)?;
+    self.five_step_mode.load_state(r)?;
+    self.frame_interrupt_inhibit.load_state(r)?;
+    self.frame_interrupt_flag.load_state(r)?;
+    self.frame_counter_reset_timer.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Sequencer {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.sequence.save_state(w);
+    self.timer.save_state(w);
+    self.reload.save_state(w);
+    self.output.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.sequence.load_state(r)?;
+    self.timer.load_state(r)?;
+    self.reload.load_state(r)?;
+    self.output.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Divider {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.reload.save_state(w);
+    self.counter.save_state(w);
+    self.force_reload.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.reload.load_state(r)?;
+    self.counter.load_state(r)?;
+    self.force_reload.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Envelope {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.start_flag.save_state(w);
+    self.divider.save_state(w);
+    self.decay_level.save_state(w);
+    self.loop_flag.save_state(w);
+    self.constant_volume_flag.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.start_flag.load_state(r)?;
+    self.divider.load_state(r)?;
+    self.decay_level.load_state(r)?;
+    self.loop_flag.load_state(r)?;
+    self.constant_volume_flag.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Sweep {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.enabled.save_state(w);
+    self.divider.save_state(w);
+    self.negate.save_state(w);
+    self.shift_count.save_state(w);
+    self.muting.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.enabled.load_state(r)?;
+    self.divider.load_state(r)?;
+    self.negate.load_state(r)?;
+    self.shift_count.load_state(r)?;
+    self.muting.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Pulse {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.enable.save_state(w);
+    self.duty.save_state(w);
+    self.sequencer.save_state(w);
+    self.length_counter.save_state(w);
+    self.length_counter_halt.save_state(w);
+    self.envelope.save_state(w);
+    self.sweep.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.enable.load_state(r)?;
+    self.duty.load_state(r)?;
+    self.sequencer.load_state(r)?;
+    self.length_counter.load_state(r)?;
+    self.length_counter_halt.load_state(r)?;
+    self.envelope.load_state(r)?;
+    self.sweep.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Triangle {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.enable.save_state(w);
+    self.sequencer.save_state(w);
+    self.length_counter.save_state(w);
+    self.linear_counter.save_state(w);
+    self.linear_counter_reload_value.save_state(w);
+    self.linear_counter_reload.save_state(w);
+    self.control.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.enable.load_state(r)?;
+    self.sequencer.load_state(r)?;
+    self.length_counter.load_state(r)?;
+    self.linear_counter.load_state(r)?;
+    self.linear_counter_reload_value.load_state(r)?;
+    self.linear_counter_reload.load_state(r)?;
+    self.control.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Noise {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.enable.save_state(w);
+    self.sequencer.save_state(w);
+    self.envelope.save_state(w);
+    self.mode_flag.save_state(w);
+    self.length_counter_halt.save_state(w);
+    self.length_counter.save_state(w);
+    self.lfsr.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.enable.load_state(r)?;
+    self.sequencer.load_state(r)?;
+    self.envelope.load_state(r)?;
+    self.mode_flag.load_state(r)?;
+    self.length_counter_halt.load_state(r)?;
+    self.length_counter.load_state(r)?;
+    self.lfsr.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for LinearFeedbackShiftRegister {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.0.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.0.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Dmc {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.enable.save_state(w);
+    self.irq_enabled_flag.save_state(w);
+    self.interrupt_flag.save_state(w);
+    self.loop_flag.save_state(w);
+    self.sample_addr.save_state(w);
+    self.sample_len.save_state(w);
+    self.current_addr.save_state(w);
+    self.bytes_remaining.save_state(w);
+    self.sample_buffer.save_state(w);
+    self.silence_flag.save_state(w);
+    self.output_level.save_state(w);
+    self.output_shift_register.save_state(w);
+    self.output_bits_remaining.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.enable.load_state(r)?;
+    self.irq_enabled_flag.load_state(r)?;
+    self.interrupt_flag.load_state(r)?;
+    self.loop_flag.load_state(r)?;
+    self.sample_addr.load_state(r)?;
+    self.sample_len.load_state(r)?;
+    self.current_addr.load_state(r)?;
+    self.bytes_remaining.load_state(r)?;
+    self.sample_buffer.load_state(r)?;
+    self.silence_flag.load_state(r)?;
+    self.output_level.load_state(r)?;
+    self.output_shift_register.load_state(r)?;
+    self.output_bits_remaining.load_state(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Runs the APU for the given number of CPU cycles, answering the DMC's
+  /// sample fetches with zeroes.
+  fn run(apu: &mut Apu, cpu_cycles: u32) {
+    for _ in 0..cpu_cycles * 3 {
+      apu.clock();
+      if let Some(addr) = apu.dmc.dma_request() {
+        apu.dmc_dma_load(addr, 0x00);
+      }
     }
-    sample
+  }
+
+  #[test]
+  fn frame_interrupt() {
+    let mut apu = Apu::new(44_100.0);
+
+    run(&mut apu, 29_800);
+    assert!(!apu.irq_active());
+    run(&mut apu, 100);
+    assert!(apu.irq_active());
+
+    // Reading the status reports the flag, and acknowledges it:
+    assert_eq!(apu.cpu_read(0x4015).unwrap() & 0b0100_0000, 0b0100_0000);
+    assert!(!apu.irq_active());
+    assert_eq!(apu.cpu_read(0x4015).unwrap() & 0b0100_0000, 0x00);
+  }
+
+  #[test]
+  fn frame_interrupt_inhibit() {
+    let mut apu = Apu::new(44_100.0);
+
+    run(&mut apu, 30_000);
+    assert!(apu.irq_active());
+
+    // Setting the inhibit flag clears the flag, and keeps it clear:
+    apu.cpu_write(0x4017, 0b0100_0000);
+    assert!(!apu.irq_active());
+    run(&mut apu, 30_000);
+    assert!(!apu.irq_active());
+
+    // The 5-step sequence never raises it:
+    apu.cpu_write(0x4017, 0b1000_0000);
+    run(&mut apu, 40_000);
+    assert!(!apu.irq_active());
+  }
+
+  #[test]
+  fn pulse_runs_at_its_period() {
+    let mut apu = Apu::new(44_100.0);
+    // 50% duty, constant volume 15, period 0x0FD (~440Hz):
+    apu.cpu_write(0x4015, 0b0000_0001);
+    apu.cpu_write(0x4000, 0b1011_1111);
+    apu.cpu_write(0x4002, 0xFD);
+    apu.cpu_write(0x4003, 0b1111_1000);
+
+    // Count the rising edges over a (CPU) second:
+    let mut edges = 0;
+    let mut last = apu.pulse[0].get_sample();
+    for _ in 0..(NTSC_CPU_CLOCK_FREQ as u32) * 3 {
+      apu.clock();
+      let sample = apu.pulse[0].get_sample();
+      if sample > last {
+        edges += 1;
+      }
+      last = sample;
+    }
+
+    // f = CPU / (16 * (t + 1)) = ~440.4Hz; the length counter is halted:
+    assert!((440..=441).contains(&edges), "{}", edges);
+  }
+
+  #[test]
+  fn samples_come_out_at_the_host_rate() {
+    let mut apu = Apu::new(44_100.0);
+    let mut samples = 0;
+    for _ in 0..(NTSC_CPU_CLOCK_FREQ as u32) * 3 {
+      apu.clock();
+      if apu.sample_ready {
+        apu.sample();
+        samples += 1;
+      }
+    }
+
+    assert!((44_090..=44_100).contains(&samples), "{}", samples);
+  }
+
+  #[test]
+  fn adjust_rate() {
+    let samples_in_a_second = |buffer_fill: f32| {
+      let mut apu = Apu::new(44_100.0);
+      apu.adjust_rate(buffer_fill);
+      let mut samples = 0;
+      for _ in 0..NTSC_CPU_CLOCK_FREQ as u32 * 3 {
+        apu.clock();
+        if apu.sample_ready {
+          apu.sample();
+          samples += 1;
+        }
+      }
+      samples
+    };
+
+    // An empty buffer wants more samples, a full one fewer, and one that's
+    // half full is doing fine:
+    assert!((44_310..=44_322).contains(&samples_in_a_second(0.0)));
+    assert!((44_090..=44_110).contains(&samples_in_a_second(0.5)));
+    assert!((43_870..=43_890).contains(&samples_in_a_second(1.0)));
+  }
+
+  #[test]
+  fn nonlinear_mix() {
+    let mut apu = Apu::new(44_100.0);
+    // The triangle powers up holding its first step, at 15; this is the one
+    // at 0:
+    apu.triangle.sequencer.sequence = 15;
+    assert_eq!(apu.mix(), 0.0);
+
+    // Everything at full blast comes out at just about 1.0:
+    apu.cpu_write(0x4015, 0b0000_0011);
+    for addr in [0x4000, 0x4004] {
+      apu.cpu_write(addr, 0b0011_1111);
+      apu.cpu_write(addr + 3, 0b1111_1000);
+    }
+    apu.pulse[0].sequencer.sequence = 1;
+    apu.pulse[1].sequencer.sequence = 1;
+    apu.triangle.sequencer.sequence = 0;
+    apu.noise.lfsr.0 = 0;
+    apu.noise.length_counter = 1;
+    apu.noise.envelope.constant_volume_flag = true;
+    apu.noise.envelope.divider.reload = 15;
+    apu.dmc.output_level = 127;
+    assert!((apu.mix() - 1.0).abs() < 0.01, "{}", apu.mix());
+
+    // The pulses share a DAC, so together they're less than twice as loud:
+    let both = apu.mix();
+    apu.pulse[1].length_counter = 0;
+    let one = apu.mix();
+    apu.pulse[0].length_counter = 0;
+    let none = apu.mix();
+    assert!(both - none < 2.0 * (one - none));
+  }
+
+  #[test]
+  fn mute_and_solo() {
+    let mut apu = Apu::new(44_100.0);
+    apu.triangle.sequencer.sequence = 0;
+    apu.dmc.output_level = 127;
+    let triangle = 159.79 / (8227.0 / 15.0 + 100.0);
+
+    apu.set_channel_mix(
+      Channel::Dmc,
+      ChannelMix {
+        muted: true,
+        ..Default::default()
+      },
+    );
+    assert_eq!(apu.mix(), triangle);
+
+    // Soloing wins over muting, and silences everything else:
+    apu.set_channel_mix(
+      Channel::Dmc,
+      ChannelMix {
+        muted: true,
+        solo: true,
+        volume: 1.0,
+      },
+    );
+    assert_eq!(apu.mix(), 159.79 / (22638.0 / 127.0 + 100.0));
+
+    apu.set_channel_mix(Channel::Dmc, ChannelMix::default());
+    apu.set_channel_mix(
+      Channel::Triangle,
+      ChannelMix {
+        volume: 0.5,
+        ..Default::default()
+      },
+    );
+    apu.dmc.output_level = 0;
+    assert_eq!(apu.mix(), 159.79 / (8227.0 / 7.5 + 100.0));
+  }
+
+  #[test]
+  fn expansion_audio() {
+    let mut apu = Apu::new(44_100.0);
+    apu.triangle.sequencer.sequence = 15;
+    assert_eq!(apu.mix(), 0.0);
+
+    // A VRC6 with both pulses at full volume and its saw at rest adds up to
+    // two of the APU's pulses, mixed linearly:
+    apu.set_expansion_audio(Some(ExpansionAudio {
+      chip: ExpansionChip::Vrc6,
+      level: 30.0 / 61.0,
+    }));
+    assert!((apu.mix() - 2.0 * pulse_out(15.0)).abs() < 0.0001);
+
+    apu.set_channel_mix(
+      Channel::Expansion,
+      ChannelMix {
+        muted: true,
+        ..Default::default()
+      },
+    );
+    assert_eq!(apu.mix(), 0.0);
+  }
+
+  #[test]
+  fn stems() {
+    let mut apu = Apu::new(44_100.0);
+    apu.set_filter_profile(FilterProfile::Raw);
+    apu.set_stems_enabled(true);
+    // Soloing doesn't affect stems:
+    apu.set_channel_mix(
+      Channel::Dmc,
+      ChannelMix {
+        solo: true,
+        ..Default::default()
+      },
+    );
+
+    let mut last = (0.0, None);
+    for _ in 0..10_000 * 3 {
+      apu.clock();
+      if apu.sample_ready {
+        last = (apu.sample(), apu.stem_sample());
+      }
+    }
+
+    let (sample, stems) = last;
+    let stems = stems.unwrap();
+    assert_eq!(sample, 0.0);
+    assert!((stems[Channel::Triangle as usize] - tnd_out(15.0, 0.0, 0.0)).abs() < 0.0001);
+    assert_eq!(stems[Channel::Pulse1 as usize], 0.0);
+
+    apu.set_stems_enabled(false);
+    assert_eq!(apu.stem_sample(), None);
+  }
+
+  #[test]
+  fn filter_profiles() {
+    // A constant level, like the triangle holding its first step at power-up,
+    // comes out of the raw mixer as-is, and the high-pass filters pull it back
+    // down to nothing:
+    let settle = |profile: FilterProfile| {
+      let mut apu = Apu::new(44_100.0);
+      apu.set_filter_profile(profile);
+      let mut sample = 0.0;
+      for _ in 0..(NTSC_CPU_CLOCK_FREQ as u32) * 3 {
+        apu.clock();
+        if apu.sample_ready {
+          sample = apu.sample();
+        }
+      }
+      sample
+    };
+
+    assert!((settle(FilterProfile::Raw) - 159.79 / (8227.0 / 15.0 + 100.0)).abs() < 0.0001);
+    assert!(settle(FilterProfile::Nes).abs() < 0.0001);
+    assert!(settle(FilterProfile::Famicom).abs() < 0.0001);
+
+    assert_eq!("Famicom".parse(), Ok(FilterProfile::Famicom));
+    assert!("vhs".parse::<FilterProfile>().is_err());
+  }
+
+  #[test]
+  fn vgm_log() {
+    let path = std::env::temp_dir().join(format!("nessers-apu-{}.vgm", std::process::id()));
+    let mut apu = Apu::new(44_100.0);
+    // A sample of one byte, at $C040:
+    apu.cpu_write(0x4012, 0x01);
+    apu.cpu_write(0x4013, 0x00);
+    apu.cpu_write(0x4015, 0x00);
+    apu.start_vgm_log(VgmLog::create(&path).unwrap());
+    apu.cpu_write(0x4015, 0b0001_0000);
+    run(&mut apu, 1_000);
+    apu.take_vgm_log().unwrap().finish().unwrap();
+
+    let vgm = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let data = &vgm[0xC0..];
+    // The log starts with what the registers were already set to, $4015
+    // first:
+    assert_eq!(&data[0..3], &[0xB4, 0x15, 0x00]);
+    assert_eq!(&data[3..6], &[0xB4, 0x00, 0x00]);
+    assert_eq!(&data[57..60], &[0xB4, 0x12, 0x01]);
+    // Then what happened since, including the byte the DMC fetched:
+    assert_eq!(&data[66..69], &[0xB4, 0x15, 0b0001_0000]);
+    assert_eq!(
+      &data[69..81],
+      &[0x67, 0x66, 0xC2, 0x03, 0x00, 0x00, 0x00, 0x40, 0xC0, 0x00, 0x61, 24]
+    );
+  }
+
+  #[test]
+  fn dmc_interrupt() {
+    let mut apu = Apu::new(44_100.0);
+    apu.cpu_write(0x4017, 0b0100_0000);
+
+    // A 1-byte sample with IRQs enabled:
+    apu.cpu_write(0x4010, 0b1000_0000);
+    apu.cpu_write(0x4013, 0x00);
+    apu.cpu_write(0x4015, 0b0001_0000);
+    run(&mut apu, 1_000);
+    assert!(apu.irq_active());
+
+    // Reading the status reports the flag, but doesn't acknowledge it:
+    assert_eq!(apu.cpu_read(0x4015).unwrap() & 0b1000_0000, 0b1000_0000);
+    assert!(apu.irq_active());
+
+    // Disabling DMC IRQs does:
+    apu.cpu_write(0x4010, 0x00);
+    assert!(!apu.irq_active());
   }
 }
@@ -1,74 +1,101 @@
 extern crate cpal;
 
-use std::sync::mpsc::Receiver;
-
-use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use nessers::audio_sink::AudioSink;
+use nessers::ring::{ring_buffer, Consumer, Producer};
 
+/// The default cpal output device.
 pub struct AudioDevice {
-  pub stream: cpal::Stream,
-  pub min_buffer_size: usize,
-  pub max_buffer_size: usize,
-  pub sample_rate: u32,
+  stream: cpal::Stream,
+  sample_rate: u32,
+  /// Where samples go to get played; holds `buffer_seconds` worth of them
+  sample_tx: Producer,
 }
 
 impl AudioDevice {
-  pub fn init(rx: Receiver<f32>) -> Self {
+  pub fn init(buffer_seconds: f32) -> Result<Self, String> {
     let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    println!("Output device: {}", device.name().unwrap());
+    let device = host
+      .default_output_device()
+      .ok_or("No audio output device")?;
+    println!(
+      "Output device: {}",
+      device.name().unwrap_or_else(|_| "(unnamed)".to_string())
+    );
 
-    let config = device.default_output_config().unwrap();
+    let config = device
+      .default_output_config()
+      .map_err(|e| format!("No usable audio output config: {}", e))?;
     let sample_rate = config.sample_rate().0;
+    let (sample_tx, rx) = ring_buffer((sample_rate as f32 * buffer_seconds) as usize);
 
-    let buffer_size = config.buffer_size().clone();
     println!("Default output config: {:?}", config);
 
     let stream = match config.sample_format() {
       cpal::SampleFormat::F32 => run::<f32>(&device, &config.into(), rx),
       cpal::SampleFormat::I16 => run::<i16>(&device, &config.into(), rx),
       cpal::SampleFormat::U16 => run::<u16>(&device, &config.into(), rx),
-    };
+    }?;
 
-    AudioDevice {
+    Ok(AudioDevice {
       stream,
-      min_buffer_size: min_buffer_size(&buffer_size),
-      max_buffer_size: max_buffer_size(&buffer_size),
       sample_rate,
-    }
+      sample_tx,
+    })
   }
 }
 
-fn min_buffer_size(buffer_size: &cpal::SupportedBufferSize) -> usize {
-  match *buffer_size {
-    cpal::SupportedBufferSize::Range { min, .. } => min as usize,
-    // Some sensible default:
-    _ => 15,
+impl AudioSink for AudioDevice {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, samples: &[f32]) {
+    // Rate control keeps the buffer from filling up, so anything that doesn't
+    // fit is a hiccup we can only drop:
+    for sample in samples {
+      if !self.sample_tx.push(*sample) {
+        break;
+      }
+    }
+  }
+
+  fn buffer_fill(&self) -> f32 {
+    self.sample_tx.len() as f32 / self.sample_tx.capacity() as f32
   }
-}
 
-fn max_buffer_size(buffer_size: &cpal::SupportedBufferSize) -> usize {
-  match *buffer_size {
-    cpal::SupportedBufferSize::Range { max, .. } => max as usize,
-    // Some sensible default:
-    _ => 4096,
+  fn play(&mut self) {
+    if let Err(e) = self.stream.play() {
+      eprintln!("Failed to play audio: {}", e);
+    }
+  }
+
+  fn pause(&mut self) {
+    if let Err(e) = self.stream.pause() {
+      eprintln!("Failed to pause audio: {}", e);
+    }
   }
 }
 
-pub fn run<T>(device: &cpal::Device, config: &cpal::StreamConfig, rx: Receiver<f32>) -> cpal::Stream
+fn run<T>(
+  device: &cpal::Device,
+  config: &cpal::StreamConfig,
+  rx: Consumer,
+) -> Result<cpal::Stream, String>
 where
   T: cpal::Sample,
 {
   let channels = config.channels as usize;
 
-  let next_value = move || rx.recv().unwrap();
-
-  // let next_value = move || match rx.try_recv() {
-  //   Ok(v) => v,
-  //   Err(_) => {
-  //     // println!("Nothing sending...");
-  //     0.0
-  //   }
-  // };
+  // If the emulator falls behind, hold the last sample rather than dropping
+  // to silence, which would pop:
+  let mut last_value = 0.0;
+  let mut next_value = move || {
+    if let Some(value) = rx.pop() {
+      last_value = value;
+    }
+    last_value
+  };
 
   device
     .build_output_stream(
@@ -83,5 +110,5 @@ where
       },
       |err| eprintln!("an error occurred on stream: {}", err),
     )
-    .unwrap()
+    .map_err(|e| format!("Failed to open audio stream: {}", e))
 }
@@ -0,0 +1,141 @@
+use std::io;
+use std::path::Path;
+
+use crate::wav::{SampleFormat, WavWriter};
+
+/// Somewhere for the emulator's audio to go: a sound card, a file, a buffer
+/// for a test to look at, or nowhere at all. See `Nes::flush_audio`.
+pub trait AudioSink {
+  /// The rate the sink plays samples at; the `Nes` feeding it should have
+  /// been made with the same rate.
+  fn sample_rate(&self) -> u32;
+
+  /// Takes samples, in the order they came out of the APU.
+  fn write(&mut self, samples: &[f32]);
+
+  /// How full the sink's buffer is, from 0.0 to 1.0, for rate control; see
+  /// `Apu::adjust_rate`. Sinks that take everything as fast as it comes stay
+  /// at 0.5, which leaves the rate alone.
+  fn buffer_fill(&self) -> f32 {
+    0.5
+  }
+
+  fn play(&mut self) {}
+
+  fn pause(&mut self) {}
+
+  /// Finishes off whatever the sink was writing to. Nothing written after
+  /// this is kept.
+  fn finish(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Throws everything away, e.g. for machines without a sound card.
+pub struct NullSink {
+  sample_rate: u32,
+}
+
+impl NullSink {
+  pub fn new(sample_rate: u32) -> Self {
+    NullSink { sample_rate }
+  }
+}
+
+impl AudioSink for NullSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, _samples: &[f32]) {}
+}
+
+/// Keeps every sample, for tests to assert on.
+pub struct BufferSink {
+  sample_rate: u32,
+  pub samples: Vec<f32>,
+}
+
+impl BufferSink {
+  pub fn new(sample_rate: u32) -> Self {
+    BufferSink {
+      sample_rate,
+      samples: vec![],
+    }
+  }
+}
+
+impl AudioSink for BufferSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, samples: &[f32]) {
+    self.samples.extend_from_slice(samples);
+  }
+}
+
+/// Writes everything to a WAV file.
+pub struct WavSink {
+  sample_rate: u32,
+  /// Gone once we've finished
+  writer: Option<WavWriter>,
+}
+
+impl WavSink {
+  pub fn create<P: AsRef<Path>>(
+    path: P,
+    sample_rate: u32,
+    format: SampleFormat,
+  ) -> io::Result<WavSink> {
+    Ok(WavSink {
+      sample_rate,
+      writer: Some(WavWriter::create(path, sample_rate, format)?),
+    })
+  }
+}
+
+impl AudioSink for WavSink {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn write(&mut self, samples: &[f32]) {
+    if let Some(writer) = &mut self.writer {
+      for sample in samples {
+        writer.write_sample(*sample);
+      }
+    }
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    match self.writer.take() {
+      Some(writer) => writer.finish(),
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn wav_sink() {
+    let path = std::env::temp_dir().join(format!("nessers-sink-{}.wav", std::process::id()));
+    let mut sink = WavSink::create(&path, 44_100, SampleFormat::Pcm16).unwrap();
+    sink.write(&[0.0, 0.5, -0.5]);
+    sink.finish().unwrap();
+    // Too late:
+    sink.write(&[1.0]);
+    sink.finish().unwrap();
+
+    let wav = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + 3 * 2);
+    assert_eq!(&wav[40..44], &6u32.to_le_bytes());
+    assert_eq!(&wav[46..48], &16383i16.to_le_bytes());
+  }
+}
@@ -0,0 +1,180 @@
+//! Runs test ROMs that report their results the way blargg's do.
+//!
+//! From the readme that ships with most of them:
+//!
+//! > Text output and the final result are written to memory at $6000. All
+//! > tests write a signature at $6001-$6003 (DE B0 61) once the text output
+//! > is valid. $6000 holds the status: $80 means the test is still running,
+//! > $81 means the test needs the reset button pressed (no sooner than 100
+//! > msec from now), and $00-$7F is the final result code, 0 being a pass.
+//! > $6004 onwards holds the zero-terminated text output.
+
+use crate::nes::Nes;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT: u16 = 0x6004;
+
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+
+// "No sooner than 100 msec"; 6 frames is a touch over that.
+const RESET_DELAY_FRAMES: u32 = 6;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+  Passed,
+  /// The ROM finished with a nonzero result code.
+  Failed(u8),
+  /// The ROM never reported a result within the frames we gave it.
+  TimedOut,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TestResult {
+  pub status: Status,
+  /// Whatever the ROM had written to $6004 by the time we stopped.
+  pub message: String,
+}
+
+/// Runs a test ROM (already loaded and reset) for up to `max_frames` frames,
+/// pressing reset whenever it asks for it.
+pub fn run(nes: &mut Nes, max_frames: u32) -> TestResult {
+  let mut reset_in: Option<u32> = None;
+
+  for _ in 0..max_frames {
+    nes.frame();
+
+    if !has_signature(nes) {
+      continue;
+    }
+
+    match nes.safe_cpu_read(STATUS) {
+      RUNNING => {}
+      NEEDS_RESET => {
+        reset_in = match reset_in {
+          None => Some(RESET_DELAY_FRAMES),
+          Some(0) => {
+            nes.reset();
+            None
+          }
+          Some(n) => Some(n - 1),
+        };
+      }
+      0x00 => {
+        return TestResult {
+          status: Status::Passed,
+          message: message(nes),
+        }
+      }
+      code => {
+        return TestResult {
+          status: Status::Failed(code),
+          message: message(nes),
+        }
+      }
+    }
+  }
+
+  TestResult {
+    status: Status::TimedOut,
+    message: if has_signature(nes) {
+      message(nes)
+    } else {
+      String::new()
+    },
+  }
+}
+
+fn has_signature(nes: &Nes) -> bool {
+  (0..SIGNATURE.len()).all(|i| nes.safe_cpu_read(STATUS + 1 + i as u16) == SIGNATURE[i])
+}
+
+fn message(nes: &Nes) -> String {
+  let mut text = vec![];
+  // The rest of PRG RAM is the most there could possibly be:
+  for addr in TEXT..=0x7FFF {
+    match nes.safe_cpu_read(addr) {
+      0x00 => break,
+      c => text.push(c),
+    }
+  }
+  String::from_utf8_lossy(&text).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::Palette;
+
+  // A tiny MMC1 ROM that speaks the protocol: on first boot it asks to be
+  // reset, and after that it prints "OK" and finishes with `code`.
+  fn make_rom(code: u8) -> Vec<u8> {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x10, 0x00];
+    rom.resize(16, 0x00);
+
+    #[rustfmt::skip]
+    let program = vec![
+      0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE; STA $6001
+      0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0; STA $6002
+      0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61; STA $6003
+      0xAD, 0x00, 0x61,             // LDA $6100
+      0xD0, 0x0D,                   // BNE +13; been here before
+      0xA9, 0x01, 0x8D, 0x00, 0x61, // LDA #$01; STA $6100
+      0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81; STA $6000
+      0x4C, 0x00, 0xC1,             // JMP $C100
+      0xA9, 0x80, 0x8D, 0x00, 0x60, // LDA #$80; STA $6000
+      0xA9, b'O', 0x8D, 0x04, 0x60, // LDA #'O'; STA $6004
+      0xA9, b'K', 0x8D, 0x05, 0x60, // LDA #'K'; STA $6005
+      0xA9, 0x00, 0x8D, 0x06, 0x60, // LDA #$00; STA $6006
+      0xA9, code, 0x8D, 0x00, 0x60, // LDA #code; STA $6000
+      0x4C, 0x00, 0xC1,             // JMP $C100
+    ];
+
+    // MMC1 powers up with the last bank fixed at $C000:
+    let mut prg = vec![0xEA; 0x8000];
+    prg[0x4000..0x4000 + program.len()].copy_from_slice(&program);
+    // $C100: JMP $C100
+    prg[0x4100..0x4103].copy_from_slice(&[0x4C, 0x00, 0xC1]);
+    // NMI, reset and IRQ vectors all point at the start:
+    prg[0x7FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+
+    rom.extend(prg);
+    rom.extend(vec![0x00; 0x2000]);
+    rom
+  }
+
+  fn run_rom(code: u8, max_frames: u32) -> TestResult {
+    let mut nes = Nes::from_rom(44_100.0, &make_rom(code), Palette::new()).unwrap();
+    nes.reset();
+    run(&mut nes, max_frames)
+  }
+
+  #[test]
+  fn passes() {
+    assert_eq!(
+      run_rom(0x00, 60),
+      TestResult {
+        status: Status::Passed,
+        message: "OK".into(),
+      }
+    );
+  }
+
+  #[test]
+  fn fails() {
+    assert_eq!(
+      run_rom(0x03, 60),
+      TestResult {
+        status: Status::Failed(0x03),
+        message: "OK".into(),
+      }
+    );
+  }
+
+  #[test]
+  fn times_out() {
+    // Not long enough to get past the reset request:
+    assert_eq!(run_rom(0x00, 3).status, Status::TimedOut);
+  }
+}
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// How many output samples each step gets spread across. Wider is closer to an
+/// ideal low-pass, at the cost of latency (half this many samples) and time.
+const WIDTH: usize = 16;
+
+/// How many sub-sample positions a step can start at.
+const PHASES: usize = 64;
+
+/// Where the band's edge goes, as a fraction of the output's Nyquist frequency.
+/// A bit shy of 1.0 leaves room for the kernel's roll-off.
+const CUTOFF: f64 = 0.90;
+
+/// A band-limited step synthesizer, à la Shay Green's blip_buf.
+///
+/// The APU's channels all output flat levels that jump from one value to the
+/// next, millions of times a second. Sampling those directly at 44.1kHz
+/// aliases horribly, but since everything is a sum of steps we can do better:
+/// instead of the level itself, we record how much it changed and when, and
+/// each change gets drawn as a step that's been low-passed just under the
+/// output's Nyquist frequency. Summing the changes back up gives an
+/// alias-free signal, at whatever sample rate we like.
+///
+/// It costs `WIDTH` multiply-adds per change, and nothing at all while the
+/// level holds still.
+///
+/// https://www.slack.net/~ant/bl-synth/
+#[derive(Clone)]
+pub struct BlipBuf {
+  /// Output samples per emulated clock
+  samples_per_clock: f64,
+  /// When the current clock falls, in samples since `deltas[0]`
+  time: f64,
+  /// Changes in level, one slot per output sample, not yet summed up
+  deltas: VecDeque<f32>,
+  /// The running sum of every delta that's been read out
+  integrator: f32,
+  /// One band-limited step per phase, as the difference between each sample
+  /// and the one before it; each sums to 1.0
+  kernel: Vec<[f32; WIDTH]>,
+}
+
+impl BlipBuf {
+  pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+    BlipBuf {
+      samples_per_clock: sample_rate / clock_rate,
+      time: 0.0,
+      deltas: VecDeque::from(vec![0.0; WIDTH]),
+      integrator: 0.0,
+      kernel: make_kernel(),
+    }
+  }
+
+  /// Changes how many samples come out per clock, from the next clock on.
+  /// Anything already added stays where it was.
+  pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+    self.samples_per_clock = sample_rate / clock_rate;
+  }
+
+  /// Changes the output level by `delta`, as of the current clock.
+  pub fn add_delta(&mut self, delta: f32) {
+    let whole = self.time.floor();
+    let phase = (((self.time - whole) * PHASES as f64) as usize).min(PHASES - 1);
+    let start = whole as usize;
+
+    if self.deltas.len() < start + WIDTH {
+      self.deltas.resize(start + WIDTH, 0.0);
+    }
+    for (i, k) in self.kernel[phase].iter().enumerate() {
+      self.deltas[start + i] += delta * k;
+    }
+  }
+
+  /// Drops every delta, going back to a level of nothing, but keeps the same
+  /// timing; a cleared copy of a `BlipBuf` produces its samples in lockstep
+  /// with the original.
+  pub fn clear(&mut self) {
+    self.deltas.iter_mut().for_each(|delta| *delta = 0.0);
+    self.integrator = 0.0;
+  }
+
+  /// Moves on to the next clock.
+  pub fn clock(&mut self) {
+    self.time += self.samples_per_clock;
+    if self.deltas.len() < (self.time as usize) + WIDTH {
+      self.deltas.resize((self.time as usize) + WIDTH, 0.0);
+    }
+  }
+
+  /// How many samples are finished, i.e. are past the reach of any delta that
+  /// could still be added.
+  pub fn samples_avail(&self) -> usize {
+    self.time as usize
+  }
+
+  /// Takes the oldest finished sample, if there is one.
+  pub fn read_sample(&mut self) -> Option<f32> {
+    if self.samples_avail() == 0 {
+      return None;
+    }
+
+    self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+    self.time -= 1.0;
+    Some(self.integrator)
+  }
+}
+
+/// Builds a windowed sinc for each phase, and turns it into the differences a
+/// step of that phase makes from one sample to the next.
+fn make_kernel() -> Vec<[f32; WIDTH]> {
+  let half = (WIDTH / 2) as f64;
+  (0..PHASES)
+    .map(|phase| {
+      let offset = phase as f64 / PHASES as f64;
+      let mut taps = [0.0f64; WIDTH];
+      for (i, tap) in taps.iter_mut().enumerate() {
+        // Centered on the middle of the kernel; this is where the latency
+        // comes from.
+        let x = i as f64 - half - offset + 1.0;
+        let sinc = if x == 0.0 {
+          1.0
+        } else {
+          (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+        };
+        // Blackman window over the kernel's width:
+        let w = (x + half) / (WIDTH as f64);
+        let window = if w <= 0.0 || w >= 1.0 {
+          0.0
+        } else {
+          0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos()
+        };
+        *tap = sinc * window;
+      }
+
+      // Normalize so every step ends up exactly as tall as its delta:
+      let sum: f64 = taps.iter().sum();
+      let mut kernel = [0.0f32; WIDTH];
+      for (k, tap) in kernel.iter_mut().zip(taps.iter()) {
+        *k = (tap / sum) as f32;
+      }
+      kernel
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn steps_settle_at_their_delta() {
+    let mut blip = BlipBuf::new(1_789_773.0, 44_100.0);
+    blip.add_delta(0.5);
+    for _ in 0..2_000 {
+      blip.clock();
+    }
+
+    let samples: Vec<f32> = std::iter::from_fn(|| blip.read_sample()).collect();
+    assert!(samples.len() >= 49);
+    assert!((samples[samples.len() - 1] - 0.5).abs() < 0.0001);
+    // Band-limiting smears the step out a bit, but it starts at nothing:
+    assert!(samples[0].abs() < 0.01);
+  }
+
+  #[test]
+  fn ultrasonic_squares_average_out() {
+    // A 100kHz square wave is way past what 44.1kHz can hold, so all that
+    // should come out is its average:
+    let mut blip = BlipBuf::new(1_789_773.0, 44_100.0);
+    let mut level = 0.0;
+    let mut samples = vec![];
+    for clock in 0..100_000 {
+      let target = if (clock / 9) % 2 == 0 { 1.0 } else { 0.0 };
+      if target != level {
+        blip.add_delta(target - level);
+        level = target;
+      }
+      blip.clock();
+      while let Some(sample) = blip.read_sample() {
+        samples.push(sample);
+      }
+    }
+
+    for sample in &samples[100..] {
+      assert!((sample - 0.5).abs() < 0.05, "{}", sample);
+    }
+  }
+}
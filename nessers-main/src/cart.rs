@@ -1,9 +1,14 @@
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
 
 use crate::mapper::{
   m000::M000, m001::M001, m002::M002, m003::M003, m004::M004, m009::M009, m069::M069,
   MappedRead::*, MappedWrite::*, Mapper, MXXX,
 };
+use crate::savestate::{self, load_fixed_len, SaveState, StateReader, StateWriter};
 
 const HEADER_START: [u8; 4] = [
   0x4E, // N
@@ -17,13 +22,21 @@ pub struct Cart {
   hw_mirroring: Mirroring,
   has_ram: bool,
   has_trainer: bool,
-  pub mapper_code: u8,
+  pub header: CartHeader,
+  pub mapper_code: u16,
   pub mapper: Box<dyn Mapper>,
   prg: Vec<u8>,
   chr: Vec<u8>,
+
+  // Battery-backed PRG RAM persistence; see `Cart::flush_save_ram`.
+  save_path: Option<PathBuf>,
+  saved_ram: Vec<u8>,
+  autosave_interval: Option<Duration>,
+  last_autosave: Instant,
 }
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub enum Mirroring {
+  #[default]
   Horizontal,
   Vertical,
   OneScreenLo,
@@ -35,92 +48,462 @@ pub const HEADER_SIZE: usize = 16;
 pub const FLAG_MIRRORING: u8 = 0b0000_0001;
 pub const FLAG_HAS_RAM: u8 = 0b000_00010;
 pub const FLAG_HAS_TRAINER: u8 = 0b0000_0100;
+pub const FLAG_FOUR_SCREEN: u8 = 0b0000_1000;
 
-impl Cart {
-  pub fn new(data: &Vec<u8>) -> Result<Cart, &'static str> {
-    // Bytes 0-3: Should indicate that this is an iNES file:
-    if data[0..4] != HEADER_START {
-      return Err("Does not appear to be in the iNES format");
-    }
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum HeaderFormat {
+  /// "Archaic" iNES; bytes 7-15 are unreliable (often garbage like
+  /// "DiskDude!"), so we only trust bytes 4-6.
+  ArchaicINes,
+  INes,
+  Nes2,
+}
 
-    if data.len() < 16 {
+/// CPU/PPU timing, from byte 12 of an NES 2.0 header (or byte 9 of iNES).
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Timing {
+  /// RP2C02 ("NTSC NES")
+  Ntsc,
+  /// RP2C07 ("Licensed PAL NES")
+  Pal,
+  /// Identical ROM content in both NTSC and PAL countries.
+  MultiRegion,
+  /// UMC 6527P ("Dendy")
+  Dendy,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ConsoleType {
+  /// Nintendo Entertainment System/Family Computer
+  Nes,
+  /// Nintendo Vs. System, with its PPU type and hardware type from byte 13.
+  VsSystem { ppu_type: u8, hardware_type: u8 },
+  /// Nintendo Playchoice 10
+  Playchoice10,
+  /// Extended console type from byte 13 (Famiclone with decimal mode, VT0x,
+  /// etc.)
+  Extended(u8),
+}
+
+/// Everything we know about the cart from its 16-byte header.
+///
+/// All sizes are in bytes. For plain iNES files, the fields that only exist
+/// in NES 2.0 are filled in with the same defaults most emulators use (e.g.
+/// 8KB of PRG RAM, and 8KB of CHR RAM if there's no CHR ROM).
+///
+/// See https://www.nesdev.org/wiki/NES_2.0
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct CartHeader {
+  pub format: HeaderFormat,
+  pub mapper: u16,
+  pub submapper: u8,
+  pub prg_rom_size: usize,
+  pub chr_rom_size: usize,
+  pub prg_ram_size: usize,
+  pub prg_nvram_size: usize,
+  pub chr_ram_size: usize,
+  pub chr_nvram_size: usize,
+  pub mirroring: Mirroring,
+  pub four_screen: bool,
+  pub has_battery: bool,
+  pub has_trainer: bool,
+  pub timing: Timing,
+  pub console_type: ConsoleType,
+  pub misc_roms: u8,
+  /// Default expansion device; see
+  /// https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+  ///
+  /// `0x01` is the standard NES/Famicom controllers, `0x00` is unspecified.
+  pub expansion_device: u8,
+}
+
+impl CartHeader {
+  pub fn new(data: &[u8]) -> Result<CartHeader, &'static str> {
+    if data.len() < HEADER_SIZE {
       return Err("Too small to contain header");
     }
 
-    let format_version = (data[7] & 0b00001100) >> 2;
-    println!("iNES format version: {}", format_version);
+    // Bytes 0-3: Should indicate that this is an iNES file:
+    if data[0..4] != HEADER_START {
+      return Err("Does not appear to be in the iNES format");
+    }
 
     let flags_6 = data[6];
-    let mapper_code_lo = flags_6 & 0xF0;
-    let mapper_code_hi = data[7] & 0xF0;
-    let mapper_code = mapper_code_hi | (mapper_code_lo >> 4);
-    println!("Cart mapper code: {:03}", mapper_code);
-
-    // if format_version != 1 {
-    //   return Err("iNES 1.0 format is the only supported format");
-    // }
-
-    // Byte 4: Size of PRG ROM in 16KB increments
-    let num_prg_banks = data[4] as usize;
-    let prg_size = num_prg_banks * 16 * 1024;
+    let flags_7 = data[7];
 
-    // Byte 5: Size of CHR ROM in 8KB increments
-    let num_chr_banks = data[5] as usize;
-    let chr_size = num_chr_banks * 8 * 1024;
+    // Bits 2-3 of byte 7 tell us which flavor of header we're dealing with:
+    //
+    // - `10` means NES 2.0
+    // - `00` with bytes 12-15 all zero means iNES
+    // - anything else is "archaic" iNES, where bytes 7-15 were probably
+    //   scribbled on by a ROM dumping tool
+    let format = match (flags_7 & 0b0000_1100, data[12..16] == [0x00; 4]) {
+      (0b0000_1000, _) => HeaderFormat::Nes2,
+      (0b0000_0000, true) => HeaderFormat::INes,
+      _ => HeaderFormat::ArchaicINes,
+    };
 
-    let hw_mirroring = if flags_6 & FLAG_MIRRORING != 0 {
+    let mirroring = if flags_6 & FLAG_MIRRORING != 0 {
       Mirroring::Vertical
     } else {
       Mirroring::Horizontal
     };
-
-    let has_ram = flags_6 & FLAG_HAS_RAM != 0;
+    let has_battery = flags_6 & FLAG_HAS_RAM != 0;
     let has_trainer = flags_6 & FLAG_HAS_TRAINER != 0;
+    let four_screen = flags_6 & FLAG_FOUR_SCREEN != 0;
+
+    let mapper_lo = ((flags_6 & 0xF0) >> 4) as u16;
+    let mapper_mid = (flags_7 & 0xF0) as u16;
+
+    match format {
+      HeaderFormat::Nes2 => {
+        // Byte 8: Mapper MSB/Submapper
+        //
+        // ```
+        // 7654 3210
+        // ---------
+        // SSSS NNNN
+        // |||| ++++- Mapper number D8..D11
+        // ++++------ Submapper number
+        // ```
+        let mapper = mapper_lo | mapper_mid | (((data[8] & 0x0F) as u16) << 8);
+        let submapper = (data[8] & 0xF0) >> 4;
+
+        // Byte 9: PRG-ROM/CHR-ROM size MSB
+        let prg_rom_size = rom_size(data[4], data[9] & 0x0F, 16 * 1024);
+        let chr_rom_size = rom_size(data[5], (data[9] & 0xF0) >> 4, 8 * 1024);
+
+        let console_type = match flags_7 & 0b0000_0011 {
+          0 => ConsoleType::Nes,
+          1 => ConsoleType::VsSystem {
+            ppu_type: data[13] & 0x0F,
+            hardware_type: (data[13] & 0xF0) >> 4,
+          },
+          2 => ConsoleType::Playchoice10,
+          _ => ConsoleType::Extended(data[13] & 0x0F),
+        };
+
+        Ok(CartHeader {
+          format,
+          mapper,
+          submapper,
+          prg_rom_size,
+          chr_rom_size,
+          // Byte 10: PRG-RAM/EEPROM size
+          prg_ram_size: shift_size(data[10] & 0x0F),
+          prg_nvram_size: shift_size((data[10] & 0xF0) >> 4),
+          // Byte 11: CHR-RAM size
+          chr_ram_size: shift_size(data[11] & 0x0F),
+          chr_nvram_size: shift_size((data[11] & 0xF0) >> 4),
+          mirroring,
+          four_screen,
+          has_battery,
+          has_trainer,
+          // Byte 12: CPU/PPU Timing
+          timing: match data[12] & 0b0000_0011 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+          },
+          console_type,
+          // Byte 14: Miscellaneous ROMs
+          misc_roms: data[14] & 0b0000_0011,
+          // Byte 15: Default Expansion Device
+          expansion_device: data[15] & 0b0011_1111,
+        })
+      }
+      HeaderFormat::INes | HeaderFormat::ArchaicINes => {
+        let archaic = format == HeaderFormat::ArchaicINes;
+
+        // Byte 4: Size of PRG ROM in 16KB increments
+        let prg_rom_size = data[4] as usize * 16 * 1024;
+        // Byte 5: Size of CHR ROM in 8KB increments
+        let chr_rom_size = data[5] as usize * 8 * 1024;
+
+        // Byte 8: PRG RAM size in 8KB increments; a value of 0 infers 8KB for
+        // compatibility.
+        let prg_ram_size = if archaic || data[8] == 0 {
+          8 * 1024
+        } else {
+          data[8] as usize * 8 * 1024
+        };
+
+        // iNES has no way to tell us how much of the PRG RAM is battery-backed,
+        // so if the battery flag is set we assume all of it is.
+        let (prg_ram_size, prg_nvram_size) = if has_battery {
+          (0, prg_ram_size)
+        } else {
+          (prg_ram_size, 0)
+        };
+
+        Ok(CartHeader {
+          format,
+          mapper: if archaic {
+            mapper_lo
+          } else {
+            mapper_lo | mapper_mid
+          },
+          submapper: 0,
+          prg_rom_size,
+          chr_rom_size,
+          prg_ram_size,
+          prg_nvram_size,
+          chr_ram_size: if chr_rom_size == 0 { 8 * 1024 } else { 0 },
+          chr_nvram_size: 0,
+          mirroring,
+          four_screen,
+          has_battery,
+          has_trainer,
+          // Byte 9: TV system (rarely used)
+          timing: if !archaic && (data[9] & 0b0000_0001) != 0 {
+            Timing::Pal
+          } else {
+            Timing::Ntsc
+          },
+          console_type: match (archaic, flags_7 & 0b0000_0011) {
+            (false, 1) => ConsoleType::VsSystem {
+              ppu_type: 0,
+              hardware_type: 0,
+            },
+            (false, 2) => ConsoleType::Playchoice10,
+            _ => ConsoleType::Nes,
+          },
+          misc_roms: 0,
+          expansion_device: 0,
+        })
+      }
+    }
+  }
 
-    let prg_start = if has_trainer {
+  /// Total PRG RAM, volatile or not; this is what lives at $6000-$7FFF on most
+  /// mappers.
+  pub fn total_prg_ram_size(&self) -> usize {
+    self.prg_ram_size + self.prg_nvram_size
+  }
+
+  /// Total CHR RAM, volatile or not.
+  pub fn total_chr_ram_size(&self) -> usize {
+    self.chr_ram_size + self.chr_nvram_size
+  }
+}
+
+/// NES 2.0 ROM sizes are either a plain 12-bit count of `unit`-sized banks, or
+/// when the MSB nybble is `$F`, an exponent-multiplier pair packed into the LSB:
+///
+/// ```text
+/// ++++++----- Exponent (E)
+/// ||||||++--- Multiplier (MM)
+/// EEEE EEMM
+///
+/// size = 2^E * (MM*2+1) bytes
+/// ```
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+  if msb == 0x0F {
+    let exponent = (lsb >> 2) as u32;
+    let multiplier = ((lsb & 0b0000_0011) as usize) * 2 + 1;
+    2usize.saturating_pow(exponent).saturating_mul(multiplier)
+  } else {
+    (((msb as usize) << 8) | lsb as usize) * unit
+  }
+}
+
+/// NES 2.0 RAM sizes are stored as shift counts: `64 << n` bytes, or nothing at
+/// all if `n` is zero.
+fn shift_size(shift: u8) -> usize {
+  if shift == 0 {
+    0
+  } else {
+    64 << shift
+  }
+}
+
+impl Cart {
+  pub fn new(data: &Vec<u8>) -> Result<Cart, &'static str> {
+    let header = CartHeader::new(data)?;
+    info!("Cart header format: {:?}", header.format);
+
+    let mapper_code = header.mapper;
+    info!(
+      "Cart mapper code: {:03} (submapper {})",
+      mapper_code, header.submapper
+    );
+
+    // The rest of the emulator still thinks of PRG ROM in terms of 16KB banks.
+    // NES 2.0 can describe smaller ROMs than that, which get mirrored to fill a
+    // whole bank, the same as they would with the top address lines unused:
+    let prg_size = header.prg_rom_size;
+    if prg_size == 0 {
+      return Err("Cart has no PRG ROM");
+    }
+    let num_prg_banks = prg_size.div_ceil(16 * 1024);
+
+    let chr_size = header.chr_rom_size;
+    let num_chr_banks = chr_size / (8 * 1024);
+
+    let prg_start = if header.has_trainer {
       HEADER_SIZE + 512
     } else {
       HEADER_SIZE
     };
     let chr_start = prg_start + prg_size;
 
+    if data.len() < prg_start + prg_size {
+      return Err("File is too small to contain reported PRG data");
+    }
+
     if chr_size > 0 && data.len() < chr_start + chr_size {
-      println!(
-        "Warning: File is too small to contain reported CHR data. Expected minimum of {} bytes but file is {} bytes.",
+      warn!(
+        "File is too small to contain reported CHR data. Expected minimum of {} bytes but file is {} bytes.",
         chr_start + chr_size,
         data.len()
       );
     }
 
+    let prg_ram_size = header.total_prg_ram_size();
     let mapper: Box<dyn Mapper> = match mapper_code {
-      000 => Box::new(M000::new(num_prg_banks)),
-      001 => Box::new(M001::new(num_prg_banks)),
+      000 => Box::new(M000::new(num_prg_banks, prg_ram_size)),
+      001 => Box::new(M001::new(num_prg_banks, prg_ram_size)),
       002 => Box::new(M002::new(num_prg_banks)),
       003 => Box::new(M003::new(num_prg_banks)),
-      004 => Box::new(M004::new(num_prg_banks)),
-      009 => Box::new(M009::new(num_prg_banks)),
-      069 => Box::new(M069::new(num_prg_banks, num_chr_banks)),
+      004 => Box::new(M004::new(num_prg_banks, prg_ram_size)),
+      009 => Box::new(M009::new(num_prg_banks, prg_ram_size)),
+      069 => Box::new(M069::new(num_prg_banks, num_chr_banks, prg_ram_size)),
       n => Box::new(MXXX::new(n)),
     };
 
+    let chr_ram_size = match header.total_chr_ram_size() {
+      0 => 8 * 1024,
+      n => n,
+    };
+
+    // Whatever the mapper starts with is "already saved", so that we don't
+    // write out a save file for a game that never touched its RAM:
+    let saved_ram = mapper
+      .save_ram()
+      .map(|ram| ram.to_vec())
+      .unwrap_or_default();
+
     Ok(Cart {
-      hw_mirroring,
-      has_ram,
-      has_trainer,
+      hw_mirroring: header.mirroring,
+      has_ram: header.has_battery,
+      has_trainer: header.has_trainer,
+      header,
       mapper_code,
       mapper,
       chr: if chr_size > 0 {
         data[chr_start..(chr_start + chr_size).min(data.len())].to_vec()
       } else {
-        vec![0x00; 1024 * 8]
+        vec![0x00; chr_ram_size]
       },
-      prg: data[prg_start..prg_start + prg_size].to_vec(),
+      prg: data[prg_start..prg_start + prg_size]
+        .iter()
+        .copied()
+        .cycle()
+        .take(num_prg_banks * 16 * 1024)
+        .collect(),
+      save_path: None,
+      saved_ram,
+      autosave_interval: None,
+      last_autosave: Instant::now(),
     })
   }
 
+  /// Loads a cart from a file; if the cart has battery-backed RAM, it will be
+  /// loaded from (and later flushed to) a `.sav` file next to the ROM.
   pub fn from_file(filename: &str) -> Result<Cart, &'static str> {
     let contents = fs::read(filename).expect(&format!("Failure reading {}", filename));
-    Cart::new(&contents)
+    let mut cart = Cart::new(&contents)?;
+    if cart.has_battery() {
+      cart.set_save_path(Path::new(filename).with_extension("sav"))?;
+    }
+    Ok(cart)
+  }
+
+  /// True if the header says there's a battery and the mapper actually has RAM
+  /// for it to keep alive.
+  pub fn has_battery(&self) -> bool {
+    self.header.has_battery && self.mapper.save_ram().is_some()
+  }
+
+  /// Sets where battery-backed RAM is persisted, loading it from that file if
+  /// it already exists.
+  pub fn set_save_path(&mut self, path: PathBuf) -> Result<(), &'static str> {
+    if path.exists() {
+      let data = fs::read(&path).map_err(|_| "Failed to read save file")?;
+      self.load_save_ram(&data);
+      info!("Loaded save RAM from {}", path.display());
+    }
+    self.save_path = Some(path);
+    Ok(())
+  }
+
+  pub fn save_path(&self) -> Option<&Path> {
+    self.save_path.as_deref()
+  }
+
+  /// Copies `data` into the mapper's save RAM.
+  ///
+  /// Save files from other emulators aren't always the same size as our RAM,
+  /// so we copy as much as fits rather than rejecting them outright.
+  pub fn load_save_ram(&mut self, data: &[u8]) {
+    if let Some(ram) = self.mapper.save_ram_mut() {
+      if data.len() != ram.len() {
+        warn!(
+          "Save RAM is {} bytes but cart has {} bytes of PRG RAM.",
+          data.len(),
+          ram.len()
+        );
+      }
+      let len = data.len().min(ram.len());
+      ram[..len].copy_from_slice(&data[..len]);
+      self.saved_ram = ram.to_vec();
+    }
+  }
+
+  /// Writes battery-backed RAM to the save file, if there's anything new to
+  /// write.
+  pub fn flush_save_ram(&mut self) -> Result<(), &'static str> {
+    if !self.has_battery() {
+      return Ok(());
+    }
+
+    let path = match &self.save_path {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+
+    let ram = match self.mapper.save_ram() {
+      Some(ram) => ram,
+      None => return Ok(()),
+    };
+
+    if ram == &self.saved_ram[..] {
+      return Ok(());
+    }
+
+    fs::write(path, ram).map_err(|_| "Failed to write save file")?;
+    self.saved_ram = ram.to_vec();
+    Ok(())
+  }
+
+  /// Flush battery-backed RAM every `interval`, or never if `None`.
+  ///
+  /// Nothing happens until `autosave` is called; the frontend is expected to
+  /// call it regularly (e.g. once per frame).
+  pub fn set_autosave_interval(&mut self, interval: Option<Duration>) {
+    self.autosave_interval = interval;
+    self.last_autosave = Instant::now();
+  }
+
+  pub fn autosave(&mut self) -> Result<(), &'static str> {
+    match self.autosave_interval {
+      Some(interval) if self.last_autosave.elapsed() >= interval => {
+        self.last_autosave = Instant::now();
+        self.flush_save_ram()
+      }
+      _ => Ok(()),
+    }
   }
 
   pub fn safe_cpu_read(&self, addr: u16) -> Option<u8> {
@@ -149,6 +532,14 @@ impl Cart {
     }
   }
 
+  pub fn safe_ppu_read(&self, addr: u16) -> Option<u8> {
+    match self.mapper.safe_ppu_read(addr) {
+      RAddr(mapped_addr) => Some(self.chr[mapped_addr % self.chr.len()]),
+      Data(data) => Some(data),
+      RSkip => None,
+    }
+  }
+
   pub fn ppu_read(&mut self, addr: u16) -> Option<u8> {
     match self.mapper.ppu_read(addr) {
       RAddr(mapped_addr) => Some(self.chr[(mapped_addr % self.chr.len()) as usize]),
@@ -178,6 +569,54 @@ impl Cart {
   pub fn reset(&mut self) {
     self.mapper.reset();
   }
+
+  /// Identifies the cart's contents for save states.
+  pub fn hash(&self) -> u64 {
+    savestate::hash(&self.prg)
+  }
+}
+
+impl SaveState for Cart {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.mapper.save_state(w);
+    // CHR ROM can't change, but CHR RAM can:
+    if self.header.chr_rom_size == 0 {
+      self.chr.save_state(w);
+    }
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.mapper.load_state(r)?;
+    if self.header.chr_rom_size == 0 {
+      load_fixed_len(&mut self.chr, r)?;
+    }
+    Ok(())
+  }
+}
+
+impl SaveState for Mirroring {
+  fn save_state(&self, w: &mut StateWriter) {
+    let v: u8 = match self {
+      Mirroring::Horizontal => 0,
+      Mirroring::Vertical => 1,
+      Mirroring::OneScreenLo => 2,
+      Mirroring::OneScreenHi => 3,
+    };
+    v.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut v: u8 = 0;
+    v.load_state(r)?;
+    *self = match v {
+      0 => Mirroring::Horizontal,
+      1 => Mirroring::Vertical,
+      2 => Mirroring::OneScreenLo,
+      3 => Mirroring::OneScreenHi,
+      _ => return Err("Save state has an invalid mirroring mode"),
+    };
+    Ok(())
+  }
 }
 
 #[cfg(test)]
@@ -237,4 +676,165 @@ mod tests {
       }
     }
   }
+
+  #[test]
+  fn header_ines() {
+    let mut data = HEADER_START.to_vec();
+    data.extend_from_slice(&[0x01, 0x01, FLAG_MIRRORING | FLAG_HAS_RAM, 0x00]);
+    data.resize(HEADER_SIZE, 0x00);
+
+    let header = CartHeader::new(&data).unwrap();
+    assert_eq!(header.format, HeaderFormat::INes);
+    assert_eq!(header.mapper, 0);
+    // iNES can't say how much RAM there is, so a battery means 8K of it:
+    assert_eq!(header.prg_nvram_size, 8 * 1024);
+    assert_eq!(header.timing, Timing::Ntsc);
+  }
+
+  #[test]
+  fn small_prg_rom_is_mirrored() {
+    let mut data = nes2_header([
+      (10 << 2) | 0b00, // PRG ROM: 2^10 * 1
+      0x00,             // CHR RAM
+      0x10,             // Mapper D0..D3 = 1
+      0b0000_1000,      // NES 2.0
+      0x00,
+      0x0F, // PRG ROM uses exponent-multiplier notation
+      0x00,
+      0x07, // CHR RAM: 64 << 7 = 8K
+      0x00,
+      0x00,
+      0x00,
+      0x00,
+    ]);
+    data.extend((0..1024).map(|i| i as u8));
+
+    let cart = Cart::new(&data).unwrap();
+    assert_eq!(cart.prg.len(), 16 * 1024);
+    assert_eq!(cart.prg[0x0401], 0x01);
+    assert_eq!(cart.prg[0x3FFF], 0xFF);
+
+    data[4] = 0x00;
+    data[9] = 0x00;
+    assert_eq!(Cart::new(&data).err(), Some("Cart has no PRG ROM"));
+  }
+
+  fn nes2_header(bytes_4_to_15: [u8; 12]) -> Vec<u8> {
+    let mut data = HEADER_START.to_vec();
+    data.extend_from_slice(&bytes_4_to_15);
+    data
+  }
+
+  #[test]
+  fn header_nes2() {
+    let data = nes2_header([
+      0x02,                // PRG ROM LSB: 2 * 16K
+      0x00,                // CHR ROM LSB: none
+      0x40 | FLAG_HAS_RAM, // Mapper D0..D3 = 4, battery
+      0x10 | 0b0000_1000,  // Mapper D4..D7 = 1, NES 2.0
+      0x31,                // Submapper 3, mapper D8..D11 = 1
+      0x00,                // PRG/CHR ROM MSB
+      0x70,                // PRG NVRAM: 64 << 7 = 8K, no volatile RAM
+      0x07,                // CHR RAM: 64 << 7 = 8K
+      0x01,                // PAL timing
+      0x00,                // Console type details
+      0x00,                // Misc ROMs
+      0x01,                // Standard controllers
+    ]);
+
+    let header = CartHeader::new(&data).unwrap();
+    assert_eq!(header.format, HeaderFormat::Nes2);
+    assert_eq!(header.mapper, 0x114);
+    assert_eq!(header.submapper, 3);
+    assert_eq!(header.prg_rom_size, 32 * 1024);
+    assert_eq!(header.chr_rom_size, 0);
+    assert_eq!(header.prg_ram_size, 0);
+    assert_eq!(header.prg_nvram_size, 8 * 1024);
+    assert_eq!(header.chr_ram_size, 8 * 1024);
+    assert_eq!(header.chr_nvram_size, 0);
+    assert_eq!(header.has_battery, true);
+    assert_eq!(header.timing, Timing::Pal);
+    assert_eq!(header.console_type, ConsoleType::Nes);
+    assert_eq!(header.expansion_device, 0x01);
+  }
+
+  #[test]
+  fn header_nes2_exponent_multiplier() {
+    let data = nes2_header([
+      (10 << 2) | 0b01, // PRG ROM: 2^10 * 3
+      (7 << 2) | 0b00,  // CHR ROM: 2^7 * 1
+      0x00,
+      0b0000_1000 | 0x01, // NES 2.0, Vs. System
+      0x00,
+      0xFF, // Both sizes use exponent-multiplier notation
+      0x00,
+      0x00,
+      0x00,
+      0x23, // Vs. hardware type 2, PPU type 3
+      0x00,
+      0x00,
+    ]);
+
+    let header = CartHeader::new(&data).unwrap();
+    assert_eq!(header.prg_rom_size, 1024 * 3);
+    assert_eq!(header.chr_rom_size, 128);
+    assert_eq!(
+      header.console_type,
+      ConsoleType::VsSystem {
+        ppu_type: 3,
+        hardware_type: 2
+      }
+    );
+  }
+
+  #[test]
+  fn header_archaic_ines() {
+    let mut data = HEADER_START.to_vec();
+    data.extend_from_slice(&[0x01, 0x01, 0x10, 0x40]);
+    data.extend_from_slice(b"DiskDude");
+
+    let header = CartHeader::new(&data).unwrap();
+    assert_eq!(header.format, HeaderFormat::ArchaicINes);
+    // The upper nybble in byte 7 is garbage ('D'), so it must be ignored:
+    assert_eq!(header.mapper, 1);
+  }
+
+  fn battery_cart() -> Cart {
+    let mut data = HEADER_START.to_vec();
+    data.extend_from_slice(&[0x02, 0x00, 0x10 | FLAG_HAS_RAM]);
+    data.resize(HEADER_SIZE + 32 * 1024, 0x00);
+    Cart::new(&data).unwrap()
+  }
+
+  #[test]
+  fn save_ram_roundtrip() {
+    let path = std::env::temp_dir().join(format!("nessers-{}-save_ram.sav", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut cart = battery_cart();
+    assert!(cart.has_battery());
+    cart.set_save_path(path.clone()).unwrap();
+    cart.cpu_write(0x6000, 0x42);
+    cart.cpu_write(0x7FFF, 0x43);
+    cart.flush_save_ram().unwrap();
+
+    let mut cart = battery_cart();
+    assert_eq!(cart.safe_cpu_read(0x6000), Some(0x00));
+    cart.set_save_path(path.clone()).unwrap();
+    assert_eq!(cart.safe_cpu_read(0x6000), Some(0x42));
+    assert_eq!(cart.safe_cpu_read(0x7FFF), Some(0x43));
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn save_ram_skips_unchanged() {
+    let path = std::env::temp_dir().join(format!("nessers-{}-unchanged.sav", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut cart = battery_cart();
+    cart.set_save_path(path.clone()).unwrap();
+    cart.flush_save_ram().unwrap();
+    assert!(!path.exists());
+  }
 }
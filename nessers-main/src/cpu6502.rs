@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::savestate::{SaveState, StateReader, StateWriter};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 
@@ -20,6 +21,30 @@ pub enum StatusFlag {
 }
 use StatusFlag::*;
 
+/// Everything that runs the 7-cycle "push the program counter and status, then
+/// jump through a vector" sequence.
+///
+/// `Brk` is kicked off by an opcode like any other instruction; the rest take
+/// the place of an opcode fetch once the current instruction is done.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Interrupt {
+  #[default]
+  Reset,
+  Nmi,
+  Irq,
+  Brk,
+}
+
+impl Interrupt {
+  fn vector(&self) -> u16 {
+    match self {
+      Interrupt::Reset => PC_INIT_ADDR,
+      Interrupt::Nmi => NMI_POINTER,
+      Interrupt::Irq | Interrupt::Brk => IRQ_POINTER,
+    }
+  }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Cpu {
   /// Processor Status
@@ -35,8 +60,44 @@ pub struct Cpu {
   /// Program Counter
   pub pc: u16,
 
-  /// The numbers of cycles remaining for the current operation
-  pub cycles_left: u8,
+  /// How many cycles of the current instruction have run; 0 means we're
+  /// between instructions, and the next `clock` fetches a new opcode
+  pub cycle: u8,
+  /// The opcode of the current instruction
+  pub opcode: u8,
+  /// The interrupt sequence we're running in place of an opcode, if any
+  pub interrupt: Option<Interrupt>,
+  /// An interrupt that will start once the current instruction is done
+  pub pending_interrupt: Option<Interrupt>,
+
+  // Interrupt inputs; see `set_nmi_line`, `set_irq_line` and
+  // `poll_interrupts`:
+  /// The level of the NMI line, which is edge-triggered
+  pub nmi_line: bool,
+  /// Set when the NMI line goes high during the current cycle
+  pub nmi_detected: bool,
+  /// An NMI that's been detected but not yet taken
+  pub nmi_pending: bool,
+  /// The level of the IRQ line, which is shared and level-triggered
+  pub irq_line: bool,
+  /// The IRQ line as of the end of the last cycle
+  pub irq_pending: bool,
+  /// The interrupt the last poll asked for; it takes the place of the next
+  /// instruction once the current one is done
+  pub interrupt_poll: Option<Interrupt>,
+
+  /// Set when a KIL opcode jams the CPU; nothing but a reset gets it going
+  /// again. `pc` and `opcode` are left pointing at the culprit.
+  pub halted: bool,
+
+  // Internal latches that carry an instruction's progress from one cycle to
+  // the next:
+  /// The effective address (or a zero page pointer, until that's resolved)
+  pub addr: u16,
+  /// The last operand or pointer byte fetched
+  pub data: u8,
+  /// Whether adding an index register to `addr` carried into its high byte
+  pub page_crossed: bool,
 }
 
 pub const STACK_START: u16 = 0x0100;
@@ -58,7 +119,20 @@ impl Cpu {
       y: 0,
       pc: 0,
       s: STACK_INIT,
-      cycles_left: 0,
+      cycle: 0,
+      opcode: 0x00,
+      interrupt: None,
+      pending_interrupt: None,
+      nmi_line: false,
+      nmi_detected: false,
+      nmi_pending: false,
+      irq_line: false,
+      irq_pending: false,
+      interrupt_poll: None,
+      halted: false,
+      addr: 0x0000,
+      data: 0x00,
+      page_crossed: false,
     }
   }
 
@@ -76,10 +150,19 @@ impl Cpu {
     }
   }
 
+  /// Where the CPU jammed, and the opcode that did it, if it's halted.
+  pub fn jammed(&self) -> Option<(u16, u8)> {
+    if self.halted {
+      Some((self.pc, self.opcode))
+    } else {
+      None
+    }
+  }
+
   pub fn step(&mut self, bus: &mut dyn Bus<Cpu>) {
     loop {
       self.clock(bus);
-      if self.cycles_left == 0 {
+      if self.cycle == 0 {
         return;
       }
     }
@@ -96,161 +179,557 @@ impl Cpu {
     data
   }
 
+  /// Runs a single CPU cycle.
+  ///
+  /// Every cycle makes exactly one bus access, on the same cycle the real 6502
+  /// makes it. That includes the "dummy" accesses the hardware makes while it's
+  /// busy with something else: reading the next byte during implied
+  /// instructions, reading from the wrong page before fixing up an indexed
+  /// address, and writing the unmodified value back during read-modify-write
+  /// instructions. Mappers and PPU/APU registers can tell the difference.
+  ///
+  /// See https://www.nesdev.org/6502_cpu.txt for the cycle-by-cycle breakdown
+  /// of every addressing mode.
   pub fn clock(&mut self, bus: &mut dyn Bus<Cpu>) {
-    if self.cycles_left == 0 {
-      let opcode = bus.read(self.pc);
-      self.pc += 1;
-
-      let operation: &Operation = opcode.into();
-
-      self.cycles_left = operation.cycles;
-
-      let addressing_mode: AddressingModeImplementation = match operation.addressing_mode {
-        IMP => imp,
-        IMM => imm,
-        ZP0 => zp0,
-        ZPX => zpx,
-        ZPY => zpy,
-        ABS => abs,
-        ABX => abx,
-        ABY => aby,
-        IND => ind,
-        IZX => izx,
-        IZY => izy,
-        ACC => acc,
-        REL => rel,
-      };
-      let address_mode_result = addressing_mode(self, bus);
-      let instruction: InstructionImplementation = match operation.instruction {
-        ADC => adc,
-        AND => and,
-        ASL => asl,
-        BCC => bcc,
-        BCS => bcs,
-        BEQ => beq,
-        BIT => bit,
-        BMI => bmi,
-        BNE => bne,
-        BPL => bpl,
-        BRK => brk,
-        BVC => bvc,
-        BVS => bvs,
-        CLC => clc,
-        CLD => cld,
-        CLI => cli,
-        CLV => clv,
-        CMP => cmp,
-        CPX => cpx,
-        CPY => cpy,
-        DEC => dec,
-        DEX => dex,
-        DEY => dey,
-        EOR => eor,
-        INC => inc,
-        INX => inx,
-        INY => iny,
-        JMP => jmp,
-        JSR => jsr,
-        LDA => lda,
-        LDX => ldx,
-        LDY => ldy,
-        LSR => lsr,
-        NOP => nop,
-        ORA => ora,
-        PHA => pha,
-        PHP => php,
-        PLA => pla,
-        PLP => plp,
-        ROL => rol,
-        ROR => ror,
-        RTI => rti,
-        RTS => rts,
-        SBC => sbc,
-        SEC => sec,
-        SED => sed,
-        SEI => sei,
-        STA => sta,
-        STX => stx,
-        STY => sty,
-        TAX => tax,
-        TAY => tay,
-        TSX => tsx,
-        TXA => txa,
-        TXS => txs,
-        TYA => tya,
-
-        LAX => lax,
-        SAX => sax,
-        DCP => dcp,
-        ISB => isb,
-        SLO => slo,
-        RLA => rla,
-        SRE => sre,
-        RRA => rra,
+    if self.halted {
+      // A jammed 6502 stops fetching and leaves $FFFF on the address bus. We
+      // stay between instructions, so anything stepping the CPU still gets
+      // control back every cycle:
+      bus.read(0xFFFF);
+      return;
+    }
+
+    self.cycle += 1;
+    self.poll_interrupts();
+
+    let in_interrupt = self.interrupt.is_some();
+    self.run_cycle(bus);
+
+    // Whatever the poll on the second-to-last cycle turned up gets to go next.
+    // Interrupt sequences don't poll, so a handler always gets to run its first
+    // instruction:
+    if self.cycle == 0 && !in_interrupt && !self.halted {
+      self.pending_interrupt = self.interrupt_poll;
+    }
+  }
+
+  /// Samples the interrupt lines, which the 6502 does at the end of every
+  /// cycle. What it samples isn't acted on until the cycle after, so the poll
+  /// that counts (the one at the end of an instruction's second-to-last cycle)
+  /// only sees what the lines were doing up to the cycle before that.
+  ///
+  /// That's also why an instruction that changes the I flag on its last cycle
+  /// (CLI, SEI and PLP) doesn't affect the poll until after the next
+  /// instruction, and why RTI, which changes it earlier, does.
+  ///
+  /// See https://www.nesdev.org/wiki/CPU_interrupts
+  fn poll_interrupts(&mut self) {
+    // Branches poll before their operand fetch, and again before fixing up the
+    // page if they cross one; but not before the last cycle of a taken branch
+    // that stays on the same page, which delays any interrupt for another
+    // instruction:
+    let operation: &Operation = self.opcode.into();
+    let skip = self.cycle == 3 && self.interrupt.is_none() && operation.addressing_mode == REL;
+
+    if !skip {
+      self.interrupt_poll = if self.nmi_pending {
+        Some(Interrupt::Nmi)
+      } else if self.irq_pending && self.get_status(DisableInterrupts) == 0 {
+        Some(Interrupt::Irq)
+      } else {
+        None
       };
-      let instruction_result = instruction(self, bus, &address_mode_result.data);
+    }
+
+    self.nmi_pending |= self.nmi_detected;
+    self.nmi_detected = false;
+    self.irq_pending = self.irq_line;
+  }
+
+  /// Does whatever the current cycle of the current instruction (or interrupt
+  /// sequence) calls for.
+  fn run_cycle(&mut self, bus: &mut dyn Bus<Cpu>) {
+    if self.cycle == 1 {
+      match self.pending_interrupt.take() {
+        Some(interrupt) => {
+          // Interrupts still fetch an opcode, but throw it away without
+          // advancing the program counter:
+          bus.read(self.pc);
+          self.interrupt = Some(interrupt);
+          if interrupt == Interrupt::Nmi {
+            self.nmi_pending = false;
+          }
+        }
+        None => {
+          self.opcode = bus.read(self.pc);
+          self.pc = self.pc.wrapping_add(1);
+          self.page_crossed = false;
+        }
+      }
+      return;
+    }
 
-      if address_mode_result.needs_extra_cycle && instruction_result.may_need_extra_cycle {
-        self.cycles_left += 1;
+    if let Some(interrupt) = self.interrupt {
+      if self.cycle == 2 {
+        bus.read(self.pc);
+      } else {
+        self.interrupt_cycle(bus, interrupt);
       }
+      return;
     }
 
-    self.cycles_left -= 1;
+    let operation: &Operation = self.opcode.into();
+    match operation.instruction {
+      BRK => self.brk(bus),
+      JMP => self.jmp(bus, operation.addressing_mode),
+      JSR => self.jsr(bus),
+      RTS => self.rts(bus),
+      RTI => self.rti(bus),
+      BCC | BCS | BEQ | BMI | BNE | BPL | BVC | BVS => self.branch(bus, operation.instruction),
+      KIL => self.kil(bus),
+      _ => self.operate(bus, operation),
+    }
   }
 
-  // SIGNALS:
-  pub fn sig_reset(&mut self, bus: &mut dyn Bus<Cpu>) {
-    self.a = 0x00;
-    self.x = 0x00;
-    self.y = 0x00;
-    self.s = STACK_SIZE;
-    self.status = 0x00 | (StatusFlag::Unused as u8);
-    self.pc = bus.read16(PC_INIT_ADDR);
+  /// Runs one cycle of an ordinary instruction: first the addressing mode's
+  /// cycles, then reading, writing or read-modify-writing the operand.
+  fn operate(&mut self, bus: &mut dyn Bus<Cpu>, operation: &Operation) {
+    let access = access(operation.instruction);
+    let operand_cycle = self.operand_cycle(operation, access);
+    if self.cycle < operand_cycle {
+      self.address_cycle(bus, operation.addressing_mode);
+      return;
+    }
+
+    let kind = match (
+      operation.addressing_mode,
+      access,
+      self.cycle - operand_cycle,
+    ) {
+      (IMP, _, _) => {
+        // Single-byte instructions read the next byte and ignore it; the ones
+        // that touch the stack have already spent that read and make their
+        // stack access now instead:
+        if self.cycle == 2 {
+          bus.read(self.pc);
+        }
+        Implicit
+      }
+      (ACC, _, _) => {
+        bus.read(self.pc);
+        Accumulator
+      }
+      (IMM, _, _) => {
+        self.addr = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+        AbsoluteAddress
+      }
+      (_, Access::ReadModifyWrite, 0) => {
+        self.data = bus.read(self.addr);
+        return;
+      }
+      (_, Access::ReadModifyWrite, 1) => {
+        // The 6502 writes the unmodified value back while it works out the
+        // new one. MMC1, for one, notices:
+        bus.write(self.addr, self.data);
+        return;
+      }
+      (_, Access::ReadModifyWrite, _) => Latched,
+      _ => AbsoluteAddress,
+    };
+
+    let data = DataSource {
+      kind,
+      addr: self.addr,
+    };
+    let instruction = instruction_implementation(operation.instruction);
+    instruction(self, bus, &data);
+    self.cycle = 0;
+  }
+
+  /// The cycle on which an instruction gets to its operand; i.e. the first
+  /// cycle after its addressing mode is done with the bus.
+  fn operand_cycle(&self, operation: &Operation, access: Access) -> u8 {
+    // Indexing only adds to the low byte of the address at first, and takes
+    // another cycle to fix up the high byte. Reads can skip that cycle when
+    // the index didn't carry; writes can't, since they can't take back a write
+    // to the wrong page:
+    let fixup = if access == Access::Read && !self.page_crossed {
+      0
+    } else {
+      1
+    };
+
+    match operation.addressing_mode {
+      IMP | ACC => match operation.instruction {
+        PHA | PHP => 3,
+        PLA | PLP => 4,
+        _ => 2,
+      },
+      IMM => 2,
+      ZP0 => 3,
+      ZPX | ZPY | ABS => 4,
+      ABX | ABY => 4 + fixup,
+      IZX => 6,
+      IZY => 5 + fixup,
+      IND | REL => unreachable!(
+        "{:?} is only used by jumps and branches",
+        operation.addressing_mode
+      ),
+    }
+  }
+
+  /// Runs one of the cycles an addressing mode spends working out `addr`.
+  fn address_cycle(&mut self, bus: &mut dyn Bus<Cpu>, mode: AddressingMode) {
+    match (mode, self.cycle) {
+      // Stack instructions; a dummy read of the next byte, then (for pulls) a
+      // dummy read of the stack while the stack pointer is incremented:
+      (IMP, 2) => {
+        bus.read(self.pc);
+      }
+      (IMP, _) => {
+        bus.read(STACK_START + (self.s as u16));
+      }
+
+      // The first operand byte; the whole address for zero page modes, the low
+      // byte for absolute modes, or a pointer into the zero page:
+      (ZP0 | ZPX | ZPY | ABS | ABX | ABY | IZX | IZY, 2) => {
+        self.addr = bus.read(self.pc) as u16;
+        self.pc = self.pc.wrapping_add(1);
+      }
+
+      // Zero page indexing reads from the unindexed address while it adds, and
+      // wraps around within the zero page:
+      (ZPX | IZX, 3) => {
+        bus.read(self.addr);
+        self.addr = (self.addr + self.x as u16) & 0x00FF;
+      }
+      (ZPY, 3) => {
+        bus.read(self.addr);
+        self.addr = (self.addr + self.y as u16) & 0x00FF;
+      }
 
-    self.cycles_left = 8;
+      (ABS, 3) => {
+        self.addr |= (bus.read(self.pc) as u16) << 8;
+        self.pc = self.pc.wrapping_add(1);
+      }
+      (ABX | ABY, 3) => {
+        let base = self.addr | (bus.read(self.pc) as u16) << 8;
+        self.pc = self.pc.wrapping_add(1);
+        let index = if mode == ABX { self.x } else { self.y };
+        self.index(base, index);
+      }
+
+      (IZX, 4) => {
+        self.data = bus.read(self.addr);
+      }
+      (IZX, _) => {
+        let hi = bus.read((self.addr + 1) & 0x00FF) as u16;
+        self.addr = (hi << 8) | self.data as u16;
+      }
+
+      (IZY, 3) => {
+        self.data = bus.read(self.addr);
+      }
+      (IZY, 4) => {
+        let hi = bus.read((self.addr + 1) & 0x00FF) as u16;
+        self.index((hi << 8) | self.data as u16, self.y);
+      }
+
+      // The high byte fixup cycle for indexed modes, which reads from whatever
+      // address we had before the fix:
+      (ABX | ABY | IZY, _) => {
+        bus.read(self.unfixed_addr());
+      }
+
+      _ => unreachable!("{:?} has no cycle {}", mode, self.cycle),
+    }
+  }
+
+  /// Adds an index register to an absolute address, noting whether it carried
+  /// into the high byte.
+  fn index(&mut self, base: u16, index: u8) {
+    self.addr = base.wrapping_add(index as u16);
+    self.page_crossed = (base & 0xFF00) != (self.addr & 0xFF00);
+  }
+
+  /// `addr` as the 6502 sees it while indexing, before the carry into the high
+  /// byte is applied.
+  fn unfixed_addr(&self) -> u16 {
+    if self.page_crossed {
+      self.addr.wrapping_sub(0x0100)
+    } else {
+      self.addr
+    }
+  }
+
+  // JUMPS, CALLS, BRANCHES AND INTERRUPTS:
+  //
+  // These don't fit the "address, then operand" pattern, so each runs its own
+  // cycles.
+
+  /// Jump
+  fn jmp(&mut self, bus: &mut dyn Bus<Cpu>, mode: AddressingMode) {
+    match (mode, self.cycle) {
+      (_, 2) => {
+        self.data = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+      }
+      (ABS, _) => {
+        let hi = bus.read(self.pc) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+        self.cycle = 0;
+      }
+      (_, 3) => {
+        self.addr = (bus.read(self.pc) as u16) << 8 | self.data as u16;
+        self.pc = self.pc.wrapping_add(1);
+      }
+      (_, 4) => {
+        self.data = bus.read(self.addr);
+      }
+      _ => {
+        // The 6502 has a hardware bug where if you happen to have a pointer
+        // address in memory that spans across pages (remember, pointers are 2
+        // bytes, and therefore it is possible for this to happen), it will not
+        // carry into the hi byte of the pointer, and reads the hi byte of the
+        // address from the start of the same page instead
+        let hi_addr = (self.addr & 0xFF00) | (self.addr.wrapping_add(1) & 0x00FF);
+        let hi = bus.read(hi_addr) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+        self.cycle = 0;
+      }
+    }
+  }
+
+  /// Jump to Subroutine
+  fn jsr(&mut self, bus: &mut dyn Bus<Cpu>) {
+    match self.cycle {
+      2 => {
+        self.data = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+      }
+      3 => {
+        bus.read(STACK_START + (self.s as u16));
+      }
+      // The return address we push is the address of the last byte of the JSR
+      // instruction; RTS adds the missing 1 when it returns:
+      4 => self.push(bus, (self.pc >> 8) as u8),
+      5 => self.push(bus, (self.pc & 0x00FF) as u8),
+      _ => {
+        let hi = bus.read(self.pc) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+        self.cycle = 0;
+      }
+    }
   }
 
-  pub fn sig_irq(&mut self, bus: &mut dyn Bus<Cpu>) {
-    if self.get_status(StatusFlag::DisableInterrupts) == 0x00 {
-      let pc_hi: u8 = (self.pc >> 8) as u8;
-      self.push(bus, pc_hi);
-      let pc_lo: u8 = (self.pc & 0x00FF) as u8;
-      self.push(bus, pc_lo);
+  /// Return from Subroutine
+  fn rts(&mut self, bus: &mut dyn Bus<Cpu>) {
+    match self.cycle {
+      2 => {
+        bus.read(self.pc);
+      }
+      3 => {
+        bus.read(STACK_START + (self.s as u16));
+      }
+      4 => {
+        self.data = self.pull(bus);
+      }
+      5 => {
+        let hi = self.pull(bus) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+      }
+      _ => {
+        bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        self.cycle = 0;
+      }
+    }
+  }
+
+  /// Return from interrupt
+  fn rti(&mut self, bus: &mut dyn Bus<Cpu>) {
+    match self.cycle {
+      2 => {
+        bus.read(self.pc);
+      }
+      3 => {
+        bus.read(STACK_START + (self.s as u16));
+      }
+      4 => {
+        self.status = self.pull(bus) | self.get_status(Break) | self.get_status(Unused);
+      }
+      5 => {
+        self.data = self.pull(bus);
+      }
+      _ => {
+        let hi = self.pull(bus) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+        self.cycle = 0;
+      }
+    }
+  }
+
+  /// Force an interrupt
+  ///
+  /// BRK has a padding byte after the opcode, which gets skipped over; after
+  /// that it's an interrupt sequence like any other.
+  fn brk(&mut self, bus: &mut dyn Bus<Cpu>) {
+    bus.read(self.pc);
+    self.pc = self.pc.wrapping_add(1);
+    self.interrupt = Some(Interrupt::Brk);
+  }
+
+  /// Halt and catch fire
+  ///
+  /// The real 6502 locks up until it's reset, ignoring NMIs and IRQs; see
+  /// `halted`.
+  fn kil(&mut self, bus: &mut dyn Bus<Cpu>) {
+    bus.read(self.pc);
+    // Point back at the opcode, so the debugger shows where we jammed:
+    self.pc = self.pc.wrapping_sub(1);
+    self.halted = true;
+    self.cycle = 0;
+  }
 
-      {
-        // TODO: `self.set_status` -> `self.status.set`
-        let mut clone = self.clone();
-        clone.set_status(Break, false);
-        clone.set_status(Unused, true);
-        self.push(bus, clone.status);
+  /// Branches take 2 cycles, plus 1 if the branch is taken, plus 1 more if it
+  /// lands on a different page.
+  fn branch(&mut self, bus: &mut dyn Bus<Cpu>, instruction: Instruction) {
+    match self.cycle {
+      2 => {
+        self.data = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        if !self.branch_condition(instruction) {
+          self.cycle = 0;
+        }
+      }
+      3 => {
+        bus.read(self.pc);
+        // The offset is signed, and like indexing, it's only added to the low
+        // byte at first:
+        self.addr = self.pc.wrapping_add(self.data as i8 as u16);
+        self.pc = (self.pc & 0xFF00) | (self.addr & 0x00FF);
+        if self.pc == self.addr {
+          self.cycle = 0;
+        }
       }
+      _ => {
+        bus.read(self.pc);
+        self.pc = self.addr;
+        self.cycle = 0;
+      }
+    }
+  }
 
-      self.set_status(DisableInterrupts, true);
-      let irq_addr = bus.read16(IRQ_POINTER);
-      self.pc = irq_addr;
-      self.cycles_left = 7;
+  fn branch_condition(&self, instruction: Instruction) -> bool {
+    match instruction {
+      // Branch if Carry Clear
+      BCC => self.get_status(Carry) == 0,
+      // Branch if Carry Set
+      BCS => self.get_status(Carry) != 0,
+      // Branch if Equal
+      BEQ => self.get_status(Zero) != 0,
+      // Branch if Minus
+      BMI => self.get_status(Negative) != 0,
+      // Branch if Positive
+      BPL => self.get_status(Negative) == 0,
+      // Branch if Not Equal
+      BNE => self.get_status(Zero) == 0,
+      // Branch if Overflow Clear
+      BVC => self.get_status(Overflow) == 0,
+      // Branch if Overflow Set
+      BVS => self.get_status(Overflow) != 0,
+      _ => unreachable!("{:?} is not a branch", instruction),
     }
   }
 
-  pub fn sig_nmi(&mut self, bus: &mut dyn Bus<Cpu>) {
-    let pc_hi: u8 = (self.pc >> 8) as u8;
-    self.push(bus, pc_hi);
-    let pc_lo: u8 = (self.pc & 0x00FF) as u8;
-    self.push(bus, pc_lo);
+  /// Cycles 3-7 of BRK and the other interrupt sequences: push the program
+  /// counter and status, then load the program counter from the vector.
+  fn interrupt_cycle(&mut self, bus: &mut dyn Bus<Cpu>, interrupt: Interrupt) {
+    match self.cycle {
+      3 => self.push_unless_reset(bus, interrupt, (self.pc >> 8) as u8),
+      4 => self.push_unless_reset(bus, interrupt, (self.pc & 0x00FF) as u8),
+      5 => {
+        // The pushed copy of the status is the only way for a handler to tell
+        // BRK apart from an IRQ:
+        let mut status = self.status | (Unused as u8);
+        if interrupt == Interrupt::Brk {
+          status |= Break as u8;
+        } else {
+          status &= !(Break as u8);
+        }
+        self.push_unless_reset(bus, interrupt, status);
+
+        // An NMI that arrives before the vector is fetched hijacks BRK and IRQ
+        // sequences, which then jump through the NMI vector instead. The B
+        // flag pushed above is the only trace of the BRK:
+        if matches!(interrupt, Interrupt::Brk | Interrupt::Irq) && self.nmi_pending {
+          self.nmi_pending = false;
+          self.interrupt = Some(Interrupt::Nmi);
+        }
+      }
+      6 => {
+        self.data = bus.read(interrupt.vector());
+        self.set_status(DisableInterrupts, true);
+      }
+      _ => {
+        let hi = bus.read(interrupt.vector() + 1) as u16;
+        self.pc = (hi << 8) | self.data as u16;
+        self.interrupt = None;
+        self.cycle = 0;
+      }
+    }
+  }
 
-    {
-      // TODO: `self.set_status` -> `self.status.set`
-      let mut clone = self.clone();
-      clone.set_status(Break, false);
-      clone.set_status(Unused, true);
-      self.push(bus, clone.status);
+  /// Reset goes through the same motions as the other interrupts, but holds
+  /// the bus in read mode, so its pushes turn into reads. The stack pointer
+  /// still moves.
+  fn push_unless_reset(&mut self, bus: &mut dyn Bus<Cpu>, interrupt: Interrupt, data: u8) {
+    if interrupt == Interrupt::Reset {
+      bus.read(STACK_START + (self.s as u16));
+      self.s = self.s.wrapping_sub(1);
+    } else {
+      self.push(bus, data);
     }
+  }
+
+  // SIGNALS:
+  //
+  // Reset abandons the current instruction; NMI and IRQ are lines that the CPU
+  // polls (see `poll_interrupts`). Either way the interrupt sequence itself
+  // runs through `clock` like anything else.
+
+  pub fn sig_reset(&mut self) {
+    self.a = 0x00;
+    self.x = 0x00;
+    self.y = 0x00;
+    // The reset sequence decrements this three times, leaving it at
+    // `STACK_INIT`:
+    self.s = 0x00;
+    self.status = 0x00 | (StatusFlag::Unused as u8);
 
-    self.set_status(DisableInterrupts, true);
-    let irq_addr = bus.read16(NMI_POINTER);
-    self.pc = irq_addr;
+    self.cycle = 0;
+    self.interrupt = None;
+    self.pending_interrupt = Some(Interrupt::Reset);
+    self.nmi_detected = false;
+    self.nmi_pending = false;
+    self.interrupt_poll = None;
+    self.halted = false;
+  }
+
+  /// Drives the NMI line. Only the rising edge matters; holding it high
+  /// doesn't trigger any more NMIs.
+  pub fn set_nmi_line(&mut self, level: bool) {
+    if level && !self.nmi_line {
+      self.nmi_detected = true;
+    }
+    self.nmi_line = level;
+  }
 
-    self.cycles_left = 8;
+  /// Drives the IRQ line. IRQs keep coming for as long as it's held high and
+  /// the I flag is clear, so whatever raised it needs acknowledging.
+  pub fn set_irq_line(&mut self, level: bool) {
+    self.irq_line = level;
   }
 }
 
@@ -264,6 +743,9 @@ pub struct Operation {
 enum DataSourceKind {
   Accumulator,
   AbsoluteAddress,
+  /// Already read from `addr` on an earlier cycle (by a read-modify-write
+  /// instruction), and kept in `Cpu::data`; writes still go to `addr`
+  Latched,
   Implicit,
 }
 use DataSourceKind::*;
@@ -278,6 +760,7 @@ impl DataSource {
     match self.kind {
       Accumulator => cpu.a,
       AbsoluteAddress => bus.read(self.addr),
+      Latched => cpu.data,
       Implicit => panic!("Cannot read from Implicit DataSource"),
     }
   }
@@ -285,23 +768,12 @@ impl DataSource {
   pub fn write(&self, cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: u8) {
     match self.kind {
       Accumulator => cpu.a = data,
-      AbsoluteAddress => bus.write(self.addr, data),
+      AbsoluteAddress | Latched => bus.write(self.addr, data),
       Implicit => panic!("Cannot write to Implicit DataSource"),
     }
   }
 }
 
-pub struct AddressingModeResult {
-  data: DataSource,
-  needs_extra_cycle: bool,
-}
-
-/// An Addressing Mode ultimately provides some data to be used by an
-/// instruction, either in the form of a constant, read-only byte value (`data`)
-/// or an absolute address from which the data can be retrieved/written to
-/// (`addr_abs`)
-type AddressingModeImplementation = fn(&mut Cpu, &mut dyn Bus<Cpu>) -> AddressingModeResult;
-
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum AddressingMode {
   IMP,
@@ -320,10 +792,101 @@ pub enum AddressingMode {
 }
 use AddressingMode::*;
 
-struct InstructionResult {
-  may_need_extra_cycle: bool,
+/// What an instruction does with its operand, which decides how many cycles it
+/// spends on it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Access {
+  Read,
+  Write,
+  ReadModifyWrite,
+}
+
+fn access(instruction: Instruction) -> Access {
+  match instruction {
+    STA | STX | STY | SAX | SHX | SHY | TAS | AHX => Access::Write,
+    ASL | LSR | ROL | ROR | INC | DEC | SLO | SRE | RLA | RRA | DCP | ISB => {
+      Access::ReadModifyWrite
+    }
+    _ => Access::Read,
+  }
+}
+
+/// An instruction's effect, run on the cycle it gets to its operand. The
+/// operand is either a constant, read-only byte value, the accumulator, or an
+/// absolute address from which the data can be retrieved/written to.
+type InstructionImplementation = fn(&mut Cpu, &mut dyn Bus<Cpu>, &DataSource);
+
+fn instruction_implementation(instruction: Instruction) -> InstructionImplementation {
+  match instruction {
+    ADC => adc,
+    AND => and,
+    ASL => asl,
+    BIT => bit,
+    CLC => clc,
+    CLD => cld,
+    CLI => cli,
+    CLV => clv,
+    CMP => cmp,
+    CPX => cpx,
+    CPY => cpy,
+    DEC => dec,
+    DEX => dex,
+    DEY => dey,
+    EOR => eor,
+    INC => inc,
+    INX => inx,
+    INY => iny,
+    LDA => lda,
+    LDX => ldx,
+    LDY => ldy,
+    LSR => lsr,
+    NOP => nop,
+    ORA => ora,
+    PHA => pha,
+    PHP => php,
+    PLA => pla,
+    PLP => plp,
+    ROL => rol,
+    ROR => ror,
+    SBC => sbc,
+    SEC => sec,
+    SED => sed,
+    SEI => sei,
+    STA => sta,
+    STX => stx,
+    STY => sty,
+    TAX => tax,
+    TAY => tay,
+    TSX => tsx,
+    TXA => txa,
+    TXS => txs,
+    TYA => tya,
+
+    LAX => lax,
+    SAX => sax,
+    DCP => dcp,
+    ISB => isb,
+    SLO => slo,
+    RLA => rla,
+    SRE => sre,
+    RRA => rra,
+    ANC => anc,
+    ALR => alr,
+    ARR => arr,
+    AXS => axs,
+    LAS => las,
+    LXA => lxa,
+    XAA => xaa,
+    SHX => shx,
+    SHY => shy,
+    TAS => tas,
+    AHX => ahx,
+
+    BCC | BCS | BEQ | BMI | BNE | BPL | BRK | BVC | BVS | JMP | JSR | KIL | RTI | RTS => {
+      unreachable!("{:?} runs its own cycles in `Cpu::clock`", instruction)
+    }
+  }
 }
-type InstructionImplementation = fn(&mut Cpu, &mut dyn Bus<Cpu>, &DataSource) -> InstructionResult;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Instruction {
@@ -393,6 +956,18 @@ pub enum Instruction {
   RLA,
   SRE,
   RRA,
+  ANC,
+  ALR,
+  ARR,
+  AXS,
+  LAS,
+  LXA,
+  XAA,
+  SHX,
+  SHY,
+  TAS,
+  AHX,
+  KIL,
 }
 use Instruction::*;
 
@@ -401,40 +976,36 @@ use Instruction::*;
 // LOGICAL INSTRUCTIONS
 
 /// AND
-fn and(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn and(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   cpu.a = cpu.a & data.read(cpu, bus);
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
 /// Exclusive OR
-fn eor(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn eor(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   cpu.a = cpu.a ^ data.read(cpu, bus);
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
 /// Inclusive OR
-fn ora(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn ora(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   cpu.a = cpu.a | data.read(cpu, bus);
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
+}
 
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
+/// Undocumented: AND, then copy the result's sign into carry
+fn anc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  cpu.a &= data.read(cpu, bus);
+  cpu.set_status(Zero, cpu.a == 0x00);
+  cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
+  cpu.set_status(Carry, cpu.a & 0b_1000_0000 != 0);
 }
 
 /// Bit Test
-fn bit(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn bit(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   cpu.set_status(Zero, cpu.a & m == 0x00);
 
@@ -442,205 +1013,204 @@ fn bit(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Overflow, (0b_0100_0000 & m) != 0);
 
   cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 // LOAD/STORE OPERATIONS
 
 /// Load Accumulator
-fn lda(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn lda(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   cpu.a = m;
   cpu.set_status(Zero, m == 0);
   cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
 /// Load X
-fn ldx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn ldx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   cpu.x = m;
   cpu.set_status(Zero, m == 0);
   cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
 /// Load Y
-fn ldy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn ldy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   cpu.y = m;
   cpu.set_status(Zero, m == 0);
   cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
-fn lax(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn lax(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   cpu.a = m;
   cpu.x = m;
   cpu.set_status(Zero, m == 0);
   cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
+}
+
+/// Undocumented: AND memory with the stack pointer, into A, X and S
+fn las(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  let m = data.read(cpu, bus) & cpu.s;
+  cpu.a = m;
+  cpu.x = m;
+  cpu.s = m;
+  cpu.set_status(Zero, m == 0);
+  cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
+}
+
+/// XAA and LXA (immediate LAX) mix the accumulator into their result through
+/// an analog quirk that varies from chip to chip, and even with temperature.
+/// Nothing sane depends on it; this is the most commonly quoted value.
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
+/// Undocumented: LAX #imm, with the accumulator's unstable input
+fn lxa(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  let m = (cpu.a | UNSTABLE_MAGIC) & data.read(cpu, bus);
+  cpu.a = m;
+  cpu.x = m;
+  cpu.set_status(Zero, m == 0);
+  cpu.set_status(Negative, (0b_1000_0000 & m) != 0);
+}
+
+/// Undocumented: TXA + AND #imm, with the accumulator's unstable input
+fn xaa(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  cpu.a = (cpu.a | UNSTABLE_MAGIC) & cpu.x & data.read(cpu, bus);
+  cpu.set_status(Zero, cpu.a == 0x00);
+  cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
 }
 
 /// Store Accumulator
-fn sta(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn sta(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   data.write(cpu, bus, cpu.a);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Store X
-fn stx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn stx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   data.write(cpu, bus, cpu.x);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Store Y
-fn sty(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn sty(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   data.write(cpu, bus, cpu.y);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented
-fn sax(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn sax(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   data.write(cpu, bus, cpu.a & cpu.x);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
+}
+
+/// The indexed stores SHX, SHY, TAS and AHX AND their value with the high byte
+/// of the unindexed address, plus 1. When indexing crosses a page, the value
+/// also lands on the high byte of the address they write to.
+fn unstable_store(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource, value: u8) {
+  let hi = (cpu.unfixed_addr() >> 8) as u8;
+  let value = value & hi.wrapping_add(1);
+  let addr = if cpu.page_crossed {
+    ((value as u16) << 8) | (data.addr & 0x00FF)
+  } else {
+    data.addr
+  };
+  bus.write(addr, value);
+}
+
+/// Undocumented: store X AND (high byte + 1)
+fn shx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  unstable_store(cpu, bus, data, cpu.x);
+}
+
+/// Undocumented: store Y AND (high byte + 1)
+fn shy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  unstable_store(cpu, bus, data, cpu.y);
+}
+
+/// Undocumented: S = A AND X, then store S AND (high byte + 1)
+fn tas(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  cpu.s = cpu.a & cpu.x;
+  unstable_store(cpu, bus, data, cpu.s);
+}
+
+/// Undocumented: store A AND X AND (high byte + 1)
+fn ahx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  unstable_store(cpu, bus, data, cpu.a & cpu.x);
 }
 
 // Register Transfers
 
 /// Transfer Accumulator to X
-fn tax(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn tax(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.x = cpu.a;
 
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Transfer Accumulator to Y
-fn tay(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn tay(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.y = cpu.a;
 
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Transfer X to Accumulator
-fn txa(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn txa(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.a = cpu.x;
 
   cpu.set_status(Zero, cpu.x == 0x00);
   cpu.set_status(Negative, cpu.x & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Transfer Y to Accumulator
-fn tya(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn tya(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.a = cpu.y;
 
   cpu.set_status(Zero, cpu.y == 0x00);
   cpu.set_status(Negative, cpu.y & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 // Stack Operations
 
 /// Transfer Stack Pointer to X
-fn tsx(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn tsx(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.x = cpu.s;
 
   cpu.set_status(Zero, cpu.s == 0x00);
   cpu.set_status(Negative, cpu.s & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Transfer X to Stack Pointer
-fn txs(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn txs(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.s = cpu.x;
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Push Accumulator
-fn pha(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn pha(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.push(bus, cpu.a);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Push Processor Status
-fn php(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn php(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.push(bus, cpu.status | (Break as u8) | (Unused as u8));
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Pull Accumulator
-fn pla(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn pla(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.a = cpu.pull(bus);
 
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Pull Processor Status
-fn plp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn plp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.status = cpu.pull(bus);
   cpu.set_status(Unused, true);
   cpu.set_status(Break, false);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 // Arithmetic
-fn adc_(cpu: &mut Cpu, a: u16, m: u16) -> InstructionResult {
+fn adc_(cpu: &mut Cpu, a: u16, m: u16) {
   let result = a + m + if cpu.get_status(Carry) != 0 { 1 } else { 0 };
   {
     let overflow: u16 = (a ^ result) & !(a ^ m) & 0x0080;
@@ -650,83 +1220,75 @@ fn adc_(cpu: &mut Cpu, a: u16, m: u16) -> InstructionResult {
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x80) != 0);
   cpu.a = (result & 0x00FF) as u8;
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 /// Add with Carry
-fn adc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn adc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let a = cpu.a as u16 & 0x00FF;
   let m = data.read(cpu, bus) as u16 & 0x00FF;
   adc_(cpu, a, m)
 }
 
 /// Subtract with Carry
-fn sbc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn sbc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let a = cpu.a as u16 & 0x00FF;
   let m = (!data.read(cpu, bus)) as u16 & 0x00FF;
   adc_(cpu, a, m)
 }
 
 /// Compare Accumulator
-fn cmp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn cmp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let a = cpu.a as u16;
   let m = data.read(cpu, bus) as u16;
   let result = a.wrapping_sub(m);
   cpu.set_status(Carry, a >= m);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
-  InstructionResult {
-    may_need_extra_cycle: true,
-  }
 }
 
 /// Compare X
-fn cpx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn cpx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let x = cpu.x as u16;
   let m = data.read(cpu, bus) as u16;
   let result = x.wrapping_sub(m);
   cpu.set_status(Carry, x >= m);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
-  InstructionResult {
-    // Unlike CMP, we cannot use address modes that may require additional
-    // cycles:
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Compare Y
-fn cpy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn cpy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let y = cpu.y as u16;
   let m = data.read(cpu, bus) as u16;
   let result = y.wrapping_sub(m);
   cpu.set_status(Carry, y >= m);
   cpu.set_status(Zero, y == m);
   cpu.set_status(Negative, (result & 0x0080) != 0);
-  InstructionResult {
-    // Unlike CMP, we cannot use address modes that may require additional
-    // cycles:
-    may_need_extra_cycle: false,
-  }
+}
+
+/// Undocumented: X = (A AND X) - M, setting flags like CMP
+fn axs(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  let ax = (cpu.a & cpu.x) as u16;
+  let m = data.read(cpu, bus) as u16;
+  let result = ax.wrapping_sub(m);
+  cpu.x = (result & 0x00FF) as u8;
+  cpu.set_status(Carry, ax >= m);
+  cpu.set_status(Zero, (result & 0x00FF) == 0);
+  cpu.set_status(Negative, (result & 0x0080) != 0);
 }
 
 // Increments & Decrements
 
 /// Increment Memory
-fn inc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn inc(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus) as u16;
   let result = m.wrapping_add(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   data.write(cpu, bus, (result & 0x00FF) as u8);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented: INC + SBC
-fn isb(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn isb(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus) as u16;
   let result = m.wrapping_add(1);
   data.write(cpu, bus, (result & 0x00FF) as u8);
@@ -737,63 +1299,48 @@ fn isb(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
 }
 
 /// Increment X
-fn inx(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn inx(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   let result = (cpu.x as u16).wrapping_add(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   cpu.x = (result & 0x00FF) as u8;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Increment Y
-fn iny(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn iny(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   let result = (cpu.y as u16).wrapping_add(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   cpu.y = (result & 0x00FF) as u8;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Decrement Memory
-fn dec(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn dec(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus) as u16;
   let result = m.wrapping_sub(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   data.write(cpu, bus, (result & 0x00FF) as u8);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Decrement X
-fn dex(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn dex(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   let result = (cpu.x as u16).wrapping_sub(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   cpu.x = (result & 0x00FF) as u8;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Decrement Y
-fn dey(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn dey(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   let result = (cpu.y as u16).wrapping_sub(1);
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   cpu.y = (result & 0x00FF) as u8;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented; DEC + CMP
-fn dcp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn dcp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let a = cpu.a as u16;
   let m = data.read(cpu, bus) as u16;
   let dec_result = m.wrapping_sub(1);
@@ -802,15 +1349,12 @@ fn dcp(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   let cmp_result = a.wrapping_sub(dec_result);
   cpu.set_status(Zero, (cmp_result & 0x00FF) == 0);
   cpu.set_status(Negative, (cmp_result & 0x0080) != 0);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 // Shifts
 
 /// Arithmetic Shift Left
-fn asl(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn asl(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = m << 1; // equivalent to m * 2
 
@@ -820,14 +1364,10 @@ fn asl(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Zero, (result & 0x00FF) == 0);
   cpu.set_status(Negative, (result & 0x0080) != 0);
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented: ASL + ORA
-fn slo(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn slo(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = m << 1; // equivalent to m * 2
 
@@ -840,14 +1380,10 @@ fn slo(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
 
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Logical Shift Right
-fn lsr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn lsr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = m >> 1; // equivalent to m / 2
 
@@ -857,14 +1393,10 @@ fn lsr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Zero, result == 0);
   cpu.set_status(Negative, result & 0x80 != 0);
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented: LSR + EOR
-fn sre(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn sre(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = m >> 1; // equivalent to m / 2
 
@@ -876,14 +1408,19 @@ fn sre(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.a = cpu.a ^ result;
   cpu.set_status(Zero, cpu.a == 0x00);
   cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
+}
 
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
+/// Undocumented: AND #imm + LSR A
+fn alr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  let m = cpu.a & data.read(cpu, bus);
+  cpu.a = m >> 1;
+  cpu.set_status(Carry, m & 0x01 == 0x01);
+  cpu.set_status(Zero, cpu.a == 0x00);
+  cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
 }
 
 /// Rotate Left
-fn rol(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn rol(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = (m << 1) | cpu.get_status(Carry);
 
@@ -892,14 +1429,10 @@ fn rol(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Zero, result == 0);
   cpu.set_status(Negative, result & 0x80 != 0);
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented: ROL + AND
-fn rla(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn rla(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = (m << 1) | cpu.get_status(Carry);
 
@@ -910,14 +1443,10 @@ fn rla(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
 
   cpu.a = cpu.a & result;
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Rotate Right
-fn ror(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn ror(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = (m >> 1) | (cpu.get_status(Carry) << 7);
 
@@ -926,14 +1455,10 @@ fn ror(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   cpu.set_status(Zero, result == 0);
   cpu.set_status(Negative, result & 0x80 != 0);
   data.write(cpu, bus, result);
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Undocumented: ROR + ADC
-fn rra(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
+fn rra(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
   let m = data.read(cpu, bus);
   let result = (m >> 1) | (cpu.get_status(Carry) << 7);
   let old_bit_0 = m & 0x01;
@@ -942,471 +1467,64 @@ fn rra(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionR
   adc_(cpu, cpu.a as u16 & 0x00FF, result as u16 & 0x00FF)
 }
 
-/// Jumps & Calls
-
-/// Jump
-fn jmp(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  cpu.pc = data.addr;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
-/// Jump to Subroutine
-fn jsr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  let return_addr = cpu.pc - 1;
-  let return_hi: u8 = ((return_addr >> 8) & 0x00FF) as u8;
-  cpu.push(bus, return_hi);
-  let return_lo: u8 = (return_addr & 0x00FF) as u8;
-  cpu.push(bus, return_lo);
-
-  cpu.pc = data.addr;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
-/// Return from Subroutine
-fn rts(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
-  let return_lo = cpu.pull(bus);
-  let return_hi = cpu.pull(bus);
-  let return_addr = ((return_hi as u16) << 8) | return_lo as u16;
-  cpu.pc = return_addr;
-  cpu.pc += 1;
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
-// Branches
-
-/// Utility function for branching instructions.
+/// Undocumented: AND #imm + ROR A
 ///
-/// Every branching instruction has the same characteristic, but operates on a
-/// different condition.
-fn branch_if(condition: bool, cpu: &mut Cpu, data: &DataSource) -> InstructionResult {
-  if condition {
-    // If we are branching, we use up an extra cycle
-    cpu.cycles_left += 1;
-
-    let new_pc = data.addr;
-    // If we're moving the program counter into a new page, we use one cycle in
-    // _addition_ to the cycle we use to branch (totaling +2).
-    //
-    // We can detect if we are crossing pages by comparing the hi byte of the
-    // new program counter with the hi bytes in the old program counter:
-    if (new_pc & 0xFF00) != (cpu.pc & 0xFF00) {
-      cpu.cycles_left += 1;
-    }
-
-    cpu.pc = new_pc;
-  }
-  InstructionResult {
-    // We manually handle incrementing cycles since the logic depends on whether
-    // we branch, so we set this to false:
-    may_need_extra_cycle: false,
-  }
-}
-
-/// Branch if Carry Clear
-fn bcc(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Carry) == 0, cpu, data)
-}
-
-/// Branch if Carry Set
-fn bcs(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Carry) != 0, cpu, data)
-}
-
-/// Branch if Equal
-fn beq(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Zero) != 0, cpu, data)
-}
-
-/// Branch if Minus
-fn bmi(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Negative) != 0, cpu, data)
-}
-
-/// Branch if Positive
-fn bpl(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Negative) == 0, cpu, data)
-}
-
-/// Branch if Not Equal
-fn bne(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Zero) == 0, cpu, data)
-}
-
-/// Branch if Overflow Clear
-fn bvc(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Overflow) == 0, cpu, data)
-}
-
-/// Branch if Overflow Set
-fn bvs(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, data: &DataSource) -> InstructionResult {
-  branch_if(cpu.get_status(Overflow) != 0, cpu, data)
+/// The rotate happens partway through the ALU's add circuitry, so carry and
+/// overflow come out of bits 6 and 5 of the result instead.
+fn arr(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  let m = cpu.a & data.read(cpu, bus);
+  cpu.a = (m >> 1) | (cpu.get_status(Carry) << 7);
+  cpu.set_status(Zero, cpu.a == 0x00);
+  cpu.set_status(Negative, cpu.a & 0b_1000_0000 != 0);
+  cpu.set_status(Carry, cpu.a & 0b_0100_0000 != 0);
+  cpu.set_status(Overflow, ((cpu.a >> 6) ^ (cpu.a >> 5)) & 0x01 != 0);
 }
 
 // Status Flag Changes
 
 /// Clear carry
-fn clc(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn clc(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(Carry, false);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Clear decimal mode
-fn cld(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn cld(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(DecimalMode, false);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Clear interrupt disable
-fn cli(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn cli(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(DisableInterrupts, false);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Clear overflow
-fn clv(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn clv(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(Overflow, false);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Set carry
-fn sec(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn sec(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(Carry, true);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Set decimal mode
-fn sed(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn sed(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(DecimalMode, true);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 /// Set interrupt disable
-fn sei(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
+fn sei(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) {
   cpu.set_status(DisableInterrupts, true);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
 }
 
 // System Functions
 
-/// Force an interrupt
-fn brk(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
-  let pc_hi: u8 = (cpu.pc >> 8) as u8;
-  cpu.push(bus, pc_hi);
-  let pc_lo: u8 = (cpu.pc << 8) as u8;
-  cpu.push(bus, pc_lo);
-  cpu.push(bus, cpu.status);
-
-  let irq_addr = bus.read16(IRQ_POINTER);
-  cpu.pc = irq_addr;
-  cpu.set_status(Break, true);
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
-/// Return from interrupt
-fn rti(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
-  cpu.status = cpu.pull(bus) | cpu.get_status(Break) | cpu.get_status(Unused);
-
-  let pc_lo = cpu.pull(bus) as u16;
-  let pc_hi = cpu.pull(bus) as u16;
-  cpu.pc = (pc_hi << 8) | pc_lo;
-
-  // let irq_addr = bus.read16(IRQ_POINTER);
-  // cpu.pc = irq_addr;
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
 /// No operation
-fn nop(_cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>, _data: &DataSource) -> InstructionResult {
-  // Do nothing.
-
-  InstructionResult {
-    may_need_extra_cycle: false,
-  }
-}
-
-// ADDRESSING MODES ///////////////////////////////////////////////////////////
-
-/// Implied addressing
-///
-/// Nothing to do here, but some implied operations operate on the accumulator,
-/// so we fetch that data here
-fn imp(_cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  AddressingModeResult {
-    data: DataSource {
-      kind: Implicit,
-      addr: 0x0000,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Immediate addressing
-///
-/// Read a byte directly from the current program counter
-fn imm(cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let addr_abs = cpu.pc;
-  cpu.pc = cpu.pc.wrapping_add(1);
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Zero Page addressing
-///
-/// Read a byte at an address in the zeroth page; i.e. from one of the first 256
-/// bytes in memory
-fn zp0(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  // Read the first operand, constructing a 16-bit address within the zeroth
-  // page:
-  let addr_abs = (bus.read(cpu.pc) as u16) & 0x00FF;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Zero Page addressing, with X address offset
-///
-/// Read a byte at an address in the zeroth page + X; i.e. starting from X, plus
-/// 0-255
-fn zpx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  // Read the first operand, constructing a 16-bit address within the zeroth
-  // page:
-  let addr_abs = ((cpu.x.wrapping_add(bus.read(cpu.pc))) as u16) & 0x00FF;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Zero Page addressing, with Y address offset
-///
-/// Read a byte at an address in the zeroth page + Y; i.e. starting from Y, plus
-/// 0-255
-fn zpy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  // Read the first operand, constructing a 16-bit address within the zeroth
-  // page:
-  let addr_abs = ((cpu.y.wrapping_add(bus.read(cpu.pc))) as u16) & 0x00FF;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Absolute addressing
-///
-/// Read a full 16-bit address from the current program counter + 1
-fn abs(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let addr_lo = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let addr_hi = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: ((addr_hi << 8) | addr_lo),
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Absolute addressing + X
-///
-/// Read a full 16-bit address from the current program counter + 1, then apply
-/// an offset of X
-fn abx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let addr_lo = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let addr_hi = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let addr_abs = ((addr_hi << 8) | addr_lo) + cpu.x as u16;
-
-  // If our hi byte is changed after we've added X, then it has changed due to
-  // overflow which means we are crossing a page. When we cross a page, we may
-  // need an extra cycle:
-  let needs_extra_cycle = addr_abs & 0xFF00 != (addr_hi << 8);
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle,
-  }
-}
-
-/// Absolute addressing + Y
-///
-/// Read a full 16-bit address from the current program counter + 1, then apply
-/// an offset of Y
-fn aby(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let addr_lo = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let addr_hi = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let addr_abs = ((addr_hi << 8) | addr_lo).wrapping_add(cpu.y as u16);
-
-  // If our hi byte is changed after we've added Y, then it has changed due to
-  // overflow which means we are crossing a page. When we cross a page, we may
-  // need an extra cycle:
-  let needs_extra_cycle = addr_abs & 0xFF00 != (addr_hi << 8);
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle,
-  }
-}
-
-/// Indirect
-fn ind(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let ptr_lo = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let ptr_hi = bus.read(cpu.pc) as u16;
-  cpu.pc = cpu.pc.wrapping_add(1);
-  let ptr = ptr_hi << 8 | ptr_lo;
-
-  // The 6502 has a hardware bug where if you happen to have a pointer address
-  // in memory that spans across pages (remember, pointers are 2 bytes, and
-  // therefore it is possible for this to happen), it will not actually read the
-  // hi byte of the address properly
-  let addr_abs = if ptr_lo == 0x00FF {
-    ((bus.read(ptr & 0xFF00) as u16) << 8) | bus.read(ptr) as u16
-  } else {
-    bus.read16(ptr)
-  };
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// (Indirect, X)
-fn izx(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  // Our pointer lives in the zeroth page, so we only need to read one byte
-  let ptr = bus.read(cpu.pc);
-  cpu.pc = cpu.pc.wrapping_add(1);
-
-  // We read X offset from this pointer
-  let lo = bus.read(ptr.wrapping_add(cpu.x) as u16 & 0x00FF) as u16;
-  let hi = bus.read(ptr.wrapping_add(cpu.x + 1) as u16 & 0x00FF) as u16;
-  let addr_abs = (hi << 8) | lo;
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// (Indirect), Y
-fn izy(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  // Our pointer lives in the zeroth page, so we only need to read one byte
-  let ptr = bus.read(cpu.pc) as u16 & 0x00FF;
-  cpu.pc = cpu.pc.wrapping_add(1);
-
-  let lo = bus.read(ptr as u16 & 0x00FF) as u16;
-  let hi = bus.read(ptr.wrapping_add(1) as u16 & 0x00FF) as u16;
-  let addr = (hi << 8) | lo;
-  let addr_abs = addr.wrapping_add(cpu.y as u16);
-
-  // We only read this here so we can check if we crossed a page:
-  let addr_hi = bus.read(ptr + 1) as u16 & 0x00FF;
-  // If our hi byte is changed after we've added Y, then it has changed due to
-  // overflow which means we are crossing a page. When we cross a page, we may
-  // need an extra cycle:
-  let needs_extra_cycle = addr_abs & 0xFF00 != (addr_hi << 8);
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr: addr_abs,
-    },
-    needs_extra_cycle,
-  }
-}
-
-/// Accumulator
-fn acc(_cpu: &mut Cpu, _bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  AddressingModeResult {
-    data: DataSource {
-      kind: Accumulator,
-      addr: 0x0000,
-    },
-    needs_extra_cycle: false,
-  }
-}
-
-/// Relative
-fn rel(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>) -> AddressingModeResult {
-  let offset = bus.read(cpu.pc);
-  cpu.pc = cpu.pc.wrapping_add(1);
-
-  // This ensures the binary arithmatic works out when adding this relative
-  // address to our program counter.
-  let addr = if offset & 0x80 != 0 {
-    // Get the inverted version of the offset by applying two's complement:
-    let neg_offset = !(offset as u16) + 1 & 0x00FF;
-    cpu.pc - neg_offset
-  } else {
-    cpu.pc + ((offset as u16) & 0x00FF)
-  };
-
-  AddressingModeResult {
-    data: DataSource {
-      kind: AbsoluteAddress,
-      addr,
-    },
-    needs_extra_cycle: false,
+fn nop(cpu: &mut Cpu, bus: &mut dyn Bus<Cpu>, data: &DataSource) {
+  // Do nothing; but the unofficial NOPs that take an operand still read it,
+  // same as any other read instruction:
+  if let AbsoluteAddress = data.kind {
+    data.read(cpu, bus);
   }
 }
 
@@ -2699,7 +2817,7 @@ lazy_static! {
     0xF3 => Operation {
       instruction: ISB,
       addressing_mode: IZY,
-      cycles: 4,
+      cycles: 8,
       undocumented: true,
     },
 
@@ -2876,6 +2994,158 @@ lazy_static! {
       undocumented: true,
     },
 
+    0x0B => Operation {
+      instruction: ANC,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x2B => Operation {
+      instruction: ANC,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x4B => Operation {
+      instruction: ALR,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x6B => Operation {
+      instruction: ARR,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xCB => Operation {
+      instruction: AXS,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xAB => Operation {
+      instruction: LXA,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x8B => Operation {
+      instruction: XAA,
+      addressing_mode: IMM,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xBB => Operation {
+      instruction: LAS,
+      addressing_mode: ABY,
+      cycles: 4,
+      undocumented: true,
+    },
+    0x9E => Operation {
+      instruction: SHX,
+      addressing_mode: ABY,
+      cycles: 5,
+      undocumented: true,
+    },
+    0x9C => Operation {
+      instruction: SHY,
+      addressing_mode: ABX,
+      cycles: 5,
+      undocumented: true,
+    },
+    0x9B => Operation {
+      instruction: TAS,
+      addressing_mode: ABY,
+      cycles: 5,
+      undocumented: true,
+    },
+    0x9F => Operation {
+      instruction: AHX,
+      addressing_mode: ABY,
+      cycles: 5,
+      undocumented: true,
+    },
+    0x93 => Operation {
+      instruction: AHX,
+      addressing_mode: IZY,
+      cycles: 6,
+      undocumented: true,
+    },
+
+    0x02 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x12 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x22 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x32 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x42 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x52 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x62 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x72 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0x92 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xB2 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xD2 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+    0xF2 => Operation {
+      instruction: KIL,
+      addressing_mode: IMP,
+      cycles: 2,
+      undocumented: true,
+    },
+
   };
 }
 
@@ -2888,6 +3158,80 @@ impl From<u8> for &Operation {
   }
 }
 
+impl SaveState for Cpu {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.status.save_state(w);
+    self.a.save_state(w);
+    self.x.save_state(w);
+    self.y.save_state(w);
+    self.s.save_state(w);
+    self.pc.save_state(w);
+    self.cycle.save_state(w);
+    self.opcode.save_state(w);
+    self.interrupt.save_state(w);
+    self.pending_interrupt.save_state(w);
+    self.nmi_line.save_state(w);
+    self.nmi_detected.save_state(w);
+    self.nmi_pending.save_state(w);
+    self.irq_line.save_state(w);
+    self.irq_pending.save_state(w);
+    self.interrupt_poll.save_state(w);
+    self.halted.save_state(w);
+    self.addr.save_state(w);
+    self.data.save_state(w);
+    self.page_crossed.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.status.load_state(r)?;
+    self.a.load_state(r)?;
+    self.x.load_state(r)?;
+    self.y.load_state(r)?;
+    self.s.load_state(r)?;
+    self.pc.load_state(r)?;
+    self.cycle.load_state(r)?;
+    self.opcode.load_state(r)?;
+    self.interrupt.load_state(r)?;
+    self.pending_interrupt.load_state(r)?;
+    self.nmi_line.load_state(r)?;
+    self.nmi_detected.load_state(r)?;
+    self.nmi_pending.load_state(r)?;
+    self.irq_line.load_state(r)?;
+    self.irq_pending.load_state(r)?;
+    self.interrupt_poll.load_state(r)?;
+    self.halted.load_state(r)?;
+    self.addr.load_state(r)?;
+    self.data.load_state(r)?;
+    self.page_crossed.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Interrupt {
+  fn save_state(&self, w: &mut StateWriter) {
+    let v: u8 = match self {
+      Interrupt::Reset => 0,
+      Interrupt::Nmi => 1,
+      Interrupt::Irq => 2,
+      Interrupt::Brk => 3,
+    };
+    v.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut v: u8 = 0;
+    v.load_state(r)?;
+    *self = match v {
+      0 => Interrupt::Reset,
+      1 => Interrupt::Nmi,
+      2 => Interrupt::Irq,
+      3 => Interrupt::Brk,
+      _ => return Err("Save state has an invalid interrupt"),
+    };
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -3022,7 +3366,7 @@ mod tests {
     bus.write(program_start, 0x29); // AND - Immediate
     bus.write(program_start + 1, 0x02); //   2
 
-    cpu.sig_reset(&mut bus);
+    cpu.sig_reset();
     cpu.step(&mut bus);
 
     cpu.a = 0x01;
@@ -3046,7 +3390,7 @@ mod tests {
 
     bus.write(program_start, 0x09); // ORA - Immediate
     bus.write(program_start + 1, 0x02); //   2
-    cpu.sig_reset(&mut bus);
+    cpu.sig_reset();
     cpu.step(&mut bus);
 
     cpu.a = 0x01;
@@ -3073,7 +3417,7 @@ mod tests {
 
     bus.write(program_start + 2, 0x49); // EOR - Immediate
     bus.write(program_start + 3, 0x02); //   2
-    cpu.sig_reset(&mut bus);
+    cpu.sig_reset();
     cpu.step(&mut bus);
 
     cpu.a = 0x01;
@@ -3212,7 +3556,7 @@ mod tests {
         bus.write(program_start + offset, byte);
         offset += 1;
       }
-      cpu.sig_reset(&mut bus);
+      cpu.sig_reset();
       cpu.step(&mut bus);
       cpu.a = test.a;
       cpu.step(&mut bus);
@@ -3348,7 +3692,7 @@ mod tests {
     //     bus.write(program_start + offset, byte);
     //     offset += 1;
     //   }
-    //   cpu.sig_reset(&mut bus);
+    //   cpu.sig_reset();
     //   cpu.step(&mut bus);
     //   cpu.a = test.a;
     //   cpu.step(&mut bus);
@@ -3362,4 +3706,458 @@ mod tests {
     //   assert_eq!(cpu.get_status(Negative) != 0, test.n);
     // }
   }
+
+  #[derive(Debug, PartialEq, Clone)]
+  enum BusAccess {
+    Read(u16),
+    Write(u16, u8),
+  }
+  use BusAccess::*;
+
+  /// 64K of flat RAM that remembers every access the CPU makes, in order.
+  struct RecordingBus {
+    ram: Vec<u8>,
+    accesses: Vec<BusAccess>,
+  }
+
+  impl RecordingBus {
+    fn new(program: &[u8]) -> RecordingBus {
+      let mut ram = vec![0x00; 64 * 1024];
+      ram[0x0200..0x0200 + program.len()].copy_from_slice(program);
+      RecordingBus {
+        ram,
+        accesses: vec![],
+      }
+    }
+  }
+
+  impl Bus<Cpu> for RecordingBus {
+    fn safe_read(&self, addr: u16) -> u8 {
+      self.ram[addr as usize]
+    }
+    fn read(&mut self, addr: u16) -> u8 {
+      self.accesses.push(Read(addr));
+      self.ram[addr as usize]
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+      self.accesses.push(Write(addr, data));
+      self.ram[addr as usize] = data;
+    }
+  }
+
+  /// Runs the program at $0200 for one instruction, and returns how many
+  /// cycles it took.
+  fn run_one(cpu: &mut Cpu, bus: &mut RecordingBus) -> u8 {
+    cpu.pc = 0x0200;
+    let mut cycles = 0;
+    loop {
+      cpu.clock(bus);
+      cycles += 1;
+      if cpu.cycle == 0 {
+        return cycles;
+      }
+    }
+  }
+
+  #[test]
+  fn cycle_counts() {
+    for (opcode, operation) in OPCODE_MAP.iter() {
+      // Branch timing depends on the flags; see `branch_cycles`:
+      if operation.addressing_mode == REL {
+        continue;
+      }
+
+      // All zeroes means every address and index is $00, so nothing crosses a
+      // page:
+      let mut bus = RecordingBus::new(&[*opcode]);
+      let mut cpu = Cpu::new();
+      let cycles = run_one(&mut cpu, &mut bus);
+
+      assert_eq!(cycles, operation.cycles, "opcode {:02X}", opcode);
+      assert_eq!(
+        bus.accesses.len(),
+        cycles as usize,
+        "opcode {:02X} should access the bus once per cycle",
+        opcode
+      );
+    }
+  }
+
+  #[test]
+  fn branch_cycles() {
+    // BNE +2, not taken:
+    let mut bus = RecordingBus::new(&[0xD0, 0x02]);
+    let mut cpu = Cpu::new();
+    cpu.set_status(Zero, true);
+    assert_eq!(run_one(&mut cpu, &mut bus), 2);
+    assert_eq!(cpu.pc, 0x0202);
+
+    // BNE +2, taken:
+    let mut bus = RecordingBus::new(&[0xD0, 0x02]);
+    let mut cpu = Cpu::new();
+    assert_eq!(run_one(&mut cpu, &mut bus), 3);
+    assert_eq!(cpu.pc, 0x0204);
+
+    // BNE -4, taken, into the previous page:
+    let mut bus = RecordingBus::new(&[0xD0, 0xFC]);
+    let mut cpu = Cpu::new();
+    assert_eq!(run_one(&mut cpu, &mut bus), 4);
+    assert_eq!(cpu.pc, 0x01FE);
+    // The extra cycle reads from the un-fixed address on the old page:
+    assert_eq!(bus.accesses[3], Read(0x02FE));
+  }
+
+  #[test]
+  fn read_modify_write_writes_twice() {
+    // INC $10
+    let mut bus = RecordingBus::new(&[0xE6, 0x10]);
+    bus.ram[0x0010] = 0x41;
+    let mut cpu = Cpu::new();
+    run_one(&mut cpu, &mut bus);
+
+    assert_eq!(
+      bus.accesses,
+      vec![
+        Read(0x0200),
+        Read(0x0201),
+        Read(0x0010),
+        Write(0x0010, 0x41),
+        Write(0x0010, 0x42),
+      ]
+    );
+  }
+
+  #[test]
+  fn indexed_dummy_reads() {
+    // LDA $02FF,X; crossing a page costs a read from the wrong page:
+    let mut bus = RecordingBus::new(&[0xBD, 0xFF, 0x02]);
+    let mut cpu = Cpu::new();
+    cpu.x = 0x01;
+    assert_eq!(run_one(&mut cpu, &mut bus), 5);
+    assert_eq!(
+      bus.accesses,
+      vec![
+        Read(0x0200),
+        Read(0x0201),
+        Read(0x0202),
+        Read(0x0200),
+        Read(0x0300),
+      ]
+    );
+
+    // STA $0300,X; writes always take the extra cycle, even without crossing:
+    let mut bus = RecordingBus::new(&[0x9D, 0x00, 0x03]);
+    let mut cpu = Cpu::new();
+    cpu.x = 0x01;
+    cpu.a = 0x99;
+    assert_eq!(run_one(&mut cpu, &mut bus), 5);
+    assert_eq!(
+      bus.accesses,
+      vec![
+        Read(0x0200),
+        Read(0x0201),
+        Read(0x0202),
+        Read(0x0301),
+        Write(0x0301, 0x99),
+      ]
+    );
+  }
+
+  #[test]
+  fn interrupts_wait_for_the_current_instruction() {
+    // LDA #$01
+    let mut bus = RecordingBus::new(&[0xA9, 0x01]);
+    bus.ram[NMI_POINTER as usize] = 0x34;
+    bus.ram[NMI_POINTER as usize + 1] = 0x12;
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+
+    cpu.set_nmi_line(true);
+    cpu.clock(&mut bus);
+    cpu.clock(&mut bus);
+    assert_eq!(cpu.a, 0x01);
+    assert_eq!(cpu.cycle, 0);
+
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x1234);
+    assert_eq!(cpu.s, STACK_INIT.wrapping_sub(3));
+    // Return address, then status with Break clear:
+    assert_eq!(bus.ram[0x01FD], 0x02);
+    assert_eq!(bus.ram[0x01FC], 0x02);
+    assert_eq!(bus.ram[0x01FB] & (Break as u8), 0x00);
+  }
+
+  #[test]
+  fn unofficial_immediates() {
+    struct Test {
+      opcode: u8,
+      a: u8,
+      x: u8,
+      m: u8,
+      carry: bool,
+      // expected outputs:
+      r_a: u8,
+      r_x: u8,
+      flags: u8,
+    }
+
+    #[rustfmt::skip]
+    let tests = vec![
+      // ANC copies N into C:
+      Test { opcode: 0x0B, a: 0xF0, x: 0x00, m: 0x80, carry: false, r_a: 0x80, r_x: 0x00, flags: Negative as u8 | Carry as u8 },
+      Test { opcode: 0x2B, a: 0xF0, x: 0x00, m: 0x0F, carry: true, r_a: 0x00, r_x: 0x00, flags: Zero as u8 },
+      // ALR shifts bit 0 of the AND into C:
+      Test { opcode: 0x4B, a: 0xFF, x: 0x00, m: 0x03, carry: false, r_a: 0x01, r_x: 0x00, flags: Carry as u8 },
+      // ARR rotates C in, and takes C and V from bits 6 and 5:
+      Test { opcode: 0x6B, a: 0xFF, x: 0x00, m: 0xC0, carry: true, r_a: 0xE0, r_x: 0x00, flags: Negative as u8 | Carry as u8 },
+      Test { opcode: 0x6B, a: 0xFF, x: 0x00, m: 0x80, carry: false, r_a: 0x40, r_x: 0x00, flags: Carry as u8 | Overflow as u8 },
+      // AXS compares A AND X with the operand, leaving the difference in X:
+      Test { opcode: 0xCB, a: 0x0F, x: 0xFF, m: 0x05, carry: false, r_a: 0x0F, r_x: 0x0A, flags: Carry as u8 },
+      Test { opcode: 0xCB, a: 0x0F, x: 0xFF, m: 0x10, carry: true, r_a: 0x0F, r_x: 0xFF, flags: Negative as u8 },
+      // LXA and XAA mix in the unstable magic value:
+      Test { opcode: 0xAB, a: 0x00, x: 0x00, m: 0xFF, carry: false, r_a: UNSTABLE_MAGIC, r_x: UNSTABLE_MAGIC, flags: Negative as u8 },
+      Test { opcode: 0x8B, a: 0x00, x: 0x0F, m: 0xFF, carry: false, r_a: UNSTABLE_MAGIC & 0x0F, r_x: 0x0F, flags: 0x00 },
+    ];
+
+    for test in tests {
+      let mut bus = RecordingBus::new(&[test.opcode, test.m]);
+      let mut cpu = Cpu::new();
+      cpu.status = 0x00;
+      cpu.set_status(Carry, test.carry);
+      cpu.a = test.a;
+      cpu.x = test.x;
+      assert_eq!(run_one(&mut cpu, &mut bus), 2);
+
+      assert_eq!(cpu.a, test.r_a, "opcode {:02X}", test.opcode);
+      assert_eq!(cpu.x, test.r_x, "opcode {:02X}", test.opcode);
+      assert_eq!(cpu.status, test.flags, "opcode {:02X}", test.opcode);
+    }
+  }
+
+  #[test]
+  fn las() {
+    // LAS $0300,Y
+    let mut bus = RecordingBus::new(&[0xBB, 0x00, 0x03]);
+    bus.ram[0x0301] = 0xF3;
+    let mut cpu = Cpu::new();
+    cpu.y = 0x01;
+    cpu.s = 0x9F;
+    assert_eq!(run_one(&mut cpu, &mut bus), 4);
+    assert_eq!((cpu.a, cpu.x, cpu.s), (0x93, 0x93, 0x93));
+    assert_ne!(cpu.get_status(Negative), 0);
+  }
+
+  #[test]
+  fn unstable_stores() {
+    // SHX $0300,Y; stores X AND ($03 + 1):
+    let mut bus = RecordingBus::new(&[0x9E, 0x00, 0x03]);
+    let mut cpu = Cpu::new();
+    cpu.x = 0xFF;
+    cpu.y = 0x01;
+    assert_eq!(run_one(&mut cpu, &mut bus), 5);
+    assert_eq!(bus.accesses[4], Write(0x0301, 0x04));
+
+    // SHY $02FF,X; crossing a page replaces the high byte of the address with
+    // the stored value:
+    let mut bus = RecordingBus::new(&[0x9C, 0xFF, 0x02]);
+    let mut cpu = Cpu::new();
+    cpu.x = 0x01;
+    cpu.y = 0x01;
+    assert_eq!(run_one(&mut cpu, &mut bus), 5);
+    assert_eq!(bus.accesses[3], Read(0x0200));
+    assert_eq!(bus.accesses[4], Write(0x0100, 0x01));
+
+    // TAS $0300,Y; also sets the stack pointer to A AND X:
+    let mut bus = RecordingBus::new(&[0x9B, 0x00, 0x03]);
+    let mut cpu = Cpu::new();
+    cpu.a = 0xF0;
+    cpu.x = 0x3C;
+    assert_eq!(run_one(&mut cpu, &mut bus), 5);
+    assert_eq!(cpu.s, 0x30);
+    assert_eq!(bus.accesses[4], Write(0x0300, 0x00));
+
+    // AHX ($10),Y
+    let mut bus = RecordingBus::new(&[0x93, 0x10]);
+    bus.ram[0x0010] = 0x00;
+    bus.ram[0x0011] = 0x07;
+    let mut cpu = Cpu::new();
+    cpu.a = 0xFF;
+    cpu.x = 0x0F;
+    cpu.y = 0x02;
+    assert_eq!(run_one(&mut cpu, &mut bus), 6);
+    assert_eq!(bus.accesses[5], Write(0x0702, 0x08));
+  }
+
+  #[test]
+  fn kil_jams_until_reset() {
+    let mut bus = RecordingBus::new(&[0x02, 0xEA]);
+    bus.ram[PC_INIT_ADDR as usize] = 0x01;
+    bus.ram[PC_INIT_ADDR as usize + 1] = 0x02;
+    let mut cpu = Cpu::new();
+    run_one(&mut cpu, &mut bus);
+    assert_eq!(cpu.jammed(), Some((0x0200, 0x02)));
+
+    // Interrupts can't get it going again; it just sits on the bus:
+    bus.accesses.clear();
+    cpu.set_nmi_line(true);
+    for _ in 0..10 {
+      cpu.step(&mut bus);
+    }
+    assert_eq!(cpu.jammed(), Some((0x0200, 0x02)));
+    assert_eq!(bus.accesses, vec![Read(0xFFFF); 10]);
+
+    cpu.sig_reset();
+    cpu.step(&mut bus);
+    assert_eq!(cpu.jammed(), None);
+    assert_eq!(cpu.pc, 0x0201);
+  }
+
+  /// Points NMIs at $0300 and IRQs at $0400, and fills both with NOPs.
+  fn interrupt_bus(program: &[u8]) -> RecordingBus {
+    let mut bus = RecordingBus::new(program);
+    bus.ram[NMI_POINTER as usize + 1] = 0x03;
+    bus.ram[IRQ_POINTER as usize + 1] = 0x04;
+    bus.ram[0x0300..0x0500].fill(0xEA);
+    bus
+  }
+
+  #[test]
+  fn late_interrupts_wait_for_the_next_instruction() {
+    // LDA #$01; LDA #$02
+    let mut bus = interrupt_bus(&[0xA9, 0x01, 0xA9, 0x02]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+
+    // An NMI during the last cycle is too late for the poll before it:
+    cpu.clock(&mut bus);
+    cpu.set_nmi_line(true);
+    cpu.clock(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.a, 0x02);
+
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0300);
+  }
+
+  #[test]
+  fn nmi_is_edge_triggered() {
+    let mut bus = interrupt_bus(&[0xEA]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+
+    cpu.set_nmi_line(true);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0300);
+
+    // Holding the line doesn't fire another one:
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0302);
+  }
+
+  #[test]
+  fn irq_is_level_triggered() {
+    // NOP; NOP; NOP; NOP
+    let mut bus = interrupt_bus(&[0xEA, 0xEA, 0xEA, 0xEA]);
+    // The handler returns straight away:
+    bus.ram[0x0400] = 0x40;
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+    cpu.set_status(DisableInterrupts, false);
+
+    cpu.set_irq_line(true);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+
+    // RTI clears the I flag early enough to let the line straight back in:
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+
+    // Until it's acknowledged:
+    cpu.set_irq_line(false);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0202);
+
+    // And it's ignored while the I flag is set:
+    cpu.set_status(DisableInterrupts, true);
+    cpu.set_irq_line(true);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0204);
+  }
+
+  #[test]
+  fn cli_delays_irq_by_one_instruction() {
+    // CLI; NOP; NOP
+    let mut bus = interrupt_bus(&[0x58, 0xEA, 0xEA]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+    cpu.set_irq_line(true);
+
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0202);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+  }
+
+  #[test]
+  fn sei_lets_one_irq_through() {
+    // SEI
+    let mut bus = interrupt_bus(&[0x78]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+    cpu.set_status(DisableInterrupts, false);
+    cpu.set_irq_line(true);
+
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+    // The pushed status has I set, so the RTI won't let the IRQ back in:
+    assert_ne!(bus.ram[0x01FB] & (DisableInterrupts as u8), 0x00);
+  }
+
+  #[test]
+  fn taken_branch_delays_irq() {
+    // BNE +0; NOP
+    let mut bus = interrupt_bus(&[0xD0, 0x00, 0xEA]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+    cpu.set_status(DisableInterrupts, false);
+
+    // In time for the poll before the last cycle of anything but a branch:
+    cpu.clock(&mut bus);
+    cpu.set_irq_line(true);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0202);
+
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0203);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0400);
+  }
+
+  #[test]
+  fn nmi_hijacks_brk() {
+    let mut bus = interrupt_bus(&[0x00]);
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0200;
+
+    for _ in 0..3 {
+      cpu.clock(&mut bus);
+    }
+    cpu.set_nmi_line(true);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0300);
+    // It still looks like a BRK to the handler:
+    assert_ne!(bus.ram[0x01FB] & (Break as u8), 0x00);
+
+    // And the NMI isn't taken again:
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0301);
+  }
 }
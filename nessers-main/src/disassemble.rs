@@ -92,6 +92,18 @@ impl From<Trace> for DisassembledOperation {
       RLA => "RLA",
       SRE => "SRE",
       RRA => "RRA",
+      ANC => "ANC",
+      ALR => "ALR",
+      ARR => "ARR",
+      AXS => "AXS",
+      LAS => "LAS",
+      LXA => "LXA",
+      XAA => "XAA",
+      SHX => "SHX",
+      SHY => "SHY",
+      TAS => "TAS",
+      AHX => "AHX",
+      KIL => "KIL",
     }
     .into();
 
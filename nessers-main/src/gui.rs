@@ -1,8 +1,20 @@
-use crate::{cpu6502::NMI_POINTER, disassemble::disassemble, nes::Nes};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nessers::{
+  apu::{Channel, FilterProfile},
+  cpu6502::NMI_POINTER,
+  disassemble::disassemble,
+  ntsc::NtscPreset,
+  palette::{Decoder, PaletteSettings},
+  wav::SampleFormat,
+  Nes, Palette,
+};
 
 use egui::{ClippedMesh, Context, TexturesDelta};
 use egui_memory_editor::{option_data::MemoryEditorOptions, MemoryEditor};
 use egui_wgpu_backend::{BackendError, RenderPass, ScreenDescriptor};
+use log::error;
 use pixels::{wgpu, PixelsContext};
 use winit::window::Window;
 
@@ -25,13 +37,28 @@ struct Gui {
   bus_open: bool,
   bus_editor: MemoryEditor,
   debugger_open: bool,
+  audio_open: bool,
+  recording_path: String,
+  recording_format: SampleFormat,
+  recording_stems: bool,
+  vgm_path: String,
+  palette_open: bool,
+  palette_path: String,
+  palette_settings: PaletteSettings,
   search_string: String,
   search_pattern: Option<Vec<u8>>,
+  state_path: PathBuf,
 }
 
 impl Framework {
   /// Create egui.
-  pub(crate) fn new(width: u32, height: u32, scale_factor: f32, pixels: &pixels::Pixels) -> Self {
+  pub(crate) fn new(
+    width: u32,
+    height: u32,
+    scale_factor: f32,
+    pixels: &pixels::Pixels,
+    state_path: PathBuf,
+  ) -> Self {
     let max_texture_size = pixels.device().limits().max_texture_dimension_2d as usize;
 
     let egui_ctx = Context::default();
@@ -43,7 +70,7 @@ impl Framework {
     };
     let rpass = RenderPass::new(pixels.device(), pixels.render_texture_format(), 1);
     let textures = TexturesDelta::default();
-    let gui = Gui::new();
+    let gui = Gui::new(state_path);
 
     Self {
       egui_ctx,
@@ -126,7 +153,7 @@ impl Framework {
 
 impl Gui {
   /// Create a `Gui`.
-  fn new() -> Self {
+  fn new(state_path: PathBuf) -> Self {
     let mut opts = MemoryEditorOptions::default();
     opts.is_options_collapsed = true;
     opts.show_ascii = false;
@@ -137,9 +164,18 @@ impl Gui {
     Self {
       bus_open: false,
       debugger_open: false,
+      audio_open: false,
+      recording_path: state_path.with_extension("wav").display().to_string(),
+      recording_format: SampleFormat::Pcm16,
+      recording_stems: false,
+      vgm_path: state_path.with_extension("vgm").display().to_string(),
+      palette_open: false,
+      palette_path: String::new(),
+      palette_settings: PaletteSettings::default(),
       bus_editor,
       search_string: String::new(),
       search_pattern: None,
+      state_path,
     }
   }
 
@@ -149,6 +185,67 @@ impl Gui {
   fn ui(&mut self, ctx: &Context, nes: &mut Nes) -> bool {
     egui::TopBottomPanel::top("menubar_container").show(ctx, |ui| {
       egui::menu::bar(ui, |ui| {
+        ui.menu_button("Cart", |ui| {
+          let has_battery = nes.cart.has_battery();
+          if ui
+            .add_enabled(has_battery, egui::Button::new("Flush save RAM"))
+            .clicked()
+          {
+            if let Err(msg) = nes.cart.flush_save_ram() {
+              error!("{}", msg);
+            }
+            ui.close_menu();
+          }
+        });
+
+        ui.menu_button("State", |ui| {
+          if ui.button("Save state").clicked() {
+            if let Err(e) = fs::write(&self.state_path, nes.save_state()) {
+              error!("Failed to save state: {}", e);
+            }
+            ui.close_menu();
+          }
+
+          if ui
+            .add_enabled(self.state_path.exists(), egui::Button::new("Load state"))
+            .clicked()
+          {
+            match fs::read(&self.state_path) {
+              Ok(data) => {
+                if let Err(msg) = nes.load_state(&data) {
+                  error!("Failed to load state: {}", msg);
+                }
+              }
+              Err(e) => error!("Failed to load state: {}", e),
+            }
+            ui.close_menu();
+          }
+        });
+
+        ui.menu_button("Video", |ui| {
+          let mut unlimited = nes.ppu.unlimited_sprites();
+          if ui.checkbox(&mut unlimited, "No sprite limit").changed() {
+            nes.ppu.set_unlimited_sprites(unlimited);
+          }
+
+          ui.separator();
+          ui.label("NTSC filter:");
+          let mut preset = nes.ntsc_preset();
+          ui.radio_value(&mut preset, None, "Off");
+          for option in NtscPreset::ALL {
+            ui.radio_value(&mut preset, Some(option), option.name());
+          }
+          if preset != nes.ntsc_preset() {
+            nes.set_ntsc_preset(preset);
+          }
+          ui.separator();
+
+          if ui.button("Palette").clicked() {
+            self.palette_open = true;
+            ui.close_menu();
+          }
+        });
+
         ui.menu_button("Debug", |ui| {
           if ui.button("Bus editor").clicked() {
             self.bus_open = true;
@@ -159,6 +256,11 @@ impl Gui {
             self.debugger_open = true;
             ui.close_menu();
           }
+
+          if ui.button("Audio").clicked() {
+            self.audio_open = true;
+            ui.close_menu();
+          }
         })
       });
     });
@@ -283,6 +385,12 @@ impl Gui {
           .max(0)
           .min(disassembled_output.len() as i32) as usize;
         let disassembled_output = &disassembled_output[start..end];
+        if let Some((addr, opcode)) = nes.cpu.jammed() {
+          ui.colored_label(
+            egui::Color32::LIGHT_RED,
+            format!("CPU jammed at ${:04X} (opcode {:02X})", addr, opcode),
+          );
+        }
         ui.code(format!(
           "PC: {:04X}        PPU: {:02X} {:08b}",
           nes.cpu.pc, nes.ppu.status, nes.ppu.status
@@ -308,15 +416,141 @@ impl Gui {
         ui.code(format!(
           "           MIRRORING: {}",
           match nes.cart.mirroring() {
-            crate::cart::Mirroring::Horizontal => "Horizontal",
-            crate::cart::Mirroring::Vertical => "Vertical",
-            crate::cart::Mirroring::OneScreenLo => "OneScreenLo",
-            crate::cart::Mirroring::OneScreenHi => "OneScreenHi",
+            nessers::Mirroring::Horizontal => "Horizontal",
+            nessers::Mirroring::Vertical => "Vertical",
+            nessers::Mirroring::OneScreenLo => "OneScreenLo",
+            nessers::Mirroring::OneScreenHi => "OneScreenHi",
           }
         ));
         ui.code(disassembled_output.join("\n"));
       });
 
+    egui::Window::new("Audio")
+      .open(&mut self.audio_open)
+      .show(ctx, |ui| {
+        egui::Grid::new("audio_channels").show(ui, |ui| {
+          for channel in Channel::ALL {
+            let mut mix = nes.apu.channel_mix(channel);
+            ui.label(channel.name());
+            ui.checkbox(&mut mix.muted, "Mute");
+            ui.checkbox(&mut mix.solo, "Solo");
+            ui.add(egui::Slider::new(&mut mix.volume, 0.0..=2.0).text("Volume"));
+            ui.end_row();
+            nes.apu.set_channel_mix(channel, mix);
+          }
+        });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+          ui.label("Filters:");
+          let mut profile = nes.apu.filter_profile();
+          for option in FilterProfile::ALL {
+            ui.radio_value(&mut profile, option, option.name());
+          }
+          if profile != nes.apu.filter_profile() {
+            nes.apu.set_filter_profile(profile);
+          }
+        });
+
+        ui.separator();
+        ui.add_enabled_ui(!nes.is_recording(), |ui| {
+          ui.horizontal(|ui| {
+            ui.label("Record to:");
+            ui.text_edit_singleline(&mut self.recording_path);
+          });
+          ui.horizontal(|ui| {
+            for format in SampleFormat::ALL {
+              ui.radio_value(&mut self.recording_format, format, format.name());
+            }
+            ui.checkbox(&mut self.recording_stems, "Stems");
+          });
+        });
+        if nes.is_recording() {
+          if ui.button("Stop recording").clicked() {
+            if let Err(e) = nes.stop_recording() {
+              error!("Failed to write audio: {}", e);
+            }
+          }
+        } else if ui.button("Start recording").clicked() {
+          if let Err(e) = nes.start_recording(
+            Path::new(&self.recording_path),
+            self.recording_format,
+            self.recording_stems,
+          ) {
+            error!("Failed to record to {}: {}", self.recording_path, e);
+          }
+        }
+
+        ui.separator();
+        ui.add_enabled_ui(!nes.is_vgm_logging(), |ui| {
+          ui.horizontal(|ui| {
+            ui.label("VGM log:");
+            ui.text_edit_singleline(&mut self.vgm_path);
+          });
+        });
+        if nes.is_vgm_logging() {
+          if ui.button("Stop logging").clicked() {
+            if let Err(e) = nes.stop_vgm_log() {
+              error!("Failed to write VGM log: {}", e);
+            }
+          }
+        } else if ui.button("Start logging").clicked() {
+          if let Err(e) = nes.start_vgm_log(Path::new(&self.vgm_path)) {
+            error!("Failed to log to {}: {}", self.vgm_path, e);
+          }
+        }
+      });
+
+    egui::Window::new("Palette")
+      .open(&mut self.palette_open)
+      .show(ctx, |ui| {
+        // Only the colors change; palette RAM is the game's:
+        if ui.button("Built-in").clicked() {
+          nes.ppu.palette.colors = Palette::embedded().colors;
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+          ui.label(".pal file:");
+          ui.text_edit_singleline(&mut self.palette_path);
+          if ui.button("Load").clicked() {
+            match Palette::from_file(&self.palette_path) {
+              Ok(palette) => nes.ppu.palette.colors = palette.colors,
+              Err(msg) => error!("Failed to load {}: {}", self.palette_path, msg),
+            }
+          }
+        });
+
+        ui.separator();
+        let settings = &mut self.palette_settings;
+        let mut changed = false;
+        changed |= ui
+          .add(egui::Slider::new(&mut settings.hue, -180.0..=180.0).text("Hue"))
+          .changed();
+        changed |= ui
+          .add(egui::Slider::new(&mut settings.saturation, 0.0..=2.0).text("Saturation"))
+          .changed();
+        changed |= ui
+          .add(egui::Slider::new(&mut settings.contrast, 0.5..=1.5).text("Contrast"))
+          .changed();
+        changed |= ui
+          .add(egui::Slider::new(&mut settings.brightness, -0.5..=0.5).text("Brightness"))
+          .changed();
+        changed |= ui
+          .add(egui::Slider::new(&mut settings.gamma, 1.0..=3.0).text("Gamma"))
+          .changed();
+        ui.horizontal(|ui| {
+          for decoder in Decoder::ALL {
+            changed |= ui
+              .radio_value(&mut settings.decoder, decoder, decoder.name())
+              .changed();
+          }
+        });
+        if ui.button("Generate").clicked() || changed {
+          nes.ppu.palette.colors = Palette::generate(settings).colors;
+        }
+      });
+
     // It's not obvious at all but this checks to see if any UI has focus, and
     // if it does, returns `Some(...)`.
     //
@@ -0,0 +1,454 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nessers::apu::FilterProfile;
+use nessers::audio_sink::NullSink;
+use nessers::ntsc::NtscPreset;
+use nessers::savestate;
+use nessers::wav::SampleFormat;
+use nessers::{Controller, Nes, Palette, NTSC_W, SCREEN_H, SCREEN_W};
+
+/// Everything needed to run the emulator without a window or audio device.
+pub struct HeadlessOptions {
+  pub rom: String,
+  pub palette: Palette,
+  pub frames: u32,
+  pub sample_rate: u32,
+  pub input: Option<PathBuf>,
+  pub screenshot: Option<PathBuf>,
+  pub hash: bool,
+  pub ram_dump: Option<PathBuf>,
+  pub audio: Option<PathBuf>,
+  pub audio_format: SampleFormat,
+  /// Whether recordings also get a file per APU channel
+  pub stems: bool,
+  pub vgm: Option<PathBuf>,
+  pub filter_profile: FilterProfile,
+  /// Whether to draw every sprite on a scanline, not just the first 8
+  pub unlimited_sprites: bool,
+  /// Screenshots go through the NTSC filter with this preset, if any
+  pub ntsc: Option<NtscPreset>,
+}
+
+/// Something to do at the start of a given frame.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+  /// Set the buttons held by a player (0 or 1); anything not listed is
+  /// released.
+  Buttons(usize, Controller),
+  Screenshot(PathBuf),
+  Hash,
+  RamDump(PathBuf),
+  Reset,
+  Record(PathBuf),
+  StopRecording,
+  VgmLog(PathBuf),
+  StopVgmLog,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScriptEvent {
+  pub frame: u32,
+  pub action: Action,
+}
+
+/// Parses an input script.
+///
+/// Each line is `<frame> <action> [args...]`; actions run at the start of the
+/// given frame (counting from 0). Blank lines and anything after a `#` are
+/// ignored:
+///
+/// ```text
+/// # Hold start for a few frames to get past the title screen
+/// 30   p1 start
+/// 35   p1
+/// 90   p1 right a
+/// 120  screenshot level-1.png
+/// 120  hash
+/// 121  ram level-1.ram
+/// 200  reset
+/// 300  record level-2.wav
+/// 900  stop-recording
+/// 300  vgm level-2.vgm
+/// 900  stop-vgm
+/// ```
+///
+/// Button names are `a`, `b`, `select`, `start`, `up`, `down`, `left` and
+/// `right`. Events must be in frame order.
+pub fn parse_script(script: &str) -> Result<Vec<ScriptEvent>, String> {
+  let mut events = vec![];
+  let mut last_frame = 0;
+
+  for (line_idx, line) in script.lines().enumerate() {
+    let line_num = line_idx + 1;
+    let line = match line.find('#') {
+      Some(idx) => &line[..idx],
+      None => line,
+    };
+    let mut words = line.split_whitespace();
+
+    let frame = match words.next() {
+      Some(word) => word
+        .parse::<u32>()
+        .map_err(|_| format!("Line {}: invalid frame number \"{}\"", line_num, word))?,
+      None => continue,
+    };
+
+    if frame < last_frame {
+      return Err(format!("Line {}: events must be in frame order", line_num));
+    }
+    last_frame = frame;
+
+    let action = match words.next() {
+      Some(player @ ("p1" | "p2")) => {
+        let mut controller = Controller::new();
+        for button in words.by_ref() {
+          match button {
+            "a" => controller.a = true,
+            "b" => controller.b = true,
+            "select" => controller.select = true,
+            "start" => controller.start = true,
+            "up" => controller.up = true,
+            "down" => controller.down = true,
+            "left" => controller.left = true,
+            "right" => controller.right = true,
+            _ => return Err(format!("Line {}: unknown button \"{}\"", line_num, button)),
+          }
+        }
+        Action::Buttons(if player == "p1" { 0 } else { 1 }, controller)
+      }
+      Some("screenshot") => Action::Screenshot(path_arg(&mut words, line_num)?),
+      Some("hash") => Action::Hash,
+      Some("ram") => Action::RamDump(path_arg(&mut words, line_num)?),
+      Some("reset") => Action::Reset,
+      Some("record") => Action::Record(path_arg(&mut words, line_num)?),
+      Some("stop-recording") => Action::StopRecording,
+      Some("vgm") => Action::VgmLog(path_arg(&mut words, line_num)?),
+      Some("stop-vgm") => Action::StopVgmLog,
+      Some(action) => return Err(format!("Line {}: unknown action \"{}\"", line_num, action)),
+      None => return Err(format!("Line {}: missing action", line_num)),
+    };
+
+    if let Some(extra) = words.next() {
+      return Err(format!("Line {}: unexpected \"{}\"", line_num, extra));
+    }
+
+    events.push(ScriptEvent { frame, action });
+  }
+
+  Ok(events)
+}
+
+fn path_arg<'a>(
+  words: &mut impl Iterator<Item = &'a str>,
+  line_num: usize,
+) -> Result<PathBuf, String> {
+  match words.next() {
+    Some(path) => Ok(PathBuf::from(path)),
+    None => Err(format!("Line {}: missing file name", line_num)),
+  }
+}
+
+pub fn run(options: &HeadlessOptions) -> Result<(), String> {
+  let script = match &options.input {
+    Some(path) => {
+      let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+      parse_script(&contents)?
+    }
+    None => vec![],
+  };
+
+  // Loading the ROM straight from memory leaves out the `.sav` file next to
+  // it, which would otherwise carry battery-backed RAM from one run to the
+  // next and make the results depend on it:
+  let rom = fs::read(&options.rom).map_err(|e| format!("Failed to read {}: {}", options.rom, e))?;
+  let mut nes = Nes::from_rom(options.sample_rate as f32, &rom, options.palette.clone())?;
+  nes.apu.set_filter_profile(options.filter_profile);
+  nes.ppu.set_unlimited_sprites(options.unlimited_sprites);
+  nes.set_ntsc_preset(options.ntsc);
+  nes.reset();
+
+  if let Some(path) = &options.audio {
+    apply(&mut nes, options, 0, &Action::Record(path.clone()))?;
+  }
+  if let Some(path) = &options.vgm {
+    apply(&mut nes, options, 0, &Action::VgmLog(path.clone()))?;
+  }
+
+  let mut sink = NullSink::new(options.sample_rate);
+  let mut events = script.iter().peekable();
+  let mut jammed = None;
+  for frame in 0..options.frames {
+    while let Some(event) = events.next_if(|e| e.frame == frame) {
+      apply(&mut nes, options, frame, &event.action)?;
+    }
+
+    // No breakpoints are set, so this always runs to the end of the frame:
+    nes.frame();
+
+    // Only a reset gets a jammed CPU going again, so there's no point saying
+    // so every frame:
+    if nes.cpu.jammed() != jammed {
+      jammed = nes.cpu.jammed();
+      if let Some((addr, opcode)) = jammed {
+        println!(
+          "frame {} CPU jammed at ${:04X} (opcode {:02X})",
+          frame, addr, opcode
+        );
+      }
+    }
+
+    // Recordings get their samples straight from `nes`, so these can go:
+    nes.flush_audio(&mut sink);
+  }
+
+  // Anything scheduled for after the last frame runs against the final state,
+  // which makes `<frames> screenshot ...` do what you'd expect:
+  for event in events {
+    apply(&mut nes, options, options.frames, &event.action)?;
+  }
+
+  apply(&mut nes, options, options.frames, &Action::StopRecording)?;
+  apply(&mut nes, options, options.frames, &Action::StopVgmLog)?;
+
+  if let Some(path) = &options.screenshot {
+    apply(
+      &mut nes,
+      options,
+      options.frames,
+      &Action::Screenshot(path.clone()),
+    )?;
+  }
+
+  if options.hash {
+    apply(&mut nes, options, options.frames, &Action::Hash)?;
+  }
+
+  if let Some(path) = &options.ram_dump {
+    apply(
+      &mut nes,
+      options,
+      options.frames,
+      &Action::RamDump(path.clone()),
+    )?;
+  }
+
+  Ok(())
+}
+
+fn apply(
+  nes: &mut Nes,
+  options: &HeadlessOptions,
+  frame: u32,
+  action: &Action,
+) -> Result<(), String> {
+  match action {
+    Action::Buttons(player, controller) => nes.set_controller(*player, *controller),
+    Action::Screenshot(path) => write_screenshot(nes, path)?,
+    Action::Hash => println!("frame {} hash {:016x}", frame, framebuffer_hash(nes)),
+    Action::RamDump(path) => {
+      let ram: Vec<u8> = (0x0000..0x0800)
+        .map(|addr| nes.safe_cpu_read(addr))
+        .collect();
+      fs::write(path, ram).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Action::Reset => nes.reset(),
+    Action::Record(path) => nes
+      .start_recording(path, options.audio_format, options.stems)
+      .map_err(|e| format!("Failed to record to {}: {}", path.display(), e))?,
+    Action::StopRecording => nes
+      .stop_recording()
+      .map_err(|e| format!("Failed to write audio: {}", e))?,
+    Action::VgmLog(path) => nes
+      .start_vgm_log(path)
+      .map_err(|e| format!("Failed to log to {}: {}", path.display(), e))?,
+    Action::StopVgmLog => nes
+      .stop_vgm_log()
+      .map_err(|e| format!("Failed to write VGM log: {}", e))?,
+  }
+  Ok(())
+}
+
+/// A hash of the current frame, handy for comparing runs without keeping
+/// screenshots around.
+pub fn framebuffer_hash(nes: &Nes) -> u64 {
+  savestate::hash(&framebuffer(nes))
+}
+
+fn framebuffer(nes: &Nes) -> Vec<u8> {
+  nes.framebuffer().iter().flatten().copied().collect()
+}
+
+fn write_screenshot(nes: &mut Nes, path: &Path) -> Result<(), String> {
+  let (pixels, width) = match nes.ntsc_framebuffer() {
+    Some(pixels) => (pixels.iter().flatten().copied().collect(), NTSC_W),
+    None => (framebuffer(nes), SCREEN_W),
+  };
+  image::save_buffer(path, &pixels, width as u32, SCREEN_H as u32, image::RGBA(8))
+    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    let script = "
+      # Comment
+      0 p1 start   # Trailing comment
+      5 p2 a b
+
+      10 screenshot out.png
+      10 hash
+      11 ram out.ram
+      12 reset
+      12 p1
+      13 record out.wav
+      20 stop-recording
+      21 vgm out.vgm
+      30 stop-vgm
+    ";
+
+    let mut start = Controller::new();
+    start.start = true;
+    let mut a_b = Controller::new();
+    a_b.a = true;
+    a_b.b = true;
+
+    assert_eq!(
+      parse_script(script).unwrap(),
+      vec![
+        ScriptEvent {
+          frame: 0,
+          action: Action::Buttons(0, start)
+        },
+        ScriptEvent {
+          frame: 5,
+          action: Action::Buttons(1, a_b)
+        },
+        ScriptEvent {
+          frame: 10,
+          action: Action::Screenshot(PathBuf::from("out.png"))
+        },
+        ScriptEvent {
+          frame: 10,
+          action: Action::Hash
+        },
+        ScriptEvent {
+          frame: 11,
+          action: Action::RamDump(PathBuf::from("out.ram"))
+        },
+        ScriptEvent {
+          frame: 12,
+          action: Action::Reset
+        },
+        ScriptEvent {
+          frame: 12,
+          action: Action::Buttons(0, Controller::new())
+        },
+        ScriptEvent {
+          frame: 13,
+          action: Action::Record(PathBuf::from("out.wav"))
+        },
+        ScriptEvent {
+          frame: 20,
+          action: Action::StopRecording
+        },
+        ScriptEvent {
+          frame: 21,
+          action: Action::VgmLog(PathBuf::from("out.vgm"))
+        },
+        ScriptEvent {
+          frame: 30,
+          action: Action::StopVgmLog
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert_eq!(
+      parse_script("x p1"),
+      Err("Line 1: invalid frame number \"x\"".into())
+    );
+    assert_eq!(
+      parse_script("1 p1\n0 p1"),
+      Err("Line 2: events must be in frame order".into())
+    );
+    assert_eq!(
+      parse_script("0 p1 turbo"),
+      Err("Line 1: unknown button \"turbo\"".into())
+    );
+    assert_eq!(
+      parse_script("0 screenshot"),
+      Err("Line 1: missing file name".into())
+    );
+    assert_eq!(
+      parse_script("0 hash please"),
+      Err("Line 1: unexpected \"please\"".into())
+    );
+  }
+
+  /// Runs `rom` for 10 frames, with no outputs
+  fn options(rom: &str) -> HeadlessOptions {
+    HeadlessOptions {
+      rom: rom.into(),
+      palette: Palette::embedded(),
+      frames: 10,
+      sample_rate: 44_100,
+      input: None,
+      screenshot: None,
+      hash: false,
+      ram_dump: None,
+      audio: None,
+      audio_format: SampleFormat::Pcm16,
+      stems: false,
+      vgm: None,
+      filter_profile: FilterProfile::Nes,
+      unlimited_sprites: false,
+      ntsc: None,
+    }
+  }
+
+  #[test]
+  fn run_outputs() {
+    let dir = std::env::temp_dir().join(format!("nessers-headless-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let options = HeadlessOptions {
+      screenshot: Some(dir.join("screen.png")),
+      hash: true,
+      ram_dump: Some(dir.join("ram.bin")),
+      audio: Some(dir.join("audio.wav")),
+      vgm: Some(dir.join("music.vgm")),
+      ..options("src/test_fixtures/nestest.nes")
+    };
+    run(&options).unwrap();
+
+    assert_eq!(fs::read(dir.join("ram.bin")).unwrap().len(), 0x0800);
+    let wav = fs::read(dir.join("audio.wav")).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    // Roughly 10 frames' worth of 16-bit samples:
+    let num_samples = (wav.len() - 44) / 2;
+    assert!(num_samples > 7000 && num_samples < 7500, "{}", num_samples);
+    let vgm = fs::read(dir.join("music.vgm")).unwrap();
+    assert_eq!(&vgm[0..4], b"Vgm ");
+    let png = fs::read(dir.join("screen.png")).unwrap();
+    assert_eq!(&png[1..4], b"PNG");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn missing_rom() {
+    let err = run(&options("src/test_fixtures/missing.nes")).unwrap_err();
+    assert!(
+      err.starts_with("Failed to read src/test_fixtures/missing.nes"),
+      "{}",
+      err
+    );
+  }
+}
@@ -0,0 +1,58 @@
+//! The emulator core, without any windowing or audio output attached.
+//!
+//! Most things you'd want to do go through [`Nes`]:
+//!
+//! ```no_run
+//! use nessers::{Controller, Nes, Palette};
+//!
+//! let rom = std::fs::read("game.nes").unwrap();
+//! let mut nes = Nes::from_rom(44_100.0, &rom, Palette::embedded()).unwrap();
+//! nes.reset();
+//!
+//! let mut buttons = Controller::new();
+//! buttons.start = true;
+//! nes.set_controller(0, buttons);
+//!
+//! nes.frame();
+//! let pixels = nes.framebuffer();
+//! let samples: Vec<f32> = nes.drain_samples().collect();
+//! let zero_page = nes.safe_cpu_read(0x0000);
+//! ```
+//!
+//! The individual components are exposed too, mostly for debuggers and the
+//! like; expect those to change more often than `Nes`'s methods.
+
+#[macro_use]
+extern crate maplit;
+
+pub mod apu;
+pub mod audio_sink;
+pub mod blargg;
+pub mod cart;
+pub mod cpu6502;
+pub mod disassemble;
+pub mod mapper;
+pub mod nes;
+pub mod ntsc;
+pub mod palette;
+pub mod peripherals;
+pub mod ppu;
+pub mod ring;
+pub mod savestate;
+pub mod vgm;
+pub mod wav;
+
+mod blip;
+mod bus;
+mod bus_device;
+mod mirror;
+mod ram;
+mod recorder;
+mod trace;
+
+pub use cart::{Cart, CartHeader, Mirroring};
+pub use nes::Nes;
+pub use ntsc::NTSC_W;
+pub use palette::Palette;
+pub use peripherals::Controller;
+pub use ppu::{SCREEN_H, SCREEN_W};
@@ -1,14 +1,10 @@
-#[macro_use]
-extern crate maplit;
-
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
 
 use audio::AudioDevice;
-use cpal::traits::StreamTrait;
 use docopt::Docopt;
 use log::error;
+use nessers::{NTSC_W, SCREEN_H, SCREEN_W};
 use pixels::{Error, Pixels, SurfaceTexture};
-use ppu::{SCREEN_H, SCREEN_W};
 use serde::Deserialize;
 use std::time::{Duration, Instant};
 use winit::dpi::LogicalSize;
@@ -17,43 +13,173 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
-mod apu;
 mod audio;
-mod bus;
-mod bus_device;
-mod cart;
-mod cpu6502;
-mod disassemble;
 mod gui;
-mod mapper;
-mod mirror;
-mod nes;
-mod palette;
-mod peripherals;
-mod ppu;
-mod ram;
-mod trace;
+mod headless;
 
 use crate::gui::Framework;
-use crate::nes::Nes;
+use nessers::apu::FilterProfile;
+use nessers::audio_sink::{AudioSink, NullSink};
+use nessers::ntsc::NtscPreset;
+use nessers::palette::PaletteSettings;
+use nessers::wav::SampleFormat;
+use nessers::{Nes, Palette};
 
 const USAGE: &'static str = "
 Usage:
 
-nessers <rom> [<breakpoints>...]
+nessers [options] <rom> [<breakpoints>...]
+
+Options:
+  --autosave=<seconds>    Flush battery-backed save RAM to disk every N seconds.
+  --headless              Run without a window or audio device, then exit.
+                          Save RAM starts empty and isn't written back.
+  --frames=<n>            Number of frames to run when headless [default: 60].
+  --input=<file>          Input script to play back when headless; see
+                          `headless::parse_script` for the format.
+  --screenshot=<file>     Write the final frame to a PNG when headless.
+  --hash                  Print a hash of the final frame when headless.
+  --ram-dump=<file>       Write CPU RAM ($0000-$07FF) to a file when headless.
+  --audio=<file>          Record audio to a WAV file when headless.
+  --audio-format=<fmt>    Sample format for recordings: pcm16 or float32
+                          [default: pcm16].
+  --stems                 Also record each APU channel to its own WAV file.
+  --vgm=<file>            Log APU register writes to a VGM file when headless.
+  --sample-rate=<hz>      Audio sample rate when headless [default: 44100].
+  --audio-filter=<name>   Output filters to emulate: raw, nes or famicom
+                          [default: nes].
+  --no-sprite-limit       Draw every sprite on a scanline instead of the first
+                          8, so games don't flicker.
+  --palette=<file>        A .pal file to use instead of the built-in palette:
+                          64 or 512 RGB colors.
+  --generate-palette      Work the palette out from the video signal instead,
+                          with the settings below.
+  --hue=<degrees>         Hue rotation for the generated palette [default: 0].
+  --saturation=<n>        Saturation for the generated palette [default: 1].
+  --contrast=<n>          Contrast for the generated palette [default: 1].
+  --brightness=<n>        Brightness for the generated palette [default: 0].
+  --gamma=<n>             Display gamma for the generated palette
+                          [default: 2.2].
+  --ppu=<model>           Whose video signal to generate the palette from:
+                          2c02 (NTSC) or 2c07 (PAL) [default: 2c02].
+  --ntsc=<preset>         Run the picture through an NTSC video filter:
+                          composite, s-video or rgb.
 ";
 
 const WIDTH: u32 = 1280;
 const HEIGHT: u32 = 960;
 
+/// One frame at the NTSC console's real frame rate: 39375000/655171, or about
+/// 60.0988Hz.
+const FRAME_DURATION: Duration = Duration::from_nanos(16_639_263);
+
+/// How many seconds of audio the buffer between the emulator and the sound
+/// card holds; rate control keeps it about half full.
+const AUDIO_BUFFER_SECONDS: f32 = 0.1;
+
 #[derive(Deserialize)]
 struct Args {
   arg_rom: String,
   arg_breakpoints: Vec<String>,
+  flag_autosave: Option<u64>,
+  flag_headless: bool,
+  flag_frames: u32,
+  flag_input: Option<String>,
+  flag_screenshot: Option<String>,
+  flag_hash: bool,
+  flag_ram_dump: Option<String>,
+  flag_audio: Option<String>,
+  flag_audio_format: String,
+  flag_stems: bool,
+  flag_vgm: Option<String>,
+  flag_sample_rate: u32,
+  flag_audio_filter: String,
+  flag_no_sprite_limit: bool,
+  flag_palette: Option<String>,
+  flag_generate_palette: bool,
+  flag_hue: f32,
+  flag_saturation: f32,
+  flag_contrast: f32,
+  flag_brightness: f32,
+  flag_gamma: f32,
+  flag_ppu: String,
+  flag_ntsc: Option<String>,
+}
+
+/// The palette asked for on the command line; the built-in one unless told
+/// otherwise.
+fn palette(args: &Args) -> Result<Palette, String> {
+  if args.flag_generate_palette && args.flag_palette.is_some() {
+    return Err("--palette and --generate-palette can't be used together".into());
+  }
+  if args.flag_generate_palette {
+    let settings = PaletteSettings {
+      hue: args.flag_hue,
+      saturation: args.flag_saturation,
+      contrast: args.flag_contrast,
+      brightness: args.flag_brightness,
+      gamma: args.flag_gamma,
+      decoder: args.flag_ppu.parse()?,
+    };
+    return Ok(Palette::generate(&settings));
+  }
+  match &args.flag_palette {
+    Some(path) => Palette::from_file(path).map_err(|msg| format!("{}: {}", path, msg)),
+    None => Ok(Palette::embedded()),
+  }
 }
 
 fn main() -> Result<(), Error> {
   env_logger::init();
+  let args: Args = Docopt::new(USAGE)
+    .and_then(|d| d.deserialize())
+    .unwrap_or_else(|e| e.exit());
+  let filter_profile: FilterProfile = args.flag_audio_filter.parse().unwrap_or_else(|msg| {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+  });
+  let audio_format: SampleFormat = args.flag_audio_format.parse().unwrap_or_else(|msg| {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+  });
+  let palette = palette(&args).unwrap_or_else(|msg| {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+  });
+  let ntsc: Option<NtscPreset> = args.flag_ntsc.as_ref().map(|name| {
+    name.parse().unwrap_or_else(|msg| {
+      eprintln!("{}", msg);
+      std::process::exit(1);
+    })
+  });
+
+  // Bail out before touching winit/wgpu/cpal, so this works on machines
+  // without a display or sound card:
+  if args.flag_headless {
+    let options = headless::HeadlessOptions {
+      rom: args.arg_rom,
+      palette,
+      frames: args.flag_frames,
+      sample_rate: args.flag_sample_rate,
+      input: args.flag_input.map(PathBuf::from),
+      screenshot: args.flag_screenshot.map(PathBuf::from),
+      hash: args.flag_hash,
+      ram_dump: args.flag_ram_dump.map(PathBuf::from),
+      audio: args.flag_audio.map(PathBuf::from),
+      audio_format,
+      stems: args.flag_stems,
+      vgm: args.flag_vgm.map(PathBuf::from),
+      filter_profile,
+      unlimited_sprites: args.flag_no_sprite_limit,
+      ntsc,
+    };
+    if let Err(msg) = headless::run(&options) {
+      eprintln!("{}", msg);
+      std::process::exit(1);
+    }
+    return Ok(());
+  }
+
   let event_loop = EventLoop::new();
   let mut input = WinitInputHelper::new();
   let window = {
@@ -73,29 +199,35 @@ fn main() -> Result<(), Error> {
     let window_size = window.inner_size();
     let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
     let pixels = Pixels::new(WIDTH, HEIGHT, surface_texture)?;
-    let framework = Framework::new(window_size.width, window_size.height, scale_factor, &pixels);
+    let framework = Framework::new(
+      window_size.width,
+      window_size.height,
+      scale_factor,
+      &pixels,
+      Path::new(&args.arg_rom).with_extension("state"),
+    );
     (pixels, framework)
   };
 
-  let args: Args = Docopt::new(USAGE)
-    .and_then(|d| d.deserialize())
-    .unwrap_or_else(|e| e.exit());
-
   let mut breakpoints_enabled = true;
 
-  // I could probably abstract some of this...
-  let (sample_tx, sample_rx) = mpsc::channel();
-  let audio_device = AudioDevice::init(sample_rx);
-  audio_device.stream.pause().unwrap();
+  // Carry on without sound if there's nowhere to play it:
+  let mut audio: Box<dyn AudioSink> = match AudioDevice::init(AUDIO_BUFFER_SECONDS) {
+    Ok(device) => Box::new(device),
+    Err(msg) => {
+      error!("{}; running without sound", msg);
+      Box::new(NullSink::new(44_100))
+    }
+  };
+  audio.pause();
 
-  let mut nes = match Nes::new(
-    audio_device.sample_rate as f32,
-    &args.arg_rom,
-    "nessers-main/src/test_fixtures/ntscpalette.pal",
-  ) {
+  let mut nes = match Nes::new(audio.sample_rate() as f32, &args.arg_rom, palette) {
     Ok(n) => n,
     Err(msg) => panic!("{}", msg),
   };
+  nes.apu.set_filter_profile(filter_profile);
+  nes.ppu.set_unlimited_sprites(args.flag_no_sprite_limit);
+  nes.set_ntsc_preset(ntsc);
 
   nes.breakpoints = args
     .arg_breakpoints
@@ -103,22 +235,24 @@ fn main() -> Result<(), Error> {
     .map(|s| u16::from_str_radix(s, 16).unwrap())
     .collect();
 
+  nes
+    .cart
+    .set_autosave_interval(args.flag_autosave.map(Duration::from_secs));
+
   nes.reset();
   nes.step();
 
-  let min_audio_buffer_size = audio_device.min_buffer_size;
-  let max_audio_buffer_size = audio_device.max_buffer_size;
-
-  let mut audio_buffer: Vec<f32> = vec![];
   let mut nes_debugger = NesDebugger::new(WIDTH, HEIGHT);
   let mut egui_has_focus = false;
-  let mut last_frame = Instant::now();
+  let mut next_frame = Instant::now();
   // Handle input and drive UI & screen rendering:
   event_loop.run(move |event, _, control_flow| {
     if input.update(&event) {
       if !egui_has_focus {
         // Close events
         if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+          flush_save_ram(&mut nes);
+          stop_recordings(&mut nes);
           *control_flow = ControlFlow::Exit;
           return;
         }
@@ -142,27 +276,27 @@ fn main() -> Result<(), Error> {
           if nes_debugger.playing {
             // Ensure we step past any breakpoints we may have been hanging on:
             nes.step();
-            audio_device.stream.play().unwrap();
+            audio.play();
           } else {
-            audio_device.stream.pause().unwrap();
+            audio.pause();
           }
         }
 
         if input.key_pressed_os(VirtualKeyCode::F) {
           nes_debugger.playing = false;
-          audio_device.stream.pause().unwrap();
+          audio.pause();
           nes.frame();
         }
 
         if input.key_pressed_os(VirtualKeyCode::Period) {
           nes_debugger.playing = false;
-          audio_device.stream.pause().unwrap();
+          audio.pause();
           nes.clock();
         }
 
         if input.key_pressed_os(VirtualKeyCode::Slash) {
           nes_debugger.playing = false;
-          audio_device.stream.pause().unwrap();
+          audio.pause();
           nes.step();
         }
 
@@ -209,49 +343,37 @@ fn main() -> Result<(), Error> {
       }
       // Draw the current frame
       Event::RedrawRequested(_) => {
-        // Only render if we're playing and enough time has passed to run at
-        // ~60hz; prevents from running too fast when on a display with > 60hz
-        if nes_debugger.playing && last_frame.elapsed() > Duration::from_millis(16) {
-          last_frame = Instant::now();
-          // Run our clock until a frame is ready, gathering samples as we go...
+        // Run as many frames as it takes to keep up with the console's real
+        // frame rate, whatever the display's refresh rate is. That usually
+        // means one, and sometimes none or two. If we've fallen way behind,
+        // e.g. from being paused, don't try to make it all up at once:
+        if next_frame + FRAME_DURATION * 4 < Instant::now() {
+          next_frame = Instant::now();
+        }
+        while nes_debugger.playing && next_frame <= Instant::now() {
+          next_frame += FRAME_DURATION;
+          // Run our clock until a frame is ready...
           loop {
-            // Prevent buffer overrun; this could result in a dropped frame:
-            if audio_buffer.len() > max_audio_buffer_size {
-              break;
-            }
-
             // Break on breakpoints:
             if nes.clock() && breakpoints_enabled {
               nes_debugger.playing = false;
-              audio_device.stream.pause().unwrap();
+              audio.pause();
               break;
             }
 
-            if nes.apu.sample_ready {
-              audio_buffer.push(nes.apu.sample());
-            }
-
-            if nes.ppu.frame_complete && audio_buffer.len() > (min_audio_buffer_size * 30) {
+            if nes.ppu.frame_complete {
               // Draw the world
-              nes_debugger.draw(pixels.get_frame(), &nes);
+              nes_debugger.draw(pixels.get_frame(), &mut nes);
               break;
             }
           }
-        }
 
-        let mut last_sample_idx = 0;
-        // Send samples until there's nothing to receive:
-        for i in 0..std::cmp::min(max_audio_buffer_size, audio_buffer.len()) {
-          last_sample_idx = i;
-          match sample_tx.send(audio_buffer[i]) {
-            Ok(_) => { /* keep sending */ }
-            Err(_) => {
-              println!("Nothing receiving... buffer overrun?");
-              break;
-            }
+          nes.flush_audio(audio.as_mut());
+
+          if let Err(msg) = nes.cart.autosave() {
+            error!("{}", msg);
           }
         }
-        audio_buffer.drain(0..last_sample_idx);
 
         // Prepare Dear ImGui
         framework.prepare(&window, &mut nes, &mut egui_has_focus);
@@ -271,6 +393,8 @@ fn main() -> Result<(), Error> {
           .map_err(|e| error!("pixels.render() failed: {}", e))
           .is_err()
         {
+          flush_save_ram(&mut nes);
+          stop_recordings(&mut nes);
           *control_flow = ControlFlow::Exit;
           return;
         }
@@ -280,6 +404,23 @@ fn main() -> Result<(), Error> {
   });
 }
 
+fn flush_save_ram(nes: &mut Nes) {
+  if let Err(msg) = nes.cart.flush_save_ram() {
+    error!("{}", msg);
+  }
+}
+
+/// WAV and VGM files need their headers patched up at the end, so don't leave
+/// a recording hanging on the way out.
+fn stop_recordings(nes: &mut Nes) {
+  if let Err(e) = nes.stop_recording() {
+    error!("Failed to write audio: {}", e);
+  }
+  if let Err(e) = nes.stop_vgm_log() {
+    error!("Failed to write VGM log: {}", e);
+  }
+}
+
 /// Tying it all together.
 struct NesDebugger {
   width: i16,
@@ -306,14 +447,21 @@ impl NesDebugger {
   /// Draw the `World` state to the frame buffer.
   ///
   /// Assumes the default texture format: `wgpu::TextureFormat::Rgba8UnormSrgb`
-  pub fn draw(&mut self, frame: &mut [u8], nes: &Nes) {
+  pub fn draw(&mut self, frame: &mut [u8], nes: &mut Nes) {
+    // The NTSC filter's picture is already twice as wide, so it only needs
+    // doubling vertically:
+    let (screen, screen_w) = if nes.ntsc_preset().is_some() {
+      (nes.ntsc_framebuffer().unwrap(), NTSC_W)
+    } else {
+      (nes.framebuffer(), SCREEN_W)
+    };
     // For now, just always redraw:
     for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
-      let x = (i % self.width as usize) / 2;
+      let x = (i % self.width as usize) * screen_w / (SCREEN_W * 2);
       let y = (i / self.width as usize) / 2;
-      if x < SCREEN_W && y > 8 && y < (SCREEN_H + 8) {
-        let ppu_screen_idx = (y - 8) * SCREEN_W + x;
-        pixel.copy_from_slice(&nes.ppu.screen[ppu_screen_idx]);
+      if x < screen_w && y > 8 && y < (SCREEN_H + 8) {
+        let ppu_screen_idx = (y - 8) * screen_w + x;
+        pixel.copy_from_slice(&screen[ppu_screen_idx]);
       } else {
         pixel.copy_from_slice(&[0x00, 0x00, 0x00, 0xFF]);
       }
@@ -1,6 +1,8 @@
 #![allow(unused_comparisons)]
 
+use crate::apu::{ExpansionAudio, ExpansionChip};
 use crate::cart::Mirroring;
+use crate::savestate::{load_fixed_len, SaveState, StateReader, StateWriter};
 
 pub mod m000;
 pub mod m001;
@@ -57,6 +59,31 @@ pub trait Mapper {
     // Default does nothing
   }
 
+  /// Clocks the cart's sound chip, if it has one, for one CPU cycle and
+  /// returns its output; `Apu` mixes it in at the chip's level.
+  ///
+  /// The chip's registers are the mapper's business, through `cpu_write` like
+  /// any others.
+  fn clock_audio(&mut self) -> Option<ExpansionAudio> {
+    // Default has no sound chip
+    None
+  }
+
+  /// Which of the sound chip's registers a CPU write to `addr` would set, if
+  /// any, so that it can be logged along with the APU's; see
+  /// `Nes::start_vgm_log`. This gets called before `cpu_write` does.
+  fn audio_register(&self, _addr: u16) -> Option<(ExpansionChip, u8)> {
+    // Default has no sound chip
+    None
+  }
+
+  /// What each of the sound chip's registers is set to, as
+  /// `(chip, register, value)`, for a log that starts partway through a game.
+  fn audio_registers(&self) -> Vec<(ExpansionChip, u8, u8)> {
+    // Default has no sound chip
+    vec![]
+  }
+
   /// This method will be called by the emulator to notify the mapper that a
   /// scanline has been completed, allowing it to do handle that however it
   /// chooses.
@@ -73,21 +100,45 @@ pub trait Mapper {
     // Default does nothing
   }
 
-  fn irq_active(&mut self) -> bool {
+  /// Whether the mapper is holding the CPU's IRQ line. It should keep holding
+  /// it until the game acknowledges the IRQ through one of its registers.
+  fn irq_active(&self) -> bool {
     // Default does nothing
     false
   }
 
-  fn irq_clear(&mut self) {
-    // Default does nothing
+  /// The mapper's PRG RAM, if it has any.
+  ///
+  /// Whether or not this RAM is actually battery-backed is up to the cart
+  /// header; `Cart` uses this to load and flush `.sav` files when it is.
+  fn save_ram(&self) -> Option<&[u8]> {
+    None
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    None
+  }
+
+  /// Writes the mapper's registers and RAM for a save state.
+  ///
+  /// Anything that came from the cart header (bank counts, RAM sizes) doesn't
+  /// need saving, since the state can only be loaded into the same cart.
+  fn save_state(&self, _w: &mut StateWriter) {
+    // Default has no state
+  }
+
+  fn load_state(&mut self, _r: &mut StateReader) -> Result<(), &'static str> {
+    // Default has no state
+    Ok(())
   }
 }
 
-/// Unimplemented mapper
-pub struct MXXX(u8);
+/// Unimplemented mapper; the cart's header can still be looked at, but running
+/// it panics.
+pub struct MXXX(u16);
 impl MXXX {
-  pub fn new(mapper: u8) -> Self {
-    panic!("Mapper {:03} not implemented", mapper)
+  pub fn new(mapper: u16) -> Self {
+    MXXX(mapper)
   }
 }
 
@@ -118,3 +169,20 @@ pub fn safe_ppu_read(addr: u16) -> MappedRead {
     RSkip
   }
 }
+
+/// For `Mapper::save_ram`: a mapper's PRG RAM, unless the cart has none.
+pub fn ram_slice(ram: &[u8]) -> Option<&[u8]> {
+  if ram.is_empty() {
+    None
+  } else {
+    Some(ram)
+  }
+}
+
+pub fn ram_slice_mut(ram: &mut [u8]) -> Option<&mut [u8]> {
+  if ram.is_empty() {
+    None
+  } else {
+    Some(ram)
+  }
+}
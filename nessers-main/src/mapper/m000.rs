@@ -4,20 +4,66 @@ use super::*;
 
 pub struct M000 {
   num_banks: usize,
+  // From nesdev:
+  //
+  // > PRG RAM: 2 or 4 KiB, not bankswitched, only in Family Basic (but most
+  // > emulators provide 8)
+  //
+  // Test ROMs in particular rely on it being there to report their results.
+  ram: Vec<u8>,
 }
 
 impl M000 {
-  pub fn new(num_banks: usize) -> Self {
-    M000 { num_banks }
+  pub fn new(num_banks: usize, prg_ram_size: usize) -> Self {
+    M000 {
+      num_banks,
+      ram: vec![0x00; prg_ram_size],
+    }
   }
 }
 
 impl Mapper for M000 {
+  fn cpu_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr {
+      0x6000..=0x7FFF if !self.ram.is_empty() => {
+        let len = self.ram.len();
+        self.ram[(addr - 0x6000) as usize % len] = data;
+        Wrote
+      }
+      _ => match self.safe_cpu_read(addr) {
+        RAddr(addr) => WAddr(addr),
+        _ => WSkip,
+      },
+    }
+  }
+
   fn safe_cpu_read(&self, addr: u16) -> MappedRead {
-    safe_cpu_read(self.num_banks, addr)
+    match addr {
+      0x6000..=0x7FFF if !self.ram.is_empty() => {
+        Data(self.ram[(addr - 0x6000) as usize % self.ram.len()])
+      }
+      _ => safe_cpu_read(self.num_banks, addr),
+    }
   }
 
   fn safe_ppu_read(&self, addr: u16) -> MappedRead {
     safe_ppu_read(addr)
   }
+
+  fn save_ram(&self) -> Option<&[u8]> {
+    ram_slice(&self.ram)
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    ram_slice_mut(&mut self.ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.ram.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    load_fixed_len(&mut self.ram, r)?;
+    Ok(())
+  }
 }
@@ -46,11 +46,20 @@ pub struct M001 {
   chr_bank_1: u8,
   prg_bank: u8,
 
-  ram: [u8; 8 * 1024],
+  ram: Vec<u8>,
+
+  // MMC1 ignores a serial write that comes on the CPU cycle right after
+  // another one. Read-modify-write instructions write twice in a row (first
+  // the old value, then the new one), so games can use e.g. `INC $FFFF` to
+  // reset the shift register without the second write shifting in a bit.
+  //
+  // We can't see CPU cycles directly, but CPU cycles are always 3 ticks apart.
+  tick: u64,
+  last_write_tick: Option<u64>,
 }
 
 impl M001 {
-  pub fn new(num_prg_banks: usize) -> Self {
+  pub fn new(num_prg_banks: usize, prg_ram_size: usize) -> Self {
     M001 {
       num_prg_banks,
       // The default load register has bit 7 set to 1, everything else 0. This
@@ -73,7 +82,9 @@ impl M001 {
       chr_bank_0: 0x00,
       chr_bank_1: 0x00,
       prg_bank: 0x00,
-      ram: [0x00; 8 * 1024],
+      ram: vec![0x00; prg_ram_size],
+      tick: 0,
+      last_write_tick: None,
     }
   }
 
@@ -104,13 +115,27 @@ impl Mapper for M001 {
     self.control = 0x1C;
   }
 
+  fn clock(&mut self, tick: u64) {
+    self.tick = tick;
+  }
+
   fn cpu_write(&mut self, addr: u16, data: u8) -> MappedWrite {
     match addr {
       0x6000..=0x7FFF => {
-        self.ram[(addr - 0x6000) as usize] = data;
+        if self.ram.is_empty() {
+          return WSkip;
+        }
+        let len = self.ram.len();
+        self.ram[(addr - 0x6000) as usize % len] = data;
         Wrote
       }
       0x8000..=0xFFFF => {
+        let consecutive = self.last_write_tick == Some(self.tick.wrapping_sub(3));
+        self.last_write_tick = Some(self.tick);
+        if consecutive {
+          return WSkip;
+        }
+
         // If bit 7 is set, we are resetting...
         if (data & 0b1000_0000) != 0 {
           // Reset load register and write Control with (Control OR $0C), locking
@@ -154,10 +179,10 @@ impl Mapper for M001 {
   fn safe_cpu_read(&self, addr: u16) -> MappedRead {
     match addr {
       // In this range, the mapper actually provides the data through its
-      // optional RAM bank.
-      //
-      // TODO: Should we make this configurable based on the cart's settings?
-      0x6000..=0x7FFF => Data(self.ram[(addr - 0x6000) as usize]),
+      // optional RAM bank, sized by the cart header.
+      0x6000..=0x7FFF if !self.ram.is_empty() => {
+        Data(self.ram[(addr - 0x6000) as usize % self.ram.len()])
+      }
 
       // ```
       // 4bit0
@@ -228,4 +253,33 @@ impl Mapper for M001 {
       _ => None,
     }
   }
+
+  fn save_ram(&self) -> Option<&[u8]> {
+    ram_slice(&self.ram)
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    ram_slice_mut(&mut self.ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.load.save_state(w);
+    self.control.save_state(w);
+    self.chr_bank_0.save_state(w);
+    self.chr_bank_1.save_state(w);
+    self.prg_bank.save_state(w);
+    self.ram.save_state(w);
+    self.last_write_tick.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.load.load_state(r)?;
+    self.control.load_state(r)?;
+    self.chr_bank_0.load_state(r)?;
+    self.chr_bank_1.load_state(r)?;
+    self.prg_bank.load_state(r)?;
+    load_fixed_len(&mut self.ram, r)?;
+    self.last_write_tick.load_state(r)?;
+    Ok(())
+  }
 }
@@ -53,4 +53,13 @@ impl Mapper for M002 {
   fn safe_ppu_read(&self, addr: u16) -> MappedRead {
     safe_ppu_read(addr)
   }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.selected_bank.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.selected_bank.load_state(r)?;
+    Ok(())
+  }
 }
@@ -45,4 +45,13 @@ impl Mapper for M003 {
       _ => RSkip,
     }
   }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.selected_bank.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.selected_bank.load_state(r)?;
+    Ok(())
+  }
 }
@@ -7,7 +7,7 @@ pub struct M004 {
 
   selected_register: Option<u8>,
   registers: [u8; 8],
-  ram: [u8; 8 * 1024],
+  ram: Vec<u8>,
   prg_bank_mode: PrgBankMode,
   chr_bank_mode: ChrBankMode,
 
@@ -31,7 +31,7 @@ enum ChrBankMode {
 use ChrBankMode::*;
 
 impl M004 {
-  pub fn new(num_prg_banks: usize) -> Self {
+  pub fn new(num_prg_banks: usize, prg_ram_size: usize) -> Self {
     M004 {
       // We have 8k-byte bank sizes but our cart implementation assumes 16k-byte
       // bank sizes, so we multiply the bank count provided by the cart by 2
@@ -39,7 +39,7 @@ impl M004 {
       num_prg_banks: num_prg_banks * 2,
       selected_register: None,
       registers: [0b0000_0000; 8],
-      ram: [0x00; 8 * 1024],
+      ram: vec![0x00; prg_ram_size],
 
       prg_bank_mode: PrgBankMode::_C000_Swap_8000_Fixed,
       chr_bank_mode: ChrBankMode::_2x2K_4x1K,
@@ -134,7 +134,11 @@ impl Mapper for M004 {
   fn cpu_write(&mut self, addr: u16, data: u8) -> MappedWrite {
     match (addr, (addr % 2) != 0) {
       (0x6000..=0x7FFF, _) => {
-        self.ram[(addr - 0x6000) as usize] = data;
+        if self.ram.is_empty() {
+          return WSkip;
+        }
+        let len = self.ram.len();
+        self.ram[(addr - 0x6000) as usize % len] = data;
         Wrote
       }
 
@@ -208,6 +212,7 @@ impl Mapper for M004 {
         // Writing any value to this register will disable MMC3 interrupts AND
         // acknowledge any pending interrupts.
         self.irq_enabled = false;
+        self.irq_active = false;
         Wrote
       }
       // IRQ enable ($E001-$FFFF, odd)
@@ -223,7 +228,7 @@ impl Mapper for M004 {
   fn safe_cpu_read(&self, addr: u16) -> MappedRead {
     let addr = addr as usize;
     match addr {
-      0x6000..=0x7FFF => Data(self.ram[(addr - 0x6000) as usize]),
+      0x6000..=0x7FFF if !self.ram.is_empty() => Data(self.ram[(addr - 0x6000) % self.ram.len()]),
       0x8000..=0x9FFF => RAddr((addr - 0x8000) + self.prg_bank(0)),
       0xA000..=0xBFFF => RAddr((addr - 0xA000) + self.prg_bank(1)),
       0xC000..=0xDFFF => RAddr((addr - 0xC000) + self.prg_bank(2)),
@@ -259,15 +264,54 @@ impl Mapper for M004 {
     }
   }
 
-  fn irq_active(&mut self) -> bool {
+  fn irq_active(&self) -> bool {
     self.irq_active
   }
 
-  fn irq_clear(&mut self) {
-    self.irq_active = false;
-  }
-
   fn mirroring(&self) -> Option<Mirroring> {
     self.mirroring
   }
+
+  fn save_ram(&self) -> Option<&[u8]> {
+    ram_slice(&self.ram)
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    ram_slice_mut(&mut self.ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.selected_register.save_state(w);
+    self.registers.save_state(w);
+    self.ram.save_state(w);
+    matches!(self.prg_bank_mode, _C000_Swap_8000_Fixed).save_state(w);
+    matches!(self.chr_bank_mode, _4x1K_2x2K).save_state(w);
+    self.mirroring.save_state(w);
+    self.irq_reload.save_state(w);
+    self.irq_counter.save_state(w);
+    self.irq_enabled.save_state(w);
+    self.irq_active.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.selected_register.load_state(r)?;
+    self.registers.load_state(r)?;
+    load_fixed_len(&mut self.ram, r)?;
+    let mut prg_c000_swap = false;
+    prg_c000_swap.load_state(r)?;
+    self.prg_bank_mode = if prg_c000_swap {
+      _C000_Swap_8000_Fixed
+    } else {
+      _8000_Swap_C000_Fixed
+    };
+    let mut chr_4x1k = false;
+    chr_4x1k.load_state(r)?;
+    self.chr_bank_mode = if chr_4x1k { _4x1K_2x2K } else { _2x2K_4x1K };
+    self.mirroring.load_state(r)?;
+    self.irq_reload.load_state(r)?;
+    self.irq_counter.load_state(r)?;
+    self.irq_enabled.load_state(r)?;
+    self.irq_active.load_state(r)?;
+    Ok(())
+  }
 }
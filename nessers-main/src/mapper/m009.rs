@@ -1,7 +1,5 @@
 #![allow(unused_comparisons)]
 
-use serde::__private::ser::FlatMapSerializeMap;
-
 use super::*;
 
 #[derive(Copy, Clone)]
@@ -15,18 +13,18 @@ pub struct M009 {
   prg_bank: u8,
   chr_bank: [u8; 4],
   chr_latch: [ChrLatch; 2],
-  ram: [u8; 8 * 1024],
+  ram: Vec<u8>,
   mirroring: Option<Mirroring>,
 }
 
 impl M009 {
-  pub fn new(num_banks: usize) -> Self {
+  pub fn new(num_banks: usize, prg_ram_size: usize) -> Self {
     M009 {
       num_banks,
       prg_bank: 0,
       chr_bank: [0x00; 4],
       chr_latch: [ChrLatch::FD; 2],
-      ram: [0x00; 8 * 1024],
+      ram: vec![0x00; prg_ram_size],
       mirroring: None,
     }
   }
@@ -35,6 +33,11 @@ impl M009 {
 impl Mapper for M009 {
   fn cpu_write(&mut self, addr: u16, data: u8) -> MappedWrite {
     match addr {
+      0x6000..=0x7FFF if !self.ram.is_empty() => {
+        let len = self.ram.len();
+        self.ram[(addr as usize) % len] = data;
+        Wrote
+      }
       0xA000..=0xAFFF => {
         // PRG ROM bank select ($A000-$AFFF)
         //
@@ -119,7 +122,7 @@ impl Mapper for M009 {
     let addr = addr as usize;
     match addr {
       // CPU $6000-$7FFF: 8 KB PRG RAM bank (PlayChoice version only; contains a 6264 and 74139)
-      0x6000..=0x7FFF => Data(self.ram[(addr as usize) % self.ram.len()]),
+      0x6000..=0x7FFF if !self.ram.is_empty() => Data(self.ram[addr % self.ram.len()]),
       // CPU $8000-$9FFF: 8 KB switchable PRG ROM bank
       0x8000..=0x9FFF => RAddr((addr - 0x8000) + (self.prg_bank as usize) * 8 * 1024),
       // CPU $A000-$FFFF: Three 8 KB PRG ROM banks, fixed to the last three banks
@@ -178,4 +181,35 @@ impl Mapper for M009 {
   fn mirroring(&self) -> Option<Mirroring> {
     self.mirroring
   }
+
+  fn save_ram(&self) -> Option<&[u8]> {
+    ram_slice(&self.ram)
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    ram_slice_mut(&mut self.ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.prg_bank.save_state(w);
+    self.chr_bank.save_state(w);
+    for latch in self.chr_latch.iter() {
+      matches!(latch, ChrLatch::FE).save_state(w);
+    }
+    self.ram.save_state(w);
+    self.mirroring.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.prg_bank.load_state(r)?;
+    self.chr_bank.load_state(r)?;
+    for latch in self.chr_latch.iter_mut() {
+      let mut fe = false;
+      fe.load_state(r)?;
+      *latch = if fe { ChrLatch::FE } else { ChrLatch::FD };
+    }
+    load_fixed_len(&mut self.ram, r)?;
+    self.mirroring.load_state(r)?;
+    Ok(())
+  }
 }
@@ -1,6 +1,7 @@
 #![allow(unused_comparisons)]
 
 use super::*;
+use crate::apu::{ExpansionAudio, ExpansionChip};
 
 pub struct M069 {
   num_prg_banks: usize,
@@ -11,16 +12,18 @@ pub struct M069 {
   chr_bank: [u8; 8],
   ram_bank: u8,
   ram_select: bool,
-  ram: [u8; 512 * 1024],
+  ram: Vec<u8>,
   mirroring: Option<Mirroring>,
 
   irq_control: u8,
   irq_counter: u16,
   irq_active: bool,
+
+  audio: Sunsoft5B,
 }
 
 impl M069 {
-  pub fn new(num_prg_banks: usize, num_chr_banks: usize) -> Self {
+  pub fn new(num_prg_banks: usize, num_chr_banks: usize, prg_ram_size: usize) -> Self {
     M069 {
       num_prg_banks,
       num_chr_banks,
@@ -30,11 +33,12 @@ impl M069 {
       chr_bank: [0x00; 8],
       ram_bank: 0x00,
       ram_select: false,
-      ram: [0x00; 512 * 1024],
+      ram: vec![0x00; prg_ram_size],
       mirroring: None,
       irq_control: 0x00,
       irq_counter: 0x0000,
       irq_active: false,
+      audio: Sunsoft5B::new(),
     }
   }
 
@@ -69,7 +73,11 @@ impl Mapper for M069 {
     match addr {
       0x6000..=0x7FFF => {
         if self.ram_select {
-          self.ram[((addr as usize) - 0x6000) + (self.ram_bank as usize) * 8 * 1024] = data;
+          if self.ram.is_empty() {
+            return WSkip;
+          }
+          let len = self.ram.len();
+          self.ram[(((addr as usize) - 0x6000) + (self.ram_bank as usize) * 8 * 1024) % len] = data;
           Wrote
         } else {
           WAddr(((addr as usize) - 0x6000) + (self.prg_bank[0] as usize) * 8 * 1024)
@@ -237,6 +245,7 @@ impl Mapper for M069 {
             // the same time as an IRQ would have been generated.
             // println!("irq_control: {:08b}", data);
             self.irq_control = data;
+            self.irq_active = false;
             Wrote
           }
           0xE => {
@@ -273,6 +282,27 @@ impl Mapper for M069 {
           _ => WSkip,
         }
       }
+      0xC000..=0xDFFF => {
+        // Audio Register Select ($C000-$DFFF)
+        //
+        // ```
+        // 7  bit  0
+        // ---- ----
+        // WWWW RRRR
+        // |||| ||||
+        // |||| ++++- The 4-bit internal register to select for use with $E000
+        // ++++------ Disable writes to $E000 if any of these bits are set (0 = enable)
+        // ```
+        //
+        // Only the 5B has audio, but nothing else writes here.
+        self.audio.select = data;
+        Wrote
+      }
+      0xE000..=0xFFFF => {
+        // Audio Register Write ($E000-$FFFF)
+        self.audio.write(data);
+        Wrote
+      }
       _ => WSkip,
     }
   }
@@ -283,7 +313,10 @@ impl Mapper for M069 {
       // CPU $6000-$7FFF: 8 KB Bankable PRG ROM or PRG RAM
       0x6000..=0x7FFF => {
         if self.ram_select {
-          Data(self.ram[(addr - 0x6000) + (self.ram_bank as usize) * 8 * 1024])
+          if self.ram.is_empty() {
+            return RSkip;
+          }
+          Data(self.ram[((addr - 0x6000) + (self.ram_bank as usize) * 8 * 1024) % self.ram.len()])
         } else {
           RAddr((addr - 0x6000) + (self.prg_bank[0] as usize) * 8 * 1024)
         }
@@ -354,11 +387,347 @@ impl Mapper for M069 {
     }
   }
 
-  fn irq_active(&mut self) -> bool {
+  fn irq_active(&self) -> bool {
     self.irq_active
   }
 
-  fn irq_clear(&mut self) {
-    self.irq_active = false;
+  fn clock_audio(&mut self) -> Option<ExpansionAudio> {
+    Some(ExpansionAudio {
+      chip: ExpansionChip::Sunsoft5B,
+      level: self.audio.clock(),
+    })
+  }
+
+  fn audio_register(&self, addr: u16) -> Option<(ExpansionChip, u8)> {
+    match addr {
+      0xE000..=0xFFFF if self.audio.select & 0xF0 == 0 => {
+        Some((ExpansionChip::Sunsoft5B, self.audio.select & 0x0F))
+      }
+      _ => None,
+    }
+  }
+
+  fn audio_registers(&self) -> Vec<(ExpansionChip, u8, u8)> {
+    (0..16)
+      .map(|register| {
+        let value = self.audio.registers[register as usize];
+        (ExpansionChip::Sunsoft5B, register, value)
+      })
+      .collect()
+  }
+
+  fn save_ram(&self) -> Option<&[u8]> {
+    ram_slice(&self.ram)
+  }
+
+  fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+    ram_slice_mut(&mut self.ram)
+  }
+
+  fn save_state(&self, w: &mut StateWriter) {
+    self.command.save_state(w);
+    self.param.save_state(w);
+    self.prg_bank.save_state(w);
+    self.chr_bank.save_state(w);
+    self.ram_bank.save_state(w);
+    self.ram_select.save_state(w);
+    self.ram.save_state(w);
+    self.mirroring.save_state(w);
+    self.irq_control.save_state(w);
+    self.irq_counter.save_state(w);
+    self.irq_active.save_state(w);
+    self.audio.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.command.load_state(r)?;
+    self.param.load_state(r)?;
+    self.prg_bank.load_state(r)?;
+    self.chr_bank.load_state(r)?;
+    self.ram_bank.load_state(r)?;
+    self.ram_select.load_state(r)?;
+    load_fixed_len(&mut self.ram, r)?;
+    self.mirroring.load_state(r)?;
+    self.irq_control.load_state(r)?;
+    self.irq_counter.load_state(r)?;
+    self.irq_active.load_state(r)?;
+    self.audio.load_state(r)?;
+    Ok(())
+  }
+}
+
+/// The 5B's sound chip: a YM2149F, i.e. a General Instrument AY-3-8910 in all
+/// but name. Three square waves, each of which can have noise mixed in and its
+/// volume driven by a shared envelope.
+///
+/// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+struct Sunsoft5B {
+  select: u8,
+  registers: [u8; 16],
+
+  /// Everything below runs at 1/16th of the CPU's rate
+  prescaler: u8,
+  tone_counters: [u16; 3],
+  tone_outputs: [bool; 3],
+  noise_counter: u8,
+  /// A 17-bit LFSR
+  noise: u32,
+  envelope_counter: u16,
+  /// Which of the envelope's 16 steps it's on
+  envelope_step: u8,
+  envelope_attack: bool,
+  envelope_holding: bool,
+}
+
+impl Sunsoft5B {
+  fn new() -> Self {
+    Sunsoft5B {
+      select: 0x00,
+      registers: [0x00; 16],
+      prescaler: 0,
+      tone_counters: [0; 3],
+      tone_outputs: [false; 3],
+      noise_counter: 0,
+      noise: 1,
+      envelope_counter: 0,
+      envelope_step: 0,
+      envelope_attack: false,
+      envelope_holding: false,
+    }
+  }
+
+  fn write(&mut self, data: u8) {
+    if self.select & 0xF0 != 0 {
+      return;
+    }
+    let register = (self.select & 0x0F) as usize;
+    self.registers[register] = data;
+
+    // Envelope Shape ($D)
+    //
+    // ```
+    // 7  bit  0
+    // ---- ----
+    // .... CAaH
+    //      ||||
+    //      |||+- Hold
+    //      ||+-- Alternate
+    //      |+--- Attack
+    //      +---- Continue
+    // ```
+    //
+    // Writing it starts the envelope over.
+    if register == 0x0D {
+      self.envelope_counter = 0;
+      self.envelope_step = 0;
+      self.envelope_attack = (data & 0b0100) != 0;
+      self.envelope_holding = false;
+    }
+  }
+
+  /// Runs for one CPU cycle, and returns the output from 0.0 to 1.0.
+  fn clock(&mut self) -> f32 {
+    self.prescaler = (self.prescaler + 1) % 16;
+    if self.prescaler == 0 {
+      // Each square flips every 16 * P CPU cycles:
+      for i in 0..3 {
+        let period =
+          (self.registers[i * 2] as u16 | ((self.registers[i * 2 + 1] as u16 & 0x0F) << 8)).max(1);
+        self.tone_counters[i] += 1;
+        if self.tone_counters[i] >= period {
+          self.tone_counters[i] = 0;
+          self.tone_outputs[i] = !self.tone_outputs[i];
+        }
+      }
+
+      // The noise and envelope step every 32 * P CPU cycles:
+      let noise_period = (self.registers[0x06] & 0x1F).max(1);
+      self.noise_counter += 1;
+      if self.noise_counter >= noise_period * 2 {
+        self.noise_counter = 0;
+        let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+        self.noise = (self.noise >> 1) | (feedback << 16);
+      }
+
+      let envelope_period =
+        (self.registers[0x0B] as u16 | ((self.registers[0x0C] as u16) << 8)).max(1);
+      self.envelope_counter += 1;
+      if self.envelope_counter >= envelope_period.saturating_mul(2) {
+        self.envelope_counter = 0;
+        self.step_envelope();
+      }
+    }
+
+    // Mixer ($7): disabling a square or its noise leaves that input high
+    let mixer = self.registers[0x07];
+    let noise = (self.noise & 1) != 0;
+    let mut level = 0.0;
+    for i in 0..3 {
+      let tone_on = self.tone_outputs[i] || (mixer & (1 << i)) != 0;
+      let noise_on = noise || (mixer & (1 << (i + 3))) != 0;
+      if tone_on && noise_on {
+        let volume = self.registers[0x08 + i];
+        let volume = if (volume & 0x10) != 0 {
+          self.envelope_volume()
+        } else {
+          volume & 0x0F
+        };
+        level += volume_level(volume);
+      }
+    }
+    level / 3.0
+  }
+
+  fn step_envelope(&mut self) {
+    if self.envelope_holding {
+      return;
+    }
+    self.envelope_step += 1;
+    if self.envelope_step < 16 {
+      return;
+    }
+
+    let shape = self.registers[0x0D];
+    let continues = (shape & 0b1000) != 0;
+    let alternate = (shape & 0b0010) != 0;
+    let hold = (shape & 0b0001) != 0;
+    if !continues {
+      // Drops to silence and stays there
+      self.envelope_attack = false;
+      self.envelope_step = 15;
+      self.envelope_holding = true;
+    } else if hold {
+      // Stays at the end of the ramp, or jumps to the other end of it
+      self.envelope_attack ^= alternate;
+      self.envelope_step = 15;
+      self.envelope_holding = true;
+    } else {
+      self.envelope_attack ^= alternate;
+      self.envelope_step = 0;
+    }
+  }
+
+  fn envelope_volume(&self) -> u8 {
+    if self.envelope_attack {
+      self.envelope_step
+    } else {
+      15 - self.envelope_step
+    }
+  }
+}
+
+/// The DAC is logarithmic, at 3dB per step.
+fn volume_level(volume: u8) -> f32 {
+  if volume == 0 {
+    0.0
+  } else {
+    10f32.powf((volume as f32 - 15.0) * 3.0 / 20.0)
+  }
+}
+
+impl SaveState for Sunsoft5B {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.select.save_state(w);
+    self.registers.save_state(w);
+    self.prescaler.save_state(w);
+    self.tone_counters.save_state(w);
+    self.tone_outputs.save_state(w);
+    self.noise_counter.save_state(w);
+    self.noise.save_state(w);
+    self.envelope_counter.save_state(w);
+    self.envelope_step.save_state(w);
+    self.envelope_attack.save_state(w);
+    self.envelope_holding.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.select.load_state(r)?;
+    self.registers.load_state(r)?;
+    self.prescaler.load_state(r)?;
+    self.tone_counters.load_state(r)?;
+    self.tone_outputs.load_state(r)?;
+    self.noise_counter.load_state(r)?;
+    self.noise.load_state(r)?;
+    self.envelope_counter.load_state(r)?;
+    self.envelope_step.load_state(r)?;
+    self.envelope_attack.load_state(r)?;
+    self.envelope_holding.load_state(r)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sunsoft_5b_square() {
+    let mut mapper = M069::new(2, 1, 0);
+    let mut write = |register: u8, data: u8| {
+      mapper.cpu_write(0xC000, register);
+      mapper.cpu_write(0xE000, data);
+    };
+    // Square A at period 100, full volume, no noise:
+    write(0x00, 100);
+    write(0x01, 0x00);
+    write(0x07, 0b0011_1110);
+    write(0x08, 0x0F);
+
+    // It flips every 1600 CPU cycles:
+    let levels: Vec<f32> = (0..6400)
+      .map(|_| mapper.clock_audio().unwrap().level)
+      .collect();
+    let high = levels.iter().filter(|level| **level > 0.0).count();
+    assert_eq!(high, 3200);
+    // Squares B and C are disabled, which holds them high at volume 0:
+    assert!(levels
+      .iter()
+      .all(|level| *level == 0.0 || *level == 1.0 / 3.0));
+    let edges = levels.windows(2).filter(|w| w[0] != w[1]).count();
+    assert_eq!(edges, 4);
+  }
+
+  #[test]
+  fn sunsoft_5b_audio_registers() {
+    let mut mapper = M069::new(2, 1, 0);
+    mapper.cpu_write(0xC000, 0x08);
+    assert_eq!(
+      mapper.audio_register(0xE000),
+      Some((ExpansionChip::Sunsoft5B, 0x08))
+    );
+    mapper.cpu_write(0xE000, 0x0F);
+    assert_eq!(
+      mapper.audio_registers()[0x08],
+      (ExpansionChip::Sunsoft5B, 0x08, 0x0F)
+    );
+    assert_eq!(mapper.audio_register(0x8000), None);
+
+    // Writes to $E000 are disabled:
+    mapper.cpu_write(0xC000, 0x18);
+    assert_eq!(mapper.audio_register(0xE000), None);
+  }
+
+  #[test]
+  fn sunsoft_5b_envelope() {
+    let mut chip = Sunsoft5B::new();
+    chip.select = 0x0D;
+    // Attack, then hold at the top:
+    chip.write(0b1101);
+    assert_eq!(chip.envelope_volume(), 0);
+    for _ in 0..15 {
+      chip.step_envelope();
+    }
+    assert_eq!(chip.envelope_volume(), 15);
+    chip.step_envelope();
+    chip.step_envelope();
+    assert_eq!(chip.envelope_volume(), 15);
+
+    // A one-shot decay drops to silence:
+    chip.write(0b0000);
+    assert_eq!(chip.envelope_volume(), 15);
+    for _ in 0..20 {
+      chip.step_envelope();
+    }
+    assert_eq!(chip.envelope_volume(), 0);
   }
 }
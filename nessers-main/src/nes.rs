@@ -1,4 +1,5 @@
 use crate::apu::Apu;
+use crate::audio_sink::AudioSink;
 use crate::bus::Bus;
 use crate::bus_device::BusDevice;
 use crate::cart::Cart;
@@ -6,12 +7,19 @@ use crate::cpu6502::Cpu;
 use crate::cpu6502::StatusFlag::*;
 use crate::disassemble::DisassembledOperation;
 use crate::mirror::Mirror;
+use crate::ntsc::{NtscFilter, NtscPreset};
 use crate::palette::Palette;
-use crate::peripherals::Peripherals;
+use crate::peripherals::{Controller, Peripherals};
 use crate::ppu::Ppu;
 use crate::ram::Ram;
+use crate::recorder::Recorder;
+use crate::savestate::{read_header, write_header, SaveState, StateReader, StateWriter};
 use crate::trace::{trace, Trace};
+use crate::vgm::VgmLog;
+use crate::wav::SampleFormat;
 use std::collections::HashSet;
+use std::io;
+use std::path::Path;
 
 pub struct Nes {
   pub breakpoints: HashSet<u16>,
@@ -26,6 +34,9 @@ pub struct Nes {
   pub cart: Cart,
   pub addresses_hit: HashSet<u16>,
   pub peripherals: Peripherals,
+  samples: Vec<f32>,
+  recorder: Option<Recorder>,
+  ntsc: Option<NtscFilter>,
 
   dma_page: u8,
   dma_addr: u8,
@@ -33,10 +44,37 @@ pub struct Nes {
 
   dma_active: bool,
   dma_dummy: bool,
+
+  /// Whether the DMC is fetching a sample byte; see `Dmc::dma_request`
+  dmc_dma_active: bool,
+  /// Whether that fetch still has its dummy cycle to go
+  dmc_dma_dummy: bool,
+
+  /// Whether a DMA has halted the CPU
+  dma_halted: bool,
+  /// The read the CPU was halted on; see `clock_cpu`
+  dma_halt_addr: u16,
+  /// The last address the CPU read during the current cycle, if it read
+  cpu_read_addr: Option<u16>,
 }
 
 impl Nes {
-  pub fn new(system_sample_rate: f32, cart_filename: &str, palette_filename: &str) -> Result<Nes, &'static str> {
+  pub fn new(
+    system_sample_rate: f32,
+    cart_filename: &str,
+    palette: Palette,
+  ) -> Result<Nes, &'static str> {
+    let cart = Cart::from_file(cart_filename)?;
+    Ok(Nes::with_cart(system_sample_rate, cart, palette))
+  }
+
+  /// Like `new`, but for a ROM image that's already in memory.
+  pub fn from_rom(system_sample_rate: f32, rom: &[u8], palette: Palette) -> Result<Nes, &'static str> {
+    let cart = Cart::new(&rom.to_vec())?;
+    Ok(Nes::with_cart(system_sample_rate, cart, palette))
+  }
+
+  pub fn with_cart(system_sample_rate: f32, cart: Cart, palette: Palette) -> Nes {
     let cpu = Cpu::new();
 
     // 2K internal RAM, mirrored to 8K
@@ -44,14 +82,12 @@ impl Nes {
     let ram_mirror = Mirror::new(0x0000, 8 * 1024);
 
     // PPU Registers, mirrored for 8K
-    let ppu = Ppu::new(Palette::from_file(palette_filename)?);
+    let ppu = Ppu::new(palette);
     let ppu_registers_mirror = Mirror::new(0x2000, 8 * 1024);
 
     let apu = Apu::new(system_sample_rate);
 
-    let cart = Cart::from_file(cart_filename)?;
-
-    Ok(Nes {
+    Nes {
       tick: 0,
       cpu,
       ppu,
@@ -63,6 +99,9 @@ impl Nes {
       addresses_hit: HashSet::new(),
       peripherals: Peripherals::new(),
       breakpoints: HashSet::new(),
+      samples: vec![],
+      recorder: None,
+      ntsc: None,
 
       dma_page: 0x00,
       dma_addr: 0x00,
@@ -70,62 +109,249 @@ impl Nes {
 
       dma_active: false,
       dma_dummy: true,
-    })
+
+      dmc_dma_active: false,
+      dmc_dma_dummy: false,
+
+      dma_halted: false,
+      dma_halt_addr: 0x0000,
+      cpu_read_addr: None,
+    }
+  }
+
+  /// The most recently rendered frame, as RGBA pixels; row-major,
+  /// `SCREEN_W` by `SCREEN_H`.
+  pub fn framebuffer(&self) -> &[[u8; 4]] {
+    &self.ppu.screen
+  }
+
+  /// Turns the NTSC filter on with `preset`, or off with `None`.
+  pub fn set_ntsc_preset(&mut self, preset: Option<NtscPreset>) {
+    match (preset, &mut self.ntsc) {
+      (Some(preset), Some(filter)) => filter.set_preset(preset),
+      (Some(preset), None) => self.ntsc = Some(NtscFilter::new(preset)),
+      (None, _) => self.ntsc = None,
+    }
+  }
+
+  pub fn ntsc_preset(&self) -> Option<NtscPreset> {
+    self.ntsc.as_ref().map(|filter| filter.preset())
+  }
+
+  /// The most recently rendered frame, through the NTSC filter if it's on;
+  /// `NTSC_W` by `SCREEN_H`, so it wants stretching to twice as tall to look
+  /// right.
+  pub fn ntsc_framebuffer(&mut self) -> Option<&[[u8; 4]]> {
+    let filter = self.ntsc.as_mut()?;
+    Some(filter.apply(
+      &self.ppu.color_indices,
+      &self.ppu.palette,
+      self.ppu.frame_count,
+    ))
+  }
+
+  /// Takes every audio sample produced since the last call.
+  ///
+  /// Samples pile up for as long as nobody drains them, so frontends that care
+  /// about audio should call this at least once per frame.
+  pub fn drain_samples(&mut self) -> std::vec::Drain<'_, f32> {
+    self.samples.drain(..)
+  }
+
+  /// Hands every sample that's piled up to `sink`, and nudges the APU's output
+  /// rate to keep the sink's buffer from running dry or overflowing.
+  pub fn flush_audio(&mut self, sink: &mut dyn AudioSink) {
+    sink.write(&self.samples);
+    self.samples.clear();
+    self.apu.adjust_rate(sink.buffer_fill());
+  }
+
+  /// Starts writing every audio sample to a WAV file, from now until
+  /// `stop_recording`; this is the same audio `drain_samples` gets.
+  ///
+  /// With `stems`, each APU channel also gets a file of its own next to it,
+  /// e.g. `music-pulse1.wav`, `music-triangle.wav`. Any recording that's
+  /// already going is stopped first.
+  pub fn start_recording(
+    &mut self,
+    path: &Path,
+    format: SampleFormat,
+    stems: bool,
+  ) -> io::Result<()> {
+    self.stop_recording()?;
+    let recorder = Recorder::create(path, self.apu.sample_rate() as u32, format, stems)?;
+    self.apu.set_stems_enabled(stems);
+    self.recorder = Some(recorder);
+    Ok(())
+  }
+
+  /// Finishes off the recording, if there is one. Errors from writing samples
+  /// along the way come out here.
+  pub fn stop_recording(&mut self) -> io::Result<()> {
+    self.apu.set_stems_enabled(false);
+    match self.recorder.take() {
+      Some(recorder) => recorder.finish(),
+      None => Ok(()),
+    }
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recorder.is_some()
+  }
+
+  /// Starts logging APU register writes to a VGM file, from now until
+  /// `stop_vgm_log`, for playing the music back without the game.
+  ///
+  /// Any DMC samples go in the log too: everything in $C000-$FFFF, where
+  /// samples can start, as of now, and anything the DMC reads later that's
+  /// changed since (e.g. from bank switching).
+  pub fn start_vgm_log(&mut self, path: &Path) -> io::Result<()> {
+    self.stop_vgm_log()?;
+    let mut log = VgmLog::create(path)?;
+    let samples: Vec<u8> = (0xC000..=0xFFFF)
+      .map(|addr| self.safe_cpu_read(addr))
+      .collect();
+    log.write_memory(0xC000, &samples);
+    self.apu.start_vgm_log(log);
+    for (chip, register, data) in self.cart.mapper.audio_registers() {
+      self.apu.log_expansion_write(chip, register, data);
+    }
+    Ok(())
+  }
+
+  /// Finishes off the VGM log, if there is one. Errors from writing along the
+  /// way come out here.
+  pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+    match self.apu.take_vgm_log() {
+      Some(log) => log.finish(),
+      None => Ok(()),
+    }
+  }
+
+  pub fn is_vgm_logging(&self) -> bool {
+    self.apu.is_vgm_logging()
+  }
+
+  pub fn set_controller(&mut self, player: usize, controller: Controller) {
+    self.peripherals.controllers[player] = controller;
   }
 
   pub fn clock(&mut self) -> bool {
     // TODO: Add break conditions for PPU, APU, and Mapper:
     self.ppu.clock(&mut self.cart);
-    self.apu.clock(&mut self.cart);
+    if self.tick % 3 == 0 {
+      let expansion = self.cart.mapper.clock_audio();
+      self.apu.set_expansion_audio(expansion);
+    }
+    self.apu.clock();
     self.cart.mapper.clock(self.tick);
 
+    if self.apu.sample_ready {
+      let sample = self.apu.sample();
+      self.samples.push(sample);
+      if let Some(recorder) = &mut self.recorder {
+        recorder.record(sample, self.apu.stem_sample());
+      }
+    }
+
     if self.tick % 3 == 0 {
-      if self.dma_active {
-        if self.dma_dummy {
-          if self.tick % 2 == 1 {
-            self.dma_dummy = false;
-          }
-        } else {
-          if self.tick % 2 == 0 {
-            self.dma_data =
-              self.cpu_read((self.dma_page as u16) << 8 | ((self.dma_addr as u16) & 0x00FF));
-          } else {
-            self.ppu.set_oam_data(self.dma_addr, self.dma_data);
-            self.dma_addr = self.dma_addr.wrapping_add(1);
-            if self.dma_addr == 0x00 {
-              self.dma_active = false;
-              self.dma_dummy = true;
-            }
+      self.clock_cpu();
+    }
+
+    // The CPU samples these every cycle and decides for itself when to act on
+    // them. IRQ is shared; whoever raised it holds it until it's acknowledged:
+    self.cpu.set_nmi_line(self.ppu.nmi_line());
+    self
+      .cpu
+      .set_irq_line(self.cart.mapper.irq_active() || self.apu.irq_active());
+
+    self.tick = self.tick.wrapping_add(1);
+
+    self.breakpoints.contains(&self.cpu.pc)
+  }
+
+  /// Runs one CPU cycle, or one cycle of whichever DMAs have the CPU halted.
+  ///
+  /// OAM DMA ($4014) and DMC sample fetches both work by halting the CPU,
+  /// which only works on a cycle the CPU spends reading; writes carry on until
+  /// the CPU gets to a read. The halted read still happens, and the CPU does it
+  /// over again once it's let go.
+  ///
+  /// While halted, the DMA unit reads on even ("get") cycles and writes on odd
+  /// ("put") cycles. OAM DMA takes a get and a put per byte, after lining up
+  /// with a get. A DMC fetch takes a dummy cycle after its halt, then the next
+  /// get; if that lands in the middle of an OAM DMA, the OAM DMA loses its get
+  /// and has to line up with the next one.
+  ///
+  /// See https://www.nesdev.org/wiki/DMA
+  fn clock_cpu(&mut self) {
+    let dmc_request = self.apu.dmc.dma_request();
+
+    if !self.dma_halted {
+      self.addresses_hit.insert(self.cpu.pc);
+      self.cpu_read_addr = None;
+      // Is there a shorthand way to run a method on a field by cloning it and
+      // replacing its value with the cloned object?
+      let cpu = &mut self.cpu.clone();
+      cpu.clock(self);
+
+      match self.cpu_read_addr {
+        Some(addr) if self.dma_active || dmc_request.is_some() => {
+          // Throwing away the CPU's progress makes it repeat this cycle once
+          // it's let go:
+          self.dma_halted = true;
+          self.dma_halt_addr = addr;
+          if dmc_request.is_some() {
+            self.dmc_dma_active = true;
+            self.dmc_dma_dummy = true;
           }
         }
-        // self.dma_active = false;
-      } else {
-        self.addresses_hit.insert(self.cpu.pc);
-        // Is there a shorthand way to run a method on a field by cloning it and
-        // replacing its value with the cloned object?
-        let cpu = &mut self.cpu.clone();
-        cpu.clock(self);
-        self.cpu = *cpu;
+        _ => self.cpu = *cpu,
       }
+      return;
     }
 
-    if self.ppu.nmi {
-      self.ppu.nmi = false;
-      let cpu = &mut self.cpu.clone();
-      cpu.sig_nmi(self);
-      self.cpu = *cpu;
+    // The CPU is already halted for OAM DMA, so a DMC fetch that comes along
+    // spends this cycle on its halt:
+    let dmc_halting = dmc_request.is_some() && !self.dmc_dma_active;
+    if dmc_halting {
+      self.dmc_dma_active = true;
+      self.dmc_dma_dummy = true;
     }
 
-    if self.cart.mapper.irq_active() {
-      self.cart.mapper.irq_clear();
-      let cpu = &mut self.cpu.clone();
-      cpu.sig_irq(self);
-      self.cpu = *cpu;
+    let get = self.tick % 2 == 0;
+    if get && self.dmc_dma_active && !self.dmc_dma_dummy {
+      if let Some(addr) = dmc_request {
+        let data = self.cpu_read(addr);
+        self.apu.dmc_dma_load(addr, data);
+      }
+      self.dmc_dma_active = false;
+    } else if get && self.dma_active {
+      self.dma_data = self.cpu_read((self.dma_page as u16) << 8 | (self.dma_addr as u16));
+      self.dma_dummy = false;
+    } else if !get && self.dma_active && !self.dma_dummy {
+      self.ppu.set_oam_data(self.dma_addr, self.dma_data);
+      self.dma_addr = self.dma_addr.wrapping_add(1);
+      self.dma_dummy = true;
+      if self.dma_addr == 0x00 {
+        self.dma_active = false;
+      }
+    } else if !matches!(self.dma_halt_addr, 0x4016 | 0x4017) {
+      // Halt, dummy and alignment cycles repeat the read the CPU was halted
+      // on. The controller ports only clock their shift registers at the start
+      // of a run of reads, so repeats don't count there; but the CPU's read
+      // once it's let go does. That's how DMC fetches eat controller bits on
+      // real hardware.
+      self.cpu_read(self.dma_halt_addr);
     }
 
-    self.tick = self.tick.wrapping_add(1);
+    if self.dmc_dma_active && !dmc_halting {
+      self.dmc_dma_dummy = false;
+    }
 
-    self.breakpoints.contains(&self.cpu.pc)
+    if !self.dma_active && !self.dmc_dma_active {
+      self.dma_halted = false;
+    }
   }
 
   pub fn step(&mut self) {
@@ -140,7 +366,7 @@ impl Nes {
       callback(self);
 
       self.clock();
-      if self.tick % 3 == 1 && self.cpu.cycles_left == 0 {
+      if self.tick % 3 == 1 && self.cpu.cycle == 0 && !self.dma_halted {
         return;
       }
     }
@@ -152,7 +378,10 @@ impl Nes {
 
       // Only breaks on CPU instruction step boundaries; similar to running
       // `step()`:
-      if self.tick % 3 == 1 && self.cpu.cycles_left == 0 && self.breakpoints.contains(&self.cpu.pc)
+      if self.tick % 3 == 1
+        && self.cpu.cycle == 0
+        && !self.dma_halted
+        && self.breakpoints.contains(&self.cpu.pc)
       {
         return true;
       }
@@ -174,9 +403,7 @@ impl Nes {
   }
 
   pub fn reset(&mut self) {
-    let cpu = &mut self.cpu.clone();
-    cpu.sig_reset(self);
-    self.cpu = *cpu;
+    self.cpu.sig_reset();
 
     self.apu.reset();
     self.cart.reset();
@@ -194,6 +421,82 @@ impl Nes {
     print_trace(trace)
   }
 
+  /// Snapshots the whole machine; see `savestate.rs` for the format.
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    write_header(&mut w, self.cart.hash());
+    self.save_state_body(&mut w);
+    w.finish()
+  }
+
+  /// Restores a snapshot made by `save_state`.
+  ///
+  /// If the state turns out to be bad partway through, the machine is put back
+  /// the way it was before returning the error.
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), &'static str> {
+    let mut r = StateReader::new(data);
+    read_header(&mut r, self.cart.hash())?;
+
+    let mut backup = StateWriter::new();
+    self.save_state_body(&mut backup);
+    let backup = backup.finish();
+
+    let result = self.load_state_body(&mut r).and_then(|_| {
+      if r.is_empty() {
+        Ok(())
+      } else {
+        Err("Save state has unexpected trailing data")
+      }
+    });
+
+    if result.is_err() {
+      self
+        .load_state_body(&mut StateReader::new(&backup))
+        .expect("Failed to restore machine state after a bad save state");
+    }
+
+    result
+  }
+
+  fn save_state_body(&self, w: &mut StateWriter) {
+    self.tick.save_state(w);
+    self.cpu.save_state(w);
+    self.ppu.save_state(w);
+    self.apu.save_state(w);
+    self.ram.save_state(w);
+    self.cart.save_state(w);
+    self.peripherals.save_state(w);
+    self.dma_page.save_state(w);
+    self.dma_addr.save_state(w);
+    self.dma_data.save_state(w);
+    self.dma_active.save_state(w);
+    self.dma_dummy.save_state(w);
+    self.dmc_dma_active.save_state(w);
+    self.dmc_dma_dummy.save_state(w);
+    self.dma_halted.save_state(w);
+    self.dma_halt_addr.save_state(w);
+  }
+
+  fn load_state_body(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.tick.load_state(r)?;
+    self.cpu.load_state(r)?;
+    self.ppu.load_state(r)?;
+    self.apu.load_state(r)?;
+    self.ram.load_state(r)?;
+    self.cart.load_state(r)?;
+    self.peripherals.load_state(r)?;
+    self.dma_page.load_state(r)?;
+    self.dma_addr.load_state(r)?;
+    self.dma_data.load_state(r)?;
+    self.dma_active.load_state(r)?;
+    self.dma_dummy.load_state(r)?;
+    self.dmc_dma_active.load_state(r)?;
+    self.dmc_dma_dummy.load_state(r)?;
+    self.dma_halted.load_state(r)?;
+    self.dma_halt_addr.load_state(r)?;
+    Ok(())
+  }
+
   // BEGIN ------ Hacky? Helper functions to avoid ugly manual dyn cast -------
 
   pub fn cpu_read(&mut self, addr: u16) -> u8 {
@@ -231,8 +534,10 @@ impl Bus<Cpu> for Nes {
   }
 
   fn read(&mut self, addr: u16) -> u8 {
+    self.cpu_read_addr = Some(addr);
     match None // Hehe, using None here just for formatting purposes:
       .or(self.cart.cpu_read(addr))
+      .or(self.apu.cpu_read(addr))
       .or(self.peripherals.read(addr, &mut self.cart))
       .or(self.ram_mirror.read(&mut self.ram, addr, &mut self.cart))
       .or(
@@ -246,6 +551,10 @@ impl Bus<Cpu> for Nes {
   }
 
   fn write(&mut self, addr: u16, data: u8) {
+    if let Some((chip, register)) = self.cart.mapper.audio_register(addr) {
+      self.apu.log_expansion_write(chip, register, data);
+    }
+
     None // Hehe, using None here just for formatting purposes:
       .or_else(|| self.cart.cpu_write(addr, data))
       .or_else(|| self.apu.cpu_write(addr, data))
@@ -257,6 +566,7 @@ impl Bus<Cpu> for Nes {
           self.dma_page = data;
           self.dma_addr = 0x00;
           self.dma_active = true;
+          self.dma_dummy = true;
           return Some(());
         }
 
@@ -384,6 +694,7 @@ mod tests {
   use crate::cpu6502::Instruction::*;
 
   use super::*;
+  use crate::audio_sink::BufferSink;
 
   // The output is wrapped in a Result to allow matching on errors
   // Returns an Iterator to the Reader of the lines of the file.
@@ -424,41 +735,13 @@ mod tests {
     cart_data.resize(16 + 0 + 16 * 1024, 0x42);
     // Fill CHR with 0x43
     cart_data.resize(16 + 0 + 16 * 1024 + 8 * 1024, 0x43);
-    let cpu = Cpu::new();
 
-    // 2K internal RAM, mirrored to 8K
-    let ram = Ram::new(0x0000, 2 * 1024);
-    let ram_mirror = Mirror::new(0x0000, 8 * 1024);
-
-    // PPU Registers, mirrored for 8K
-    let ppu = Ppu::new(Palette {
-      colors: [Color { r: 0, g: 0, b: 0 }; 64],
+    let palette = Palette {
+      colors: [Color { r: 0, g: 0, b: 0 }; 512],
       map: [0x00; 32],
-    });
-    let ppu_registers_mirror = Mirror::new(0x2000, 8 * 1024);
-
-    let apu = Apu::new();
-
-    let cart = Cart::new(&cart_data).unwrap();
+    };
 
-    Nes {
-      tick: 0,
-      cpu,
-      ppu,
-      apu,
-      cart,
-      ram_mirror,
-      ram,
-      ppu_registers_mirror,
-      addresses_hit: HashSet::new(),
-      peripherals: Peripherals::new(),
-      breakpoints: HashSet::new(),
-      dma_page: 0x00,
-      dma_addr: 0x00,
-      dma_data: 0x00,
-      dma_active: false,
-      dma_dummy: true,
-    }
+    Nes::with_cart(44_100.0, Cart::new(&cart_data).unwrap(), palette)
   }
 
   fn debug_line_test(prog_data: &Vec<u8>, cpu: Cpu, expected_output: &'static str) {
@@ -483,7 +766,7 @@ mod tests {
         y: 0x00,
         status: 0x6F,
         s: 0xFB,
-        cycles_left: 0,
+        ..Cpu::new()
       },
       "C7ED  F0 04     BEQ $C7F3                       A:6F X:00 Y:00 P:6F SP:FB",
     );
@@ -496,7 +779,7 @@ mod tests {
         y: 0x5F,
         status: 0x65,
         s: 0xFB,
-        cycles_left: 0,
+        ..Cpu::new()
       },
       "D082  A9 70     LDA #$70                        A:F5 X:00 Y:5F P:65 SP:FB",
     );
@@ -510,7 +793,7 @@ mod tests {
     //     y: 0x5F,
     //     status: 0x65,
     //     s: 0xFB,
-    //     cycles_left: 0,
+    //     ..Cpu::new()
     //   },
     //   "D084  8D 00 03  STA $0300 = EF                  A:70 X:00 Y:5F P:65 SP:FB",
     // )
@@ -581,8 +864,9 @@ mod tests {
   #[test]
   fn nestest() {
     let mut nes = match Nes::new(
+      44_100.0,
       "src/test_fixtures/nestest.nes",
-      "src/test_fixtures/ntscpalette.pal",
+      Palette::embedded(),
     ) {
       Ok(n) => n,
       Err(msg) => panic!("{}", msg),
@@ -608,6 +892,243 @@ mod tests {
       });
   }
 
+  /// Loads `prog` into RAM at $0300 and points the CPU at it.
+  fn make_dma_nes(prog: &[u8]) -> Nes {
+    let mut nes = make_test_nes();
+    for (i, byte) in prog.iter().enumerate() {
+      nes.cpu_write(0x0300 + i as u16, *byte);
+    }
+    nes.cpu.pc = 0x0300;
+    nes
+  }
+
+  /// Steps one instruction, returning how many CPU cycles it took.
+  fn step_cycles(nes: &mut Nes) -> u32 {
+    let mut cycles = 0;
+    nes.step_with_callback(|nes| {
+      if nes.tick % 3 == 0 {
+        cycles += 1;
+      }
+    });
+    cycles
+  }
+
+  /// Queues a one byte DMC sample, which the DMC asks for straight away.
+  fn start_dmc_fetch(nes: &mut Nes) {
+    nes.apu.cpu_write(0x4012, 0x00);
+    nes.apu.cpu_write(0x4013, 0x00);
+    nes.apu.cpu_write(0x4015, 0x00);
+    nes.apu.cpu_write(0x4015, 0x10);
+  }
+
+  #[test]
+  fn oam_dma() {
+    // LDA #$07; STA $4014; NOP
+    let mut nes = make_dma_nes(&[0xA9, 0x07, 0x8D, 0x14, 0x40, 0xEA]);
+    for i in 0..=0xFF {
+      nes.cpu_write(0x0700 + i, i as u8);
+    }
+
+    step_cycles(&mut nes);
+    step_cycles(&mut nes);
+    // The NES starts on a get cycle, and the LDA and STA take 6 cycles, so the
+    // halt lands on get cycle 6. The DMA then waits out put cycle 7 before
+    // its first get: the halt, plus 1 to line up, plus 512 for the DMA.
+    assert_eq!(step_cycles(&mut nes), 514);
+    assert_eq!(step_cycles(&mut nes), 2);
+    assert_eq!(nes.cpu.pc, 0x0306);
+
+    let last = nes.ppu.oam[63];
+    assert_eq!(
+      (last.y, last.tile_id, last.attribute, last.x),
+      (0xFC, 0xFD, 0xFE, 0xFF)
+    );
+  }
+
+  #[test]
+  fn ntsc_framebuffer() {
+    let mut nes = make_test_nes();
+    assert_eq!(nes.ntsc_preset(), None);
+    assert!(nes.ntsc_framebuffer().is_none());
+
+    nes.set_ntsc_preset(Some(NtscPreset::Composite));
+    nes.set_ntsc_preset(Some(NtscPreset::SVideo));
+    assert_eq!(nes.ntsc_preset(), Some(NtscPreset::SVideo));
+    assert_eq!(
+      nes.ntsc_framebuffer().unwrap().len(),
+      crate::NTSC_W * crate::SCREEN_H
+    );
+
+    nes.set_ntsc_preset(None);
+    assert!(nes.ntsc_framebuffer().is_none());
+  }
+
+  #[test]
+  fn dmc_dma_stalls_reads() {
+    // NOP; NOP
+    let mut nes = make_dma_nes(&[0xEA, 0xEA]);
+    start_dmc_fetch(&mut nes);
+
+    // The halt lands on get cycle 0 and the dummy on put cycle 1, so the get
+    // follows straight on with no alignment cycle:
+    assert_eq!(step_cycles(&mut nes), 3);
+    assert_eq!(nes.apu.dmc.dma_request(), None);
+
+    assert_eq!(step_cycles(&mut nes), 2);
+  }
+
+  #[test]
+  fn dmc_dma_waits_for_writes() {
+    // STA $00
+    let mut nes = make_dma_nes(&[0x85, 0x00, 0xEA]);
+    step_cycles(&mut nes);
+    nes.cpu.pc = 0x0300;
+
+    // Two cycles of opcode and operand fetch:
+    for _ in 0..6 {
+      nes.clock();
+    }
+    start_dmc_fetch(&mut nes);
+    // The write goes ahead, so the DMA only gets started on the next
+    // instruction's opcode fetch:
+    assert_eq!(step_cycles(&mut nes), 1);
+    assert!(nes.apu.dmc.dma_request().is_some());
+    // The first STA took cycles 0-2 and this one cycles 3-5, so the halt
+    // lands on get cycle 6, the dummy on put cycle 7, and the get on cycle 8:
+    assert_eq!(step_cycles(&mut nes), 3);
+  }
+
+  #[test]
+  fn dmc_dma_during_oam_dma() {
+    // LDA #$07; STA $4014; NOP
+    let mut nes = make_dma_nes(&[0xA9, 0x07, 0x8D, 0x14, 0x40, 0xEA]);
+    step_cycles(&mut nes);
+    step_cycles(&mut nes);
+
+    // Let the OAM DMA get going first:
+    for _ in 0..100 * 3 {
+      nes.clock();
+    }
+    start_dmc_fetch(&mut nes);
+    // The DMC only costs 2 cycles on top of the 514 in `oam_dma`. Its halt is
+    // on get cycle 106 and its dummy on put cycle 107, which the OAM DMA still
+    // gets to use. Then the DMC takes get cycle 108, and the OAM DMA has to
+    // wait out put cycle 109 to line up again:
+    assert_eq!(step_cycles(&mut nes) + 100, 516);
+    assert_eq!(nes.apu.dmc.dma_request(), None);
+  }
+
+  #[test]
+  fn dmc_dma_deletes_controller_bits() {
+    let run = |dmc: bool| {
+      // STA $4016; LDA $4016
+      let mut nes = make_dma_nes(&[0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40]);
+      nes.set_controller(
+        0,
+        Controller {
+          a: true,
+          ..Controller::new()
+        },
+      );
+      step_cycles(&mut nes);
+
+      // Halt the LDA on its read of $4016:
+      for _ in 0..3 * 3 {
+        nes.clock();
+      }
+      if dmc {
+        start_dmc_fetch(&mut nes);
+      }
+      step_cycles(&mut nes);
+      nes.cpu.a
+    };
+
+    assert_eq!(run(false), 1);
+    // The halted read clocks the shift register, then the CPU reads again and
+    // gets B instead of A:
+    assert_eq!(run(true), 0);
+  }
+
+  #[test]
+  fn save_state_roundtrip() {
+    let mut nes = Nes::new(
+      44_100.0,
+      "src/test_fixtures/nestest.nes",
+      Palette::embedded(),
+    )
+    .unwrap();
+    nes.reset();
+    for _ in 0..10 {
+      nes.frame();
+    }
+
+    let state = nes.save_state();
+    for _ in 0..5 {
+      nes.frame();
+    }
+    let expected_state = nes.save_state();
+    let expected_screen = nes.ppu.screen.to_vec();
+
+    nes.load_state(&state).unwrap();
+    assert_eq!(nes.save_state(), state);
+    for _ in 0..5 {
+      nes.frame();
+    }
+    assert_eq!(nes.save_state(), expected_state);
+    assert!(nes.ppu.screen.to_vec() == expected_screen);
+  }
+
+  #[test]
+  fn flush_audio() {
+    let mut nes = Nes::new(
+      44_100.0,
+      "src/test_fixtures/nestest.nes",
+      Palette::embedded(),
+    )
+    .unwrap();
+    nes.reset();
+    let mut sink = BufferSink::new(44_100);
+    for _ in 0..60 {
+      nes.frame();
+      nes.flush_audio(&mut sink);
+    }
+
+    // A second's worth, give or take the console running a hair over 60Hz:
+    assert!(
+      (44_000..=44_100).contains(&sink.samples.len()),
+      "{}",
+      sink.samples.len()
+    );
+    assert_eq!(nes.drain_samples().count(), 0);
+  }
+
+  #[test]
+  fn save_state_bad_data() {
+    let mut nes = Nes::new(
+      44_100.0,
+      "src/test_fixtures/nestest.nes",
+      Palette::embedded(),
+    )
+    .unwrap();
+    nes.reset();
+    nes.frame();
+    let state = nes.save_state();
+
+    assert_eq!(
+      nes.load_state(&state[0..state.len() - 1]),
+      Err("Save state is truncated")
+    );
+    assert_eq!(nes.save_state(), state);
+
+    let mut extra = state.clone();
+    extra.push(0x00);
+    assert_eq!(
+      nes.load_state(&extra),
+      Err("Save state has unexpected trailing data")
+    );
+    assert_eq!(nes.save_state(), state);
+  }
+
   // Meh. Wild goose chase.
   //
   // #[test]
@@ -781,6 +1302,18 @@ mod tests {
       "RLA" => RLA,
       "SRE" => SRE,
       "RRA" => RRA,
+      "ANC" => ANC,
+      "ALR" => ALR,
+      "ARR" => ARR,
+      "AXS" => AXS,
+      "LAS" => LAS,
+      "LXA" => LXA,
+      "XAA" => XAA,
+      "SHX" => SHX,
+      "SHY" => SHY,
+      "TAS" => TAS,
+      "AHX" => AHX,
+      "KIL" => KIL,
       _ => NOP,
     };
 
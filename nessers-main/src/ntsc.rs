@@ -0,0 +1,284 @@
+use crate::palette::{self, Palette, NTSC_HUE_OFFSET};
+use crate::ppu::{SCREEN_H, SCREEN_W};
+
+/// How wide the filtered picture is: two pixels for each of the PPU's, or a
+/// third of a cycle of the color subcarrier each, which is plenty to show the
+/// artifacts off. It's as tall as ever.
+pub const NTSC_W: usize = SCREEN_W * 2;
+
+// https://www.nesdev.org/wiki/NTSC_video
+//
+// The PPU puts out 8 samples of its signal per pixel, at 12 per cycle of the
+// color subcarrier:
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_LINE: usize = SCREEN_W * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_OUTPUT: usize = SAMPLES_PER_LINE / NTSC_W;
+// Each scanline is 341 dots, so it starts 4 samples further round the color
+// cycle than the last. A frame here is always 89341 dots, with the PPU
+// skipping the first dot of scanline 0, so each starts 8 samples further
+// round: that's the dot crawl.
+const PHASE_PER_LINE: usize = 341 * SAMPLES_PER_PIXEL % 12;
+const PHASE_PER_FRAME: usize = 89_341 * SAMPLES_PER_PIXEL % 12;
+
+/// What sort of cable the filter imitates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NtscPreset {
+  /// Brightness and color share one wire, and the TV can't quite pull them
+  /// apart again: dot crawl, fringing on edges, and colors that aren't in the
+  /// palette at all
+  Composite,
+  /// Brightness and color get a wire each: sharp, and no dot crawl, but color
+  /// still bleeds a little
+  SVideo,
+  /// Straight from the palette, only stretched to the filter's width
+  Rgb,
+}
+
+impl NtscPreset {
+  pub const ALL: [NtscPreset; 3] = [NtscPreset::Composite, NtscPreset::SVideo, NtscPreset::Rgb];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      NtscPreset::Composite => "composite",
+      NtscPreset::SVideo => "s-video",
+      NtscPreset::Rgb => "rgb",
+    }
+  }
+
+  /// How many samples the TV averages brightness and color over; the wider,
+  /// the blurrier. Brightness needs a whole color cycle (12) to get rid of the
+  /// color subcarrier, so with any less of one, some of it is left behind as
+  /// dot crawl.
+  fn filter_widths(&self) -> (usize, usize) {
+    match self {
+      NtscPreset::Composite => (10, 24),
+      NtscPreset::SVideo => (4, 12),
+      NtscPreset::Rgb => (1, 1),
+    }
+  }
+}
+
+impl std::str::FromStr for NtscPreset {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    NtscPreset::ALL
+      .iter()
+      .find(|preset| preset.name() == name.to_lowercase())
+      .copied()
+      .ok_or_else(|| {
+        format!(
+          "Unknown NTSC preset \"{}\"; try composite, s-video or rgb",
+          name
+        )
+      })
+  }
+}
+
+/// Turns the PPU's colors back into the video signal it would have put out,
+/// then decodes that the way a TV would. See `Nes::ntsc_framebuffer`.
+///
+/// It works from `Ppu::color_indices` rather than RGB, since the artifacts
+/// all come from the signal: a pixel's color is a square wave, which bleeds
+/// into its neighbours and gets mistaken for brightness, and vice versa.
+#[derive(Clone)]
+pub struct NtscFilter {
+  preset: NtscPreset,
+  /// `palette::signal` for each of the 512 colors at each phase, so it's
+  /// looked up rather than worked out for every sample
+  signals: Vec<[f32; 12]>,
+  /// Each color's brightness, i.e. its signal averaged over a whole cycle;
+  /// what an S-Video cable carries on its own wire
+  lumas: Vec<f32>,
+  /// What the TV multiplies the signal by to get I and Q out, at each phase
+  carrier: [(f32, f32); 12],
+  pixels: Vec<[u8; 4]>,
+}
+
+impl NtscFilter {
+  pub fn new(preset: NtscPreset) -> Self {
+    let signals: Vec<[f32; 12]> = (0..512u16)
+      .map(|index| {
+        let mut signal = [0.0; 12];
+        for (phase, sample) in signal.iter_mut().enumerate() {
+          *sample = palette::signal(index as u8 & 0x3F, (index >> 6) as u8, phase as u8);
+        }
+        signal
+      })
+      .collect();
+    let lumas = signals
+      .iter()
+      .map(|signal| signal.iter().sum::<f32>() / 12.0)
+      .collect();
+
+    let mut carrier = [(0.0, 0.0); 12];
+    for (phase, c) in carrier.iter_mut().enumerate() {
+      let angle = (phase as f32 * 30.0 + NTSC_HUE_OFFSET).to_radians();
+      *c = (angle.cos(), angle.sin());
+    }
+
+    NtscFilter {
+      preset,
+      signals,
+      lumas,
+      carrier,
+      pixels: vec![[0x00, 0x00, 0x00, 0xFF]; NTSC_W * SCREEN_H],
+    }
+  }
+
+  pub fn preset(&self) -> NtscPreset {
+    self.preset
+  }
+
+  pub fn set_preset(&mut self, preset: NtscPreset) {
+    self.preset = preset;
+  }
+
+  /// Filters a frame of `Ppu::color_indices` into RGBA pixels, `NTSC_W` by
+  /// `SCREEN_H`. `frame` is the PPU's `frame_count`, which the dot crawl
+  /// follows.
+  pub fn apply(&mut self, color_indices: &[u16], palette: &Palette, frame: u64) -> &[[u8; 4]] {
+    let frame_phase = (frame % 12) as usize * PHASE_PER_FRAME % 12;
+
+    for y in 0..SCREEN_H {
+      let line = &color_indices[y * SCREEN_W..(y + 1) * SCREEN_W];
+      if self.preset == NtscPreset::Rgb {
+        for x in 0..NTSC_W {
+          let color = palette.colors[line[x * SCREEN_W / NTSC_W] as usize];
+          self.pixels[y * NTSC_W + x] = [color.r, color.g, color.b, 0xFF];
+        }
+      } else {
+        self.filter_line(y, line, (frame_phase + y * PHASE_PER_LINE) % 12);
+      }
+    }
+    &self.pixels
+  }
+
+  fn filter_line(&mut self, y: usize, line: &[u16], start_phase: usize) {
+    let separate = self.preset == NtscPreset::SVideo;
+    let (luma_width, chroma_width) = self.preset.filter_widths();
+
+    // Running totals of brightness and of I and Q, so that averaging any
+    // stretch of samples is just a subtraction:
+    let mut sums = vec![(0.0f32, 0.0f32, 0.0f32); SAMPLES_PER_LINE + 1];
+    for s in 0..SAMPLES_PER_LINE {
+      let index = line[s / SAMPLES_PER_PIXEL] as usize;
+      let phase = (start_phase + s) % 12;
+      let signal = self.signals[index][phase];
+      let (luma, chroma) = if separate {
+        let luma = self.lumas[index];
+        (luma, signal - luma)
+      } else {
+        (signal, signal)
+      };
+      let (cos, sin) = self.carrier[phase];
+      let (y, i, q) = sums[s];
+      sums[s + 1] = (y + luma, i + chroma * cos, q + chroma * sin);
+    }
+    let average = |center: usize, width: usize| {
+      let start = center.saturating_sub(width / 2);
+      let end = (center + width - width / 2).min(SAMPLES_PER_LINE);
+      let (a, b) = (sums[start], sums[end]);
+      let n = (end - start) as f32;
+      ((b.0 - a.0) / n, (b.1 - a.1) / n, (b.2 - a.2) / n)
+    };
+
+    for x in 0..NTSC_W {
+      let center = x * SAMPLES_PER_OUTPUT + SAMPLES_PER_OUTPUT / 2;
+      let (luma, _, _) = average(center, luma_width);
+      let (_, i, q) = average(center, chroma_width);
+      let color = palette::yiq_to_color(luma, i, q, 2.2);
+      self.pixels[y * NTSC_W + x] = [color.r, color.g, color.b, 0xFF];
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::PaletteSettings;
+
+  /// A whole frame of one color
+  fn solid(index: u16) -> Vec<u16> {
+    vec![index; SCREEN_W * SCREEN_H]
+  }
+
+  fn middle(pixels: &[[u8; 4]]) -> [u8; 4] {
+    pixels[SCREEN_H / 2 * NTSC_W + NTSC_W / 2]
+  }
+
+  #[test]
+  fn decodes_palette_colors() {
+    let palette = Palette::generate(&PaletteSettings::default());
+    let mut filter = NtscFilter::new(NtscPreset::SVideo);
+    for index in [0x0F, 0x16, 0x2A, 0x30, 0x12 | 0b101 << 6] {
+      let color = palette.colors[index as usize];
+      let pixel = middle(filter.apply(&solid(index), &palette, 0));
+      for (a, b) in [
+        (pixel[0], color.r),
+        (pixel[1], color.g),
+        (pixel[2], color.b),
+      ] {
+        assert!((a as i32 - b as i32).abs() <= 2, "${:03X}", index);
+      }
+    }
+  }
+
+  #[test]
+  fn rgb() {
+    let palette = Palette::embedded();
+    let mut frame = solid(0x0F);
+    frame[1] = 0x30;
+    let mut filter = NtscFilter::new(NtscPreset::Rgb);
+    let pixels = filter.apply(&frame, &palette, 0);
+    assert_eq!(
+      &pixels[0..4],
+      &[
+        [0, 0, 0, 0xFF],
+        [0, 0, 0, 0xFF],
+        [0xFE, 0xFF, 0xFF, 0xFF],
+        [0xFE, 0xFF, 0xFF, 0xFF]
+      ]
+    );
+  }
+
+  #[test]
+  fn dot_crawl() {
+    let palette = Palette::embedded();
+    let mut filter = NtscFilter::new(NtscPreset::Composite);
+    // Away from the ends of the scanline, where the TV has less to go on:
+    let row =
+      |pixels: &[[u8; 4]], y: usize| pixels[y * NTSC_W + 16..(y + 1) * NTSC_W - 16].to_vec();
+
+    // Grays have no color subcarrier to leave behind:
+    let gray = row(filter.apply(&solid(0x10), &palette, 0), 0);
+    assert_eq!(row(filter.apply(&solid(0x10), &palette, 1), 0), gray);
+
+    // Colors do, and it moves from one frame to the next...
+    let red = filter.apply(&solid(0x16), &palette, 0).to_vec();
+    assert_ne!(
+      row(filter.apply(&solid(0x16), &palette, 1), 0),
+      row(&red, 0)
+    );
+    // ...coming back round every 3 frames...
+    assert_eq!(
+      row(filter.apply(&solid(0x16), &palette, 3), 0),
+      row(&red, 0)
+    );
+    // ...and from one scanline to the next:
+    assert_ne!(row(&red, 0), row(&red, 1));
+
+    // Filtering the same frame again doesn't move it:
+    assert_eq!(
+      row(filter.apply(&solid(0x16), &palette, 0), 0),
+      row(&red, 0)
+    );
+
+    // S-Video keeps them apart:
+    filter.set_preset(NtscPreset::SVideo);
+    let red = row(filter.apply(&solid(0x16), &palette, 0), 0);
+    assert_eq!(row(filter.apply(&solid(0x16), &palette, 1), 0), red);
+
+    assert_eq!("S-Video".parse(), Ok(NtscPreset::SVideo));
+  }
+}
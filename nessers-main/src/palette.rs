@@ -3,6 +3,7 @@ use std::fs;
 use crate::{
   bus_device::{BusDevice, BusDeviceRange},
   cart::Cart,
+  savestate::{SaveState, StateReader, StateWriter},
 };
 
 /// 24-bit sRGB color
@@ -23,11 +24,21 @@ impl Color {
   }
 }
 
+/// The palette nessers ships with, so it works without any files around.
+const EMBEDDED: &[u8; 192] = include_bytes!("test_fixtures/ntscpalette.pal");
+
+/// How much each of the other two color channels is dimmed by one emphasis
+/// bit. Roughly what a 2C02 does; the real effect varies from console to
+/// console.
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
 /// NES color palette
 #[derive(Clone)]
 pub struct Palette {
-  // The SRGB colors that the NES is capable of displaying.
-  pub colors: [Color; 64],
+  // The SRGB colors that the NES is capable of displaying: 64 colors for each
+  // of the 8 combinations of PPUMASK's emphasis bits, indexed by
+  // `emphasis * 64 + color`.
+  pub colors: [Color; 512],
   // The actual "live" palette of colors; each `u8` in the array is an index
   // into the `colors` array.
   pub map: [u8; 32],
@@ -36,26 +47,58 @@ pub struct Palette {
 impl Palette {
   pub fn new() -> Self {
     Palette {
-      colors: [Color::new(); 64],
+      colors: [Color::new(); 512],
       map: [0x00; 32],
     }
   }
   pub fn from_file(filename: &str) -> Result<Palette, &'static str> {
-    let contents = fs::read(filename).expect(&format!("Failure reading {}", filename));
-    if contents.len() != 192 {
-      return Err("File had size other than 192 (3 * 64) bytes");
+    let contents = fs::read(filename).map_err(|_| "Failed to read palette file")?;
+    Palette::from_bytes(&contents)
+  }
+
+  /// The built-in palette.
+  pub fn embedded() -> Palette {
+    // Always the right size:
+    Palette::from_bytes(EMBEDDED).unwrap()
+  }
+
+  /// Works out all 512 colors from what the PPU puts on the video signal,
+  /// the way a TV would decode it.
+  ///
+  /// https://www.nesdev.org/wiki/NTSC_video
+  pub fn generate(settings: &PaletteSettings) -> Palette {
+    let mut palette = Palette::new();
+    for (i, color) in palette.colors.iter_mut().enumerate() {
+      let mut emphasis = i >> 6;
+      if settings.decoder == Decoder::Pal2C07 {
+        // The 2C07 swaps the red and green emphasis bits:
+        emphasis = (emphasis & 0b100) | (emphasis & 0b001) << 1 | (emphasis & 0b010) >> 1;
+      }
+      let (y, i, q) = decode(i as u8 & 0x3F, emphasis as u8, settings);
+      let y = y * settings.contrast + settings.brightness;
+      let i = i * settings.saturation;
+      let q = q * settings.saturation;
+
+      *color = yiq_to_color(y, i, q, settings.gamma);
     }
+    palette
+  }
 
-    let mut palette = Palette {
-      colors: [Color { r: 0, g: 0, b: 0 }; 64],
-      map: [0x00; 32],
-    };
-    let mut index = 0;
-    while index < 192 {
-      palette.colors[index / 3].r = contents[index + 0];
-      palette.colors[index / 3].g = contents[index + 1];
-      palette.colors[index / 3].b = contents[index + 2];
-      index += 3;
+  /// Parses a `.pal` file of 3-byte RGB colors: either the 64 base colors, in
+  /// which case we work out the emphasized ones ourselves, or all 512.
+  pub fn from_bytes(contents: &[u8]) -> Result<Palette, &'static str> {
+    if contents.len() != 192 && contents.len() != 1536 {
+      return Err("File had size other than 192 (3 * 64) or 1536 (3 * 512) bytes");
+    }
+
+    let mut palette = Palette::new();
+    for (color, rgb) in palette.colors.iter_mut().zip(contents.chunks(3)) {
+      color.r = rgb[0];
+      color.g = rgb[1];
+      color.b = rgb[2];
+    }
+    if contents.len() == 192 {
+      palette.generate_emphasis();
     }
 
     // for i in 0..32 {
@@ -64,6 +107,176 @@ impl Palette {
 
     Ok(palette)
   }
+
+  /// Fills in colors 64-511 from the first 64. Emphasizing a color really
+  /// darkens the other two, so e.g. red emphasis dims green and blue.
+  fn generate_emphasis(&mut self) {
+    for emphasis in 1..8 {
+      // Bit 0 is red, bit 1 green and bit 2 blue, as in PPUMASK:
+      let dim = |channel: usize| {
+        let others: usize = emphasis & !(1 << channel);
+        EMPHASIS_ATTENUATION.powi(others.count_ones() as i32)
+      };
+      let (r, g, b) = (dim(0), dim(1), dim(2));
+      for i in 0..64 {
+        let color = self.colors[i];
+        self.colors[emphasis * 64 + i] = Color {
+          r: (color.r as f32 * r).round() as u8,
+          g: (color.g as f32 * g).round() as u8,
+          b: (color.b as f32 * b).round() as u8,
+        };
+      }
+    }
+  }
+}
+
+/// Which PPU's signal `Palette::generate` decodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decoder {
+  /// NTSC consoles
+  Ntsc2C02,
+  /// PAL consoles; the same colors, a little rotated
+  Pal2C07,
+}
+
+impl Decoder {
+  pub const ALL: [Decoder; 2] = [Decoder::Ntsc2C02, Decoder::Pal2C07];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Decoder::Ntsc2C02 => "2c02",
+      Decoder::Pal2C07 => "2c07",
+    }
+  }
+}
+
+impl std::str::FromStr for Decoder {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    Decoder::ALL
+      .iter()
+      .find(|decoder| decoder.name() == name.to_lowercase())
+      .copied()
+      .ok_or_else(|| format!("Unknown PPU \"{}\"; try 2c02 or 2c07", name))
+  }
+}
+
+/// The knobs on the TV, for `Palette::generate`. The defaults are a TV with
+/// everything left in the middle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteSettings {
+  /// Degrees to rotate every color by
+  pub hue: f32,
+  /// 1.0 is as decoded; 0.0 is grayscale
+  pub saturation: f32,
+  /// Scales brightness, 1.0 being as decoded
+  pub contrast: f32,
+  /// Added to brightness, from -1.0 to 1.0
+  pub brightness: f32,
+  /// The display gamma to correct for; 2.2 leaves the signal alone
+  pub gamma: f32,
+  pub decoder: Decoder,
+}
+
+impl Default for PaletteSettings {
+  fn default() -> Self {
+    PaletteSettings {
+      hue: 0.0,
+      saturation: 1.0,
+      contrast: 1.0,
+      brightness: 0.0,
+      gamma: 2.2,
+      decoder: Decoder::Ntsc2C02,
+    }
+  }
+}
+
+// Signal levels, relative to sync; black is $0D's low level, and white is
+// $20's high one:
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+/// How much the emphasis bits cut the signal by, while they're in effect
+const SIGNAL_ATTENUATION: f32 = 0.746;
+
+/// Where hue 0 sits relative to the colorburst, in degrees; lines the decoded
+/// hues up with the TV's.
+pub(crate) const NTSC_HUE_OFFSET: f32 = 120.0;
+
+/// The PPU's output for a color at one of the 12 phases of the color
+/// subcarrier, from 0.0 for black to 1.0 for white.
+///
+/// Hues 1-12 are a square wave, each 30 degrees round from the last. Hue 0
+/// is only the high level, and 13-15 only the low, so those are grays. Each
+/// emphasis bit attenuates the signal for a third of the cycle, centered on
+/// red, green or blue.
+pub(crate) fn signal(color: u8, emphasis: u8, phase: u8) -> f32 {
+  let hue = color & 0x0F;
+  let level = if hue > 13 { 1 } else { (color >> 4) as usize };
+  let high = if hue > 12 {
+    SIGNAL_LOW[level]
+  } else {
+    SIGNAL_HIGH[level]
+  };
+  let low = if hue == 0 {
+    SIGNAL_HIGH[level]
+  } else {
+    SIGNAL_LOW[level]
+  };
+  let in_phase = |hue: u8| (hue + phase) % 12 < 6;
+
+  let mut signal = if in_phase(hue) { high } else { low };
+  if (emphasis & 0b001 != 0 && in_phase(0))
+    || (emphasis & 0b010 != 0 && in_phase(4))
+    || (emphasis & 0b100 != 0 && in_phase(8))
+  {
+    signal *= SIGNAL_ATTENUATION;
+  }
+  (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+/// A whole cycle of `signal`, decoded into YIQ.
+fn decode(color: u8, emphasis: u8, settings: &PaletteSettings) -> (f32, f32, f32) {
+  // Plus however far the TV's hue knob is turned; the 2C07's colors come out
+  // half a hue early:
+  let offset = match settings.decoder {
+    Decoder::Ntsc2C02 => NTSC_HUE_OFFSET,
+    Decoder::Pal2C07 => NTSC_HUE_OFFSET - 15.0,
+  } + settings.hue;
+
+  let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+  for phase in 0..12 {
+    let v = signal(color, emphasis, phase) / 12.0;
+    let angle = (phase as f32 * 30.0 + offset).to_radians();
+    y += v;
+    i += v * angle.cos();
+    q += v * angle.sin();
+  }
+  (y, i, q)
+}
+
+/// YIQ to RGB, then from the signal's (roughly) 2.2 gamma to `gamma`.
+pub(crate) fn yiq_to_color(y: f32, i: f32, q: f32, gamma: f32) -> Color {
+  let to_u8 = |v: f32| (v.clamp(0.0, 1.0).powf(2.2 / gamma) * 255.0).round() as u8;
+  Color {
+    r: to_u8(y + 0.946_882 * i + 0.623_557 * q),
+    g: to_u8(y - 0.274_788 * i - 0.635_691 * q),
+    b: to_u8(y - 1.108_545 * i + 1.709_007 * q),
+  }
+}
+
+// Only the palette RAM (`map`) is console state; `colors` is however the user
+// has chosen to display it.
+impl SaveState for Palette {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.map.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.map.load_state(r)
+  }
 }
 
 impl BusDeviceRange for Palette {
@@ -121,3 +334,80 @@ fn addr_to_palette_map_index(addr_: u16) -> usize {
 
   addr as usize
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn emphasis() {
+    let mut pal = vec![0x00; 192];
+    pal[0x30 * 3..0x30 * 3 + 3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    let palette = Palette::from_bytes(&pal).unwrap();
+
+    let white = |emphasis: usize| {
+      let color = palette.colors[emphasis * 64 + 0x30];
+      (color.r, color.g, color.b)
+    };
+    assert_eq!(white(0), (0xFF, 0xFF, 0xFF));
+    // Red:
+    assert_eq!(white(0b001), (0xFF, 208, 208));
+    // Red and green:
+    assert_eq!(white(0b011), (208, 208, 170));
+    assert_eq!(white(0b111), (170, 170, 170));
+
+    // All 512 colors, straight from the file:
+    let mut pal = vec![0x00; 1536];
+    pal[1533..].copy_from_slice(&[1, 2, 3]);
+    let color = Palette::from_bytes(&pal).unwrap().colors[511];
+    assert_eq!((color.r, color.g, color.b), (1, 2, 3));
+
+    assert!(Palette::from_bytes(&[0x00; 193]).is_err());
+  }
+
+  #[test]
+  fn generate() {
+    let color = |palette: &Palette, i: usize| {
+      let color = palette.colors[i];
+      (color.r, color.g, color.b)
+    };
+
+    let ntsc = Palette::generate(&PaletteSettings::default());
+    assert_eq!(color(&ntsc, 0x30), (0xFF, 0xFF, 0xFF));
+    assert_eq!(color(&ntsc, 0x0F), (0x00, 0x00, 0x00));
+    // Red, and about the same as the built-in palette's:
+    let (r, g, b) = color(&ntsc, 0x16);
+    assert!(r > g * 2 && r > b * 2);
+    let embedded = Palette::embedded();
+    let distance: u32 = (0..64)
+      .map(|i| {
+        let (a, b) = (ntsc.colors[i], embedded.colors[i]);
+        (a.r as i32 - b.r as i32).unsigned_abs()
+          + (a.g as i32 - b.g as i32).unsigned_abs()
+          + (a.b as i32 - b.b as i32).unsigned_abs()
+      })
+      .sum();
+    assert!(distance / 64 < 40, "{}", distance / 64);
+
+    let gray = Palette::generate(&PaletteSettings {
+      saturation: 0.0,
+      ..Default::default()
+    });
+    let (r, g, b) = color(&gray, 0x16);
+    assert!(r == g && g == b);
+
+    // Red emphasis tints white red on a 2C02, but green on a 2C07, which has
+    // the bits the other way round:
+    let (r, g, b) = color(&ntsc, 0b001 << 6 | 0x30);
+    assert!(r > g && r > b);
+    let pal = Palette::generate(&PaletteSettings {
+      decoder: Decoder::Pal2C07,
+      ..Default::default()
+    });
+    let (r, g, b) = color(&pal, 0b001 << 6 | 0x30);
+    assert!(g > r && g > b);
+    assert_ne!(color(&pal, 0x16), color(&ntsc, 0x16));
+
+    assert_eq!("2C07".parse(), Ok(Decoder::Pal2C07));
+  }
+}
@@ -1,6 +1,7 @@
 use crate::bus_device::BusDevice;
+use crate::savestate::{SaveState, StateReader, StateWriter};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Controller {
   pub a: bool,
   pub b: bool,
@@ -88,3 +89,41 @@ impl BusDevice for Peripherals {
     None
   }
 }
+
+impl SaveState for Peripherals {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.controllers.save_state(w);
+    self.controller_shifts.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.controllers.load_state(r)?;
+    self.controller_shifts.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for Controller {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.a.save_state(w);
+    self.b.save_state(w);
+    self.select.save_state(w);
+    self.start.save_state(w);
+    self.up.save_state(w);
+    self.down.save_state(w);
+    self.left.save_state(w);
+    self.right.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.a.load_state(r)?;
+    self.b.load_state(r)?;
+    self.select.load_state(r)?;
+    self.start.load_state(r)?;
+    self.up.load_state(r)?;
+    self.down.load_state(r)?;
+    self.left.load_state(r)?;
+    self.right.load_state(r)?;
+    Ok(())
+  }
+}
@@ -1,6 +1,7 @@
 use crate::bus_device::{BusDevice, BusDeviceRange};
 use crate::cart::{Cart, Mirroring};
 use crate::palette::{Color, Palette};
+use crate::savestate::{SaveState, StateReader, StateWriter};
 
 pub const SCREEN_W: usize = 256;
 pub const SCREEN_H: usize = 240;
@@ -28,7 +29,13 @@ pub struct Ppu {
   pub name_tables: [[u8; 1024]; 2],
   pub pattern_tables: [[u8; 4096]; 2],
   pub frame_complete: bool,
+  /// Frames finished since power-on
+  pub frame_count: u64,
   pub screen: [[u8; 4]; SCREEN_W * SCREEN_H],
+  /// The same frame as `screen`, before it's turned into RGB: each pixel's
+  /// color and emphasis bits, as an index into `Palette::colors`. For video
+  /// filters that want to work from the signal the PPU would have put out.
+  pub color_indices: [u16; SCREEN_W * SCREEN_H],
 
   address_latch: bool,
 
@@ -45,9 +52,6 @@ pub struct Ppu {
   pub mask: u8,
   pub control: u8,
 
-  /// Whether a non-maskable interrupt has been triggered
-  pub nmi: bool,
-
   // Internal state for rendering 8-pixels of background at a time
   bg_next_tile_id: u8,
   bg_next_tile_attribute: u8,
@@ -63,13 +67,40 @@ pub struct Ppu {
 
   oam_addr: u8,
 
-  // For rendering sprites:
-  sprites_on_scanline: Vec<ObjectAttributeEntry>,
-  sprites_on_scanline_contains_sprite_0: bool,
+  // Sprite evaluation, which fills secondary OAM with up to 8 sprites for the
+  // next scanline; see `evaluate_sprites`:
+  secondary_oam: [u8; 32],
+  /// The byte evaluation read from OAM on the last odd cycle
+  oam_latch: u8,
+  /// Which sprite in OAM evaluation is looking at
+  eval_n: u8,
+  /// Which byte of that sprite
+  eval_m: u8,
+  /// Where the next byte goes in secondary OAM; 32 once it's full
+  eval_secondary_addr: u8,
+  /// Whether evaluation has been all the way through OAM, or found an overflow
+  eval_done: bool,
+  /// Whether sprite 0 made it into secondary OAM
+  eval_sprite_0: bool,
+
+  // Sprites fetched during cycles 257-320 for the next scanline; see
+  // `fetch_sprites`:
+  // Only the first 8 come from secondary OAM; the rest are only here with the
+  // sprite limit off:
+  sprites: [ObjectAttributeEntry; 64],
+  /// Pattern rows, already flipped horizontally where the sprite asks for it
+  sprite_pattern_lo: [u8; 64],
+  sprite_pattern_hi: [u8; 64],
+  sprite_count: u8,
+  sprites_contain_sprite_0: bool,
+
+  /// Whether to draw every sprite on a scanline rather than the first 8, to do
+  /// away with flicker. Not part of the save state; it's a setting.
+  unlimited_sprites: bool,
 }
 
 /// A Sprite, basically
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct ObjectAttributeEntry {
   /// Y position of the sprite
   pub y: u8,
@@ -282,10 +313,12 @@ impl Ppu {
       scanline: 0,
       cycle: 0,
       frame_complete: false,
+      frame_count: 0,
       palette,
       name_tables: [[0x00; 1024]; 2],
       pattern_tables: [[0x00; 4096]; 2],
       screen: [[0xFF, 0x00, 0xFF, 0xFF]; SCREEN_W * SCREEN_H],
+      color_indices: [0x0000; SCREEN_W * SCREEN_H],
 
       // Misc internal state
       address_latch: false,
@@ -300,8 +333,6 @@ impl Ppu {
       mask: 0x00,
       control: 0x00,
 
-      nmi: false,
-
       bg_next_tile_id: 0x00,
       bg_next_tile_attribute: 0x00,
       bg_next_tile_addr_lsb: 0x00,
@@ -321,8 +352,21 @@ impl Ppu {
 
       oam_addr: 0x00,
 
-      sprites_on_scanline: vec![],
-      sprites_on_scanline_contains_sprite_0: false,
+      secondary_oam: [0xFF; 32],
+      oam_latch: 0x00,
+      eval_n: 0,
+      eval_m: 0,
+      eval_secondary_addr: 0,
+      eval_done: false,
+      eval_sprite_0: false,
+
+      sprites: [ObjectAttributeEntry::default(); 64],
+      sprite_pattern_lo: [0x00; 64],
+      sprite_pattern_hi: [0x00; 64],
+      sprite_count: 0,
+      sprites_contain_sprite_0: false,
+
+      unlimited_sprites: false,
     }
   }
 
@@ -531,35 +575,13 @@ impl Ppu {
         self.transfer_address_y();
       }
 
-      // Foreground sprite "evaluation"
-      if self.cycle == 257 && self.scanline >= 0 {
-        let scanline = self.scanline as i16;
-        let sprite_height = if self.control.tall_sprites() { 16 } else { 8 };
-
-        self.sprites_on_scanline.clear();
-        self.sprites_on_scanline_contains_sprite_0 = false;
-        // Determine which sprites will be visible on our scanline; we only draw
-        // the first 8 that appear in the order of our OAM.
-        for i in 0..self.oam.len() {
-          let sprite = self.oam[i];
-          let y_diff = scanline - (sprite.y as i16);
-
-          // First determine whether the sprite is within our Y range:
-          if !(y_diff >= 0 && y_diff < sprite_height) {
-            continue;
-          }
-
-          if self.sprites_on_scanline.len() < 8 {
-            if i == 0 {
-              self.sprites_on_scanline_contains_sprite_0 = true;
-            }
-            self.sprites_on_scanline.push(sprite);
-          }
-
-          if self.sprites_on_scanline.len() >= 8 {
-            self.status = self.status.set_sprite_overflow(true);
-            break;
-          }
+      // Foreground sprites; the PPU only works on these while it's rendering:
+      if self.mask.render_background() || self.mask.render_sprites() {
+        if self.scanline >= 0 && self.cycle >= 1 && self.cycle <= 256 {
+          self.evaluate_sprites();
+        }
+        if self.cycle >= 257 && self.cycle <= 320 {
+          self.fetch_sprites(cart);
         }
       }
     }
@@ -573,9 +595,6 @@ impl Ppu {
       // Start of VBlank:
       if self.scanline == 241 && self.cycle == 1 {
         self.status = self.status.set_vblank(true);
-        if self.control.enable_nmi() {
-          self.nmi = true;
-        }
       }
     }
 
@@ -589,63 +608,18 @@ impl Ppu {
       {
         // First determine which sprite pixel (if any) we need to render:
 
-        for i in 0..self.sprites_on_scanline.len() {
-          let sprite = self.sprites_on_scanline[i];
+        for i in 0..self.sprite_count as usize {
+          let sprite = self.sprites[i];
           let x_diff = (self.cycle as i16) - (sprite.x as i16) - 1;
           if !(x_diff >= 0 && x_diff < 8) {
             continue;
           }
-          let y_diff = (self.scanline as i16) - (sprite.y as i16) - 1;
-
-          // Table number to get our sprite graphics from.
-          // false = 0; true = 1
-          let table: bool;
-          let tile_id: u8;
-          match self.control.tall_sprites() {
-            // When we're working with tall sprites, each sprite takes up 2x the
-            // space, so we can only refer to 128 sprites per table instead of
-            // the usual 256.
-            //
-            // Rather than being limited to a single pattern table for sprites,
-            // we can use the unused bit in our tile ID byte to select which
-            // pattern table we want our sprite to be from.
-            //
-            // The NES designers use the least significant bit of our tile ID
-            // byte for this purpose.
-            true => {
-              table = (sprite.tile_id & 0b0000_0001) != 0;
-              // The tile_id
-              tile_id = if y_diff < 8 {
-                // Top 8x8 of the 8x16 sprite:
-                (sprite.tile_id & 0b1111_1110) + if sprite.flip_y() { 1 } else { 0 }
-              } else {
-                // Bottom 8x8 of the 8x16 sprite; effectively one full row down:
-                (sprite.tile_id & 0b1111_1110) + if sprite.flip_y() { 0 } else { 1 }
-              };
-            }
-            // Otherwise all sprites share the same table, controlled with a
-            // flag in the control register:
-            false => {
-              table = self.control.pattern_fg_table();
-              tile_id = sprite.tile_id;
-            }
-          };
-
-          // Low/High tile byte
-          let base_addr = ((table as u16) << 12)
-            | ((tile_id as u16) << 4)
-            | (((if sprite.flip_y() { 7 - y_diff } else { y_diff }) as u16) & 0x0007);
-
-          // We can shift our bit-fields by our x-diff so the most significant
-          // bit is the current pixel value:
-          let mut tile_lsb = self.ppu_read(base_addr + 0, cart);
-          let mut tile_msb = self.ppu_read(base_addr + 8, cart);
-          if sprite.flip_x() {
-            tile_lsb = flip(tile_lsb);
-            tile_msb = flip(tile_msb);
-          }
-          tile_lsb <<= x_diff;
-          tile_msb <<= x_diff;
+
+          // The pattern was fetched at the end of the last scanline, so we can
+          // shift it by our x-diff so the most significant bit is the current
+          // pixel value:
+          let tile_lsb = self.sprite_pattern_lo[i] << x_diff;
+          let tile_msb = self.sprite_pattern_hi[i] << x_diff;
           let p0_pixel = ((tile_lsb & 0b1000_0000) > 0) as u8;
           let p1_pixel = ((tile_msb & 0b1000_0000) > 0) as u8;
           let pixel = (p1_pixel << 1) | p0_pixel;
@@ -653,7 +627,7 @@ impl Ppu {
             fg_pixel = pixel;
             fg_palette = sprite.palette();
             fg_priority = sprite.priority();
-            if i == 0 && self.sprites_on_scanline_contains_sprite_0 {
+            if i == 0 && self.sprites_contain_sprite_0 {
               fg_sprite_0_hit = true;
             }
             break;
@@ -727,7 +701,9 @@ impl Ppu {
       let screen_x = self.cycle - 1;
       let screen_y = self.scanline;
       let idx = (screen_y as usize) * SCREEN_W + (screen_x as usize);
-      let color = self.get_color_from_palette_ram(palette, pixel, cart);
+      let color_index = self.get_color_index(palette, pixel, cart);
+      let color = self.palette.colors[color_index as usize];
+      self.color_indices[idx] = color_index;
       self.screen[idx][0] = color.r;
       self.screen[idx][1] = color.g;
       self.screen[idx][2] = color.b;
@@ -748,23 +724,266 @@ impl Ppu {
       if self.scanline >= 261 {
         self.scanline = -1;
         self.frame_complete = true;
+        self.frame_count += 1;
       }
     }
   }
 
   fn get_color_from_palette_ram(&self, palette: u8, pixel: u8, cart: &mut Cart) -> Color {
-    let idx = self.ppu_read(0x3F00 as u16 + ((palette << 2) + pixel) as u16, cart);
-    self.palette.colors[(idx % 64) as usize]
+    self.palette.colors[self.get_color_index(palette, pixel, cart) as usize]
+  }
+
+  /// Which of the 512 colors (64 colors, times 8 for emphasis) a pixel is.
+  fn get_color_index(&self, palette: u8, pixel: u8, cart: &mut Cart) -> u16 {
+    let mut idx = self.ppu_read(0x3F00 as u16 + ((palette << 2) + pixel) as u16, cart) % 64;
+    if self.mask.grayscale() {
+      // Keep the brightness, but use the gray column:
+      idx &= 0x30;
+    }
+    // The emphasis bits pick one of the palette's 8 sets of 64 colors:
+    let emphasis = (self.mask.enhance_red() as u16)
+      | (self.mask.enhance_green() as u16) << 1
+      | (self.mask.enhance_blue() as u16) << 2;
+    emphasis * 64 + idx as u16
+  }
+
+  /// One cycle of sprite evaluation, which picks out the first 8 sprites on the
+  /// next scanline and copies them into secondary OAM for `fetch_sprites`.
+  ///
+  /// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+  ///
+  /// Cycles 1-64 clear secondary OAM to $FF. Cycles 65-256 alternate between
+  /// reading a byte of OAM (odd cycles) and writing it to secondary OAM (even
+  /// cycles). A sprite's Y coordinate is always written, but only kept if the
+  /// sprite is on the next scanline, in which case its other 3 bytes follow.
+  fn evaluate_sprites(&mut self) {
+    if self.cycle <= 64 {
+      if self.cycle % 2 == 0 {
+        self.secondary_oam[(self.cycle / 2 - 1) as usize] = 0xFF;
+      }
+      if self.cycle == 64 {
+        self.eval_n = 0;
+        self.eval_m = 0;
+        self.eval_secondary_addr = 0;
+        self.eval_done = false;
+        self.eval_sprite_0 = false;
+      }
+      return;
+    }
+
+    if self.cycle % 2 == 1 {
+      self.oam_latch = self.oam_byte(self.eval_n * 4 + self.eval_m);
+      return;
+    }
+
+    if self.eval_done {
+      // The PPU carries on reading Y coordinates and failing to copy them
+      // until the end of the scanline; nothing can see that.
+      self.eval_n = (self.eval_n + 1) % 64;
+      return;
+    }
+
+    let y_diff = self.scanline - self.oam_latch as isize;
+    let in_range = y_diff >= 0 && y_diff < self.sprite_height();
+
+    if self.eval_secondary_addr < 32 {
+      self.secondary_oam[self.eval_secondary_addr as usize] = self.oam_latch;
+      if self.eval_m == 0 && !in_range {
+        // Not on the next scanline, so the next Y coordinate goes over this
+        // one:
+        self.next_sprite();
+        return;
+      }
+      if self.eval_m == 0 && self.eval_n == 0 {
+        self.eval_sprite_0 = true;
+      }
+      self.eval_secondary_addr += 1;
+      self.eval_m += 1;
+      if self.eval_m == 4 {
+        self.eval_m = 0;
+        self.next_sprite();
+      }
+    } else if in_range {
+      // A ninth sprite... or so the PPU thinks; see below. The real PPU reads
+      // the rest of the sprite before giving up, but that makes no difference
+      // to us.
+      self.status = self.status.set_sprite_overflow(true);
+      self.eval_done = true;
+    } else {
+      // Once secondary OAM is full, the PPU is meant to only check each
+      // sprite's Y coordinate, but it moves on to the next byte along with the
+      // next sprite. So it ends up treating tile IDs, attributes and X
+      // positions as Y coordinates, which can both miss a ninth sprite and
+      // find one that isn't there.
+      self.eval_m = (self.eval_m + 1) % 4;
+      self.next_sprite();
+    }
+  }
+
+  fn sprite_height(&self) -> isize {
+    if self.control.tall_sprites() {
+      16
+    } else {
+      8
+    }
+  }
+
+  fn next_sprite(&mut self) {
+    self.eval_n += 1;
+    if self.eval_n == 64 {
+      self.eval_n = 0;
+      self.eval_done = true;
+    }
+  }
+
+  /// One cycle of fetching the sprites in secondary OAM for the next scanline,
+  /// during cycles 257-320. Each sprite takes 8 cycles: two nametable reads
+  /// that go nowhere, then the low and high bytes of its pattern row. Empty
+  /// slots still fetch tile $FF, which mappers watching the bus rely on.
+  fn fetch_sprites(&mut self, cart: &mut Cart) {
+    let i = ((self.cycle - 257) / 8) as usize;
+    let entry = &self.secondary_oam[i * 4..i * 4 + 4];
+    let sprite = ObjectAttributeEntry {
+      y: entry[0],
+      tile_id: entry[1],
+      attribute: entry[2],
+      x: entry[3],
+    };
+
+    if self.cycle == 257 {
+      // Nothing is drawn on the pre-render scanline, so it has nothing to pass
+      // on to scanline 0:
+      if self.scanline >= 0 {
+        self.sprite_count = self.eval_secondary_addr / 4;
+        self.sprites_contain_sprite_0 = self.eval_sprite_0;
+      } else {
+        self.sprite_count = 0;
+        self.sprites_contain_sprite_0 = false;
+      }
+    }
+
+    match (self.cycle - 257) % 8 {
+      0 | 2 => {
+        let tile_addr = 0x2000 | (self.vram_addr & 0x0FFF);
+        self.ppu_read(tile_addr, cart);
+        self.sprites[i] = sprite;
+      }
+      4 | 6 => {
+        // Low/High tile byte
+        let base_addr = self.sprite_pattern_addr(sprite);
+        let lo = (self.cycle - 257) % 8 == 4;
+        let mut data = self.ppu_read(base_addr + if lo { 0 } else { 8 }, cart);
+        if sprite.flip_x() {
+          data = flip(data);
+        }
+        if lo {
+          self.sprite_pattern_lo[i] = data;
+        } else {
+          self.sprite_pattern_hi[i] = data;
+        }
+      }
+      _ => {}
+    }
+
+    if self.cycle == 320 && self.unlimited_sprites && self.sprite_count == 8 {
+      self.fetch_extra_sprites(cart);
+    }
+  }
+
+  /// Where the row of `sprite`'s pattern for the next scanline starts.
+  fn sprite_pattern_addr(&self, sprite: ObjectAttributeEntry) -> u16 {
+    // Which row of the sprite the next scanline is; anything for an empty
+    // slot
+    let y_diff = (self.scanline - sprite.y as isize).rem_euclid(16) as u8;
+
+    // Table number to get our sprite graphics from.
+    // false = 0; true = 1
+    let table: bool;
+    let tile_id: u8;
+    let row: u8;
+    match self.control.tall_sprites() {
+      // When we're working with tall sprites, each sprite takes up 2x the
+      // space, so we can only refer to 128 sprites per table instead of
+      // the usual 256.
+      //
+      // Rather than being limited to a single pattern table for sprites,
+      // we can use the unused bit in our tile ID byte to select which
+      // pattern table we want our sprite to be from.
+      //
+      // The NES designers use the least significant bit of our tile ID
+      // byte for this purpose.
+      true => {
+        table = (sprite.tile_id & 0b0000_0001) != 0;
+        let y = if sprite.flip_y() { 15 - y_diff } else { y_diff };
+        // The bottom 8x8 of the 8x16 sprite is effectively one full row
+        // down:
+        tile_id = (sprite.tile_id & 0b1111_1110) + (y >= 8) as u8;
+        row = y & 0x07;
+      }
+      // Otherwise all sprites share the same table, controlled with a
+      // flag in the control register:
+      false => {
+        table = self.control.pattern_fg_table();
+        tile_id = sprite.tile_id;
+        let y = y_diff & 0x07;
+        row = if sprite.flip_y() { 7 - y } else { y };
+      }
+    };
+
+    ((table as u16) << 12) | ((tile_id as u16) << 4) | (row as u16)
+  }
+
+  /// With the sprite limit off, finds the sprites on the next scanline that
+  /// didn't fit in secondary OAM and fetches them too, quietly: mappers don't
+  /// see these reads, and nothing the CPU can see changes.
+  fn fetch_extra_sprites(&mut self, cart: &Cart) {
+    // The first 8 are already in secondary OAM:
+    let mut skip = 8;
+    for n in 0..self.oam.len() {
+      let sprite = self.oam[n];
+      let y_diff = self.scanline - sprite.y as isize;
+      if y_diff < 0 || y_diff >= self.sprite_height() {
+        continue;
+      }
+      if skip > 0 {
+        skip -= 1;
+        continue;
+      }
+      let i = self.sprite_count as usize;
+      let base_addr = self.sprite_pattern_addr(sprite);
+      let mut lo = self.safe_ppu_read(base_addr, cart);
+      let mut hi = self.safe_ppu_read(base_addr + 8, cart);
+      if sprite.flip_x() {
+        lo = flip(lo);
+        hi = flip(hi);
+      }
+      self.sprites[i] = sprite;
+      self.sprite_pattern_lo[i] = lo;
+      self.sprite_pattern_hi[i] = hi;
+      self.sprite_count += 1;
+    }
+  }
+
+  /// Reads pattern memory without the mapper noticing.
+  fn safe_ppu_read(&self, addr: u16, cart: &Cart) -> u8 {
+    match cart.safe_ppu_read(addr) {
+      Some(data) => data,
+      None => self.pattern_tables[((addr & 0x1000) >> 12) as usize][(addr & 0x0FFF) as usize],
+    }
   }
 
   fn get_oam_data(&self) -> u8 {
+    self.oam_byte(self.oam_addr)
+  }
+
+  fn oam_byte(&self, addr: u8) -> u8 {
     // Each OAM entry is 4 bytes long, so our OAM address needs to be divided by
     // four to determine which index into our OAM array we need to read from.
-    let oam_entry = self.oam[(self.oam_addr as usize) / 4];
+    let oam_entry = self.oam[(addr as usize) / 4];
 
     // The remainder determines which piece of data we need to read from our OAM
     // entry:
-    match self.oam_addr % 4 {
+    match addr % 4 {
       0 => oam_entry.y,
       1 => oam_entry.tile_id,
       2 => oam_entry.attribute,
@@ -773,6 +992,24 @@ impl Ppu {
     }
   }
 
+  pub fn unlimited_sprites(&self) -> bool {
+    self.unlimited_sprites
+  }
+
+  /// Turns the 8-sprites-per-scanline limit off (or back on). Only the picture
+  /// changes: sprite overflow and sprite 0 hits still happen as on hardware,
+  /// so games behave the same.
+  pub fn set_unlimited_sprites(&mut self, unlimited: bool) {
+    self.unlimited_sprites = unlimited;
+  }
+
+  /// The PPU's NMI output, which is held for as long as we're in vblank with
+  /// NMIs enabled. The CPU only watches for it going high, so enabling NMIs
+  /// partway through vblank fires one straight away.
+  pub fn nmi_line(&self) -> bool {
+    self.status.vblank() && self.control.enable_nmi()
+  }
+
   pub fn set_oam_data(&mut self, oam_addr: u8, data: u8) {
     // Each OAM entry is 4 bytes long, so our OAM address needs to be divided by
     // four to determine which index into our OAM array we need to read from.
@@ -922,7 +1159,7 @@ impl Ppu {
   ///
   /// It can help to picture a tile as something like this:
   ///
-  /// ```
+  /// ```text
   /// 0, 1, 2, 3, 3, 2, 1, 0
   /// ...7 more rows like this...
   /// ```
@@ -930,7 +1167,7 @@ impl Ppu {
   /// You might at first assume that these pixels are stored in the following
   /// way in memory (it'll be clear why I'm using binary notation here later):
   ///
-  /// ```
+  /// ```text
   /// 0,    1,    2,    3,    3,    2,    1,    0
   /// 0b00, 0b01, 0b10, 0b11, 0b11, 0b10, 0b01, 0b00
   /// ...7 more rows like this...
@@ -939,7 +1176,7 @@ impl Ppu {
   /// Written in _bytes_ (the unit we're used to reading one at a time) this
   /// would look like this:
   ///
-  /// ```
+  /// ```text
   ///   0,1,2,3,    3,2,1,0
   /// 0b00011011, 0b11100100
   /// ...7 more rows like this...
@@ -970,7 +1207,7 @@ impl Ppu {
   /// Concretely, the first 8 pixels (`0, 1, 2, 3, 3, 2, 1, 0`) could be
   /// represented like this in the pattern table memory:
   ///
-  /// ```
+  /// ```text
   ///       2-bit number:  0   1   2   3   3   2   1   0
   ///      binary number: 00  01  10  11  11  10  01  00
   /// lsb (offset by  0):  0,  1,  0,  1,  1,  0,  1,  0
@@ -1270,9 +1507,114 @@ impl BusDevice for Ppu {
   }
 }
 
+// Note that `screen` isn't part of the state; it's output, and gets redrawn
+// within a frame of loading a state anyway. Nor is `frame_count`, which only
+// decides where the NTSC filter's dot crawl starts.
+impl SaveState for Ppu {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.scanline.save_state(w);
+    self.cycle.save_state(w);
+    self.palette.save_state(w);
+    self.name_tables.save_state(w);
+    self.pattern_tables.save_state(w);
+    self.frame_complete.save_state(w);
+    self.address_latch.save_state(w);
+    self.data_buffer.save_state(w);
+    self.vram_addr.save_state(w);
+    self.tram_addr.save_state(w);
+    self.fine_x.save_state(w);
+    self.status.save_state(w);
+    self.mask.save_state(w);
+    self.control.save_state(w);
+    self.bg_next_tile_id.save_state(w);
+    self.bg_next_tile_attribute.save_state(w);
+    self.bg_next_tile_addr_lsb.save_state(w);
+    self.bg_next_tile_addr_msb.save_state(w);
+    self.bg_shifter_pattern_lo.save_state(w);
+    self.bg_shifter_pattern_hi.save_state(w);
+    self.bg_shifter_attrib_lo.save_state(w);
+    self.bg_shifter_attrib_hi.save_state(w);
+    self.oam.save_state(w);
+    self.oam_addr.save_state(w);
+    self.secondary_oam.save_state(w);
+    self.oam_latch.save_state(w);
+    self.eval_n.save_state(w);
+    self.eval_m.save_state(w);
+    self.eval_secondary_addr.save_state(w);
+    self.eval_done.save_state(w);
+    self.eval_sprite_0.save_state(w);
+    self.sprites.save_state(w);
+    self.sprite_pattern_lo.save_state(w);
+    self.sprite_pattern_hi.save_state(w);
+    self.sprite_count.save_state(w);
+    self.sprites_contain_sprite_0.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.scanline.load_state(r)?;
+    self.cycle.load_state(r)?;
+    self.palette.load_state(r)?;
+    self.name_tables.load_state(r)?;
+    self.pattern_tables.load_state(r)?;
+    self.frame_complete.load_state(r)?;
+    self.address_latch.load_state(r)?;
+    self.data_buffer.load_state(r)?;
+    self.vram_addr.load_state(r)?;
+    self.tram_addr.load_state(r)?;
+    self.fine_x.load_state(r)?;
+    self.status.load_state(r)?;
+    self.mask.load_state(r)?;
+    self.control.load_state(r)?;
+    self.bg_next_tile_id.load_state(r)?;
+    self.bg_next_tile_attribute.load_state(r)?;
+    self.bg_next_tile_addr_lsb.load_state(r)?;
+    self.bg_next_tile_addr_msb.load_state(r)?;
+    self.bg_shifter_pattern_lo.load_state(r)?;
+    self.bg_shifter_pattern_hi.load_state(r)?;
+    self.bg_shifter_attrib_lo.load_state(r)?;
+    self.bg_shifter_attrib_hi.load_state(r)?;
+    self.oam.load_state(r)?;
+    self.oam_addr.load_state(r)?;
+    self.secondary_oam.load_state(r)?;
+    self.oam_latch.load_state(r)?;
+    self.eval_n.load_state(r)?;
+    self.eval_m.load_state(r)?;
+    self.eval_secondary_addr.load_state(r)?;
+    self.eval_done.load_state(r)?;
+    self.eval_sprite_0.load_state(r)?;
+    self.sprites.load_state(r)?;
+    self.sprite_pattern_lo.load_state(r)?;
+    self.sprite_pattern_hi.load_state(r)?;
+    self.sprite_count.load_state(r)?;
+    self.sprites_contain_sprite_0.load_state(r)?;
+    Ok(())
+  }
+}
+
+impl SaveState for ObjectAttributeEntry {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.y.save_state(w);
+    self.tile_id.save_state(w);
+    self.attribute.save_state(w);
+    self.x.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    self.y.load_state(r)?;
+    self.tile_id.load_state(r)?;
+    self.attribute.load_state(r)?;
+    self.x.load_state(r)?;
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use crate::{palette::Palette, ppu::LoopyRegister};
+  use crate::{
+    cart::Cart,
+    palette::Palette,
+    ppu::{LoopyRegister, MaskRegister, StatusRegister},
+  };
   use pretty_assertions::assert_eq;
 
   use super::{ObjectAttributeEntry, Ppu};
@@ -1371,4 +1713,105 @@ mod tests {
     ppu.set_oam_data(idx * 4 + 5, 47);
     assert_eq!(ppu.oam[idx as usize + 1].tile_id, 47);
   }
+
+  /// A PPU about to evaluate sprites for scanline 11, with every sprite well
+  /// out of the way apart from `sprites`
+  fn evaluation(sprites: &[ObjectAttributeEntry]) -> Ppu {
+    let mut ppu = Ppu::new(Palette::new());
+    ppu.mask = ppu.mask.set_render_sprites(true);
+    ppu.scanline = 10;
+    for (i, entry) in ppu.oam.iter_mut().enumerate() {
+      *entry = sprites.get(i).copied().unwrap_or(ObjectAttributeEntry {
+        y: 0xF0,
+        ..Default::default()
+      });
+    }
+    for cycle in 1..=256 {
+      ppu.cycle = cycle;
+      ppu.evaluate_sprites();
+    }
+    ppu
+  }
+
+  fn sprite(y: u8, tile_id: u8) -> ObjectAttributeEntry {
+    ObjectAttributeEntry {
+      y,
+      tile_id,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn sprite_evaluation() {
+    let mut sprites = vec![sprite(0xF0, 0); 20];
+    for i in 0..8 {
+      sprites[i * 2 + 1] = sprite(5, i as u8);
+    }
+    let ppu = evaluation(&sprites);
+    for i in 0..8 {
+      assert_eq!(&ppu.secondary_oam[i * 4..i * 4 + 2], &[5, i as u8]);
+    }
+    assert_eq!(ppu.eval_secondary_addr, 32);
+    assert!(!ppu.eval_sprite_0);
+    // Exactly 8 isn't an overflow:
+    assert!(!ppu.status.sprite_overflow());
+
+    let ppu = evaluation(&[sprite(3, 0), sprite(0xF0, 0), sprite(10, 1)]);
+    assert_eq!(&ppu.secondary_oam[0..8], &[3, 0, 0, 0, 10, 1, 0, 0]);
+    // The next slot gets the Y coordinate of every sprite that didn't make it,
+    // but the rest are left clear:
+    assert_eq!(ppu.secondary_oam[8], 0xF0);
+    assert_eq!(&ppu.secondary_oam[9..], &[0xFF; 23]);
+    assert!(ppu.eval_sprite_0);
+
+    sprites[16] = sprite(5, 0);
+    assert!(evaluation(&sprites).status.sprite_overflow());
+  }
+
+  #[test]
+  fn sprite_overflow_bug() {
+    // Once 8 are found, sprite 8 is checked by its Y coordinate, but sprite 9
+    // by its tile ID:
+    let mut sprites = vec![sprite(5, 0); 8];
+    sprites.push(sprite(100, 0));
+    sprites.push(sprite(5, 100));
+    assert!(!evaluation(&sprites).status.sprite_overflow());
+
+    sprites[9] = sprite(100, 5);
+    assert!(evaluation(&sprites).status.sprite_overflow());
+  }
+
+  #[test]
+  fn unlimited_sprites() {
+    // NROM, with every pattern solid:
+    let mut rom = b"NES\x1A\x01\x01".to_vec();
+    rom.resize(16, 0x00);
+    rom.resize(16 + 16 * 1024, 0x00);
+    rom.resize(16 + 24 * 1024, 0xFF);
+    let mut cart = Cart::new(&rom).unwrap();
+
+    let sprites: Vec<ObjectAttributeEntry> = (0..10)
+      .map(|i| ObjectAttributeEntry {
+        y: 5,
+        x: i * 16,
+        ..Default::default()
+      })
+      .collect();
+    for unlimited in [false, true] {
+      let mut ppu = evaluation(&sprites);
+      ppu.set_unlimited_sprites(unlimited);
+      for cycle in 257..=320 {
+        ppu.cycle = cycle;
+        ppu.fetch_sprites(&mut cart);
+      }
+      assert_eq!(ppu.sprite_count, if unlimited { 10 } else { 8 });
+      assert_eq!(ppu.sprites[9].x, if unlimited { 144 } else { 0 });
+      assert_eq!(
+        ppu.sprite_pattern_lo[9],
+        if unlimited { 0xFF } else { 0x00 }
+      );
+      // The CPU sees the same either way:
+      assert!(ppu.status.sprite_overflow());
+    }
+  }
 }
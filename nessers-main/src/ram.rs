@@ -1,6 +1,7 @@
 use crate::{
   bus_device::{BusDevice, BusDeviceRange},
   cart::Cart,
+  savestate::{load_fixed_len, SaveState, StateReader, StateWriter},
 };
 
 #[derive(Clone)]
@@ -45,3 +46,13 @@ impl BusDevice for Ram {
     Some(self.buf[addr as usize])
   }
 }
+
+impl SaveState for Ram {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.buf.save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    load_fixed_len(&mut self.buf, r)
+  }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::apu::Channel;
+use crate::wav::{SampleFormat, WavWriter};
+
+/// Writes everything the APU plays to a WAV file, and optionally each channel
+/// to a "stem" file of its own. See `Nes::start_recording`.
+pub struct Recorder {
+  mix: WavWriter,
+  stems: Vec<WavWriter>,
+}
+
+impl Recorder {
+  pub fn create(
+    path: &Path,
+    sample_rate: u32,
+    format: SampleFormat,
+    stems: bool,
+  ) -> io::Result<Recorder> {
+    let mix = WavWriter::create(path, sample_rate, format)?;
+    let stems = if stems {
+      Channel::ALL
+        .iter()
+        .map(|channel| WavWriter::create(stem_path(path, *channel), sample_rate, format))
+        .collect::<io::Result<Vec<WavWriter>>>()?
+    } else {
+      vec![]
+    };
+
+    Ok(Recorder { mix, stems })
+  }
+
+  /// Errors along the way come out of `finish`.
+  pub fn record(&mut self, sample: f32, stems: Option<[f32; 6]>) {
+    self.mix.write_sample(sample);
+    if let Some(stems) = stems {
+      for (writer, sample) in self.stems.iter_mut().zip(stems.iter()) {
+        writer.write_sample(*sample);
+      }
+    }
+  }
+
+  pub fn finish(self) -> io::Result<()> {
+    self.mix.finish()?;
+    for writer in self.stems {
+      writer.finish()?;
+    }
+    Ok(())
+  }
+}
+
+/// Where a channel's stem goes: `music.wav`'s pulse 1 goes to
+/// `music-pulse1.wav`, and so on.
+pub fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  let name = channel.name().to_lowercase().replace(' ', "");
+  path.with_file_name(format!("{}-{}.wav", stem, name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn stems() {
+    let dir = std::env::temp_dir().join(format!("nessers-recorder-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("music.wav");
+    assert_eq!(
+      stem_path(&path, Channel::Pulse1),
+      dir.join("music-pulse1.wav")
+    );
+
+    let mut recorder = Recorder::create(&path, 44_100, SampleFormat::Float32, true).unwrap();
+    for _ in 0..100 {
+      recorder.record(0.5, Some([0.1, 0.2, 0.3, 0.4, 0.5, 0.0]));
+    }
+    recorder.finish().unwrap();
+
+    let mix = fs::read(&path).unwrap();
+    assert_eq!(&mix[0..4], b"RIFF");
+    assert_eq!(&mix[20..22], &3u16.to_le_bytes());
+    // 58 bytes of header, then the samples:
+    assert_eq!(mix.len(), 58 + 100 * 4);
+    assert_eq!(&mix[58..62], &0.5f32.to_le_bytes());
+
+    let dmc = fs::read(stem_path(&path, Channel::Dmc)).unwrap();
+    assert_eq!(dmc.len(), 58 + 100 * 4);
+    assert_eq!(&dmc[58..62], &0.5f32.to_le_bytes());
+    let triangle = fs::read(stem_path(&path, Channel::Triangle)).unwrap();
+    assert_eq!(&triangle[58..62], &0.3f32.to_le_bytes());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Makes a lock-free ring buffer of samples for one thread to fill and another
+/// to drain, e.g. the emulator and the audio callback.
+///
+/// Neither end ever blocks or allocates, which matters on the audio thread: a
+/// callback that waits on a lock, or on the emulator, is a crackle.
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+  let shared = Arc::new(Shared {
+    // One slot always stays empty, so that a full buffer doesn't look the same
+    // as an empty one:
+    slots: (0..capacity + 1).map(|_| AtomicU32::new(0)).collect(),
+    read: AtomicUsize::new(0),
+    write: AtomicUsize::new(0),
+  });
+  (
+    Producer {
+      shared: shared.clone(),
+    },
+    Consumer { shared },
+  )
+}
+
+struct Shared {
+  /// Samples, as their bits; there's no atomic f32
+  slots: Box<[AtomicU32]>,
+  /// The next slot to read; only the consumer moves it
+  read: AtomicUsize,
+  /// The next slot to write; only the producer moves it
+  write: AtomicUsize,
+}
+
+impl Shared {
+  fn len(&self) -> usize {
+    let read = self.read.load(Ordering::Acquire);
+    let write = self.write.load(Ordering::Acquire);
+    (write + self.slots.len() - read) % self.slots.len()
+  }
+
+  fn capacity(&self) -> usize {
+    self.slots.len() - 1
+  }
+}
+
+pub struct Producer {
+  shared: Arc<Shared>,
+}
+
+impl Producer {
+  /// Adds a sample, unless the buffer's full; returns whether it fit.
+  pub fn push(&self, sample: f32) -> bool {
+    let write = self.shared.write.load(Ordering::Relaxed);
+    let next = (write + 1) % self.shared.slots.len();
+    if next == self.shared.read.load(Ordering::Acquire) {
+      return false;
+    }
+    self.shared.slots[write].store(sample.to_bits(), Ordering::Relaxed);
+    // Publishes the sample along with the new position:
+    self.shared.write.store(next, Ordering::Release);
+    true
+  }
+
+  pub fn len(&self) -> usize {
+    self.shared.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+}
+
+pub struct Consumer {
+  shared: Arc<Shared>,
+}
+
+impl Consumer {
+  /// Takes the oldest sample, if there is one.
+  pub fn pop(&self) -> Option<f32> {
+    let read = self.shared.read.load(Ordering::Relaxed);
+    if read == self.shared.write.load(Ordering::Acquire) {
+      return None;
+    }
+    let sample = f32::from_bits(self.shared.slots[read].load(Ordering::Relaxed));
+    // Hands the slot back to the producer:
+    self
+      .shared
+      .read
+      .store((read + 1) % self.shared.slots.len(), Ordering::Release);
+    Some(sample)
+  }
+
+  pub fn len(&self) -> usize {
+    self.shared.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fills_and_drains() {
+    let (tx, rx) = ring_buffer(3);
+    assert!(rx.is_empty());
+    assert_eq!(rx.pop(), None);
+    assert!(tx.push(1.0));
+    assert!(tx.push(2.0));
+    assert!(tx.push(3.0));
+    assert!(!tx.push(4.0));
+    assert_eq!(tx.len(), 3);
+
+    assert_eq!(rx.pop(), Some(1.0));
+    // Wraps around:
+    assert!(tx.push(5.0));
+    assert_eq!(rx.pop(), Some(2.0));
+    assert_eq!(rx.pop(), Some(3.0));
+    assert_eq!(rx.pop(), Some(5.0));
+    assert_eq!(rx.pop(), None);
+    assert!(rx.is_empty());
+  }
+
+  #[test]
+  fn across_threads() {
+    let (tx, rx) = ring_buffer(64);
+    let producer = std::thread::spawn(move || {
+      for i in 0..10_000 {
+        while !tx.push(i as f32) {
+          std::thread::yield_now();
+        }
+      }
+    });
+
+    let mut expected = 0;
+    while expected < 10_000 {
+      match rx.pop() {
+        Some(sample) => {
+          assert_eq!(sample, expected as f32);
+          expected += 1;
+        }
+        None => std::thread::yield_now(),
+      }
+    }
+    producer.join().unwrap();
+  }
+}
@@ -0,0 +1,310 @@
+// Save states.
+//
+// A save state is a snapshot of the entire machine: CPU, PPU, APU, RAM, DMA
+// state, controllers and whatever the mapper keeps behind `Box<dyn Mapper>`.
+//
+// The format is a small header followed by every component's fields in a
+// fixed order, all little-endian:
+//
+// ```text
+// "NSST"         magic
+// u16            format version (`VERSION`)
+// u64            hash of the cart's PRG ROM, so we don't load a state into
+//                the wrong game
+// ...            component state, as written by each `SaveState` impl
+// ```
+//
+// There's no per-field tagging, so any change to what a component writes
+// (adding a field, changing a type, reordering) must bump `VERSION`. Old
+// states are rejected rather than loaded wrong.
+pub const MAGIC: [u8; 4] = *b"NSST";
+pub const VERSION: u16 = 11;
+
+pub trait SaveState {
+  fn save_state(&self, w: &mut StateWriter);
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    StateWriter { data: vec![] }
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    StateReader { data, pos: 0 }
+  }
+
+  pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+    if self.pos + len > self.data.len() {
+      return Err("Save state is truncated");
+    }
+    let bytes = &self.data[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pos >= self.data.len()
+  }
+}
+
+/// Writes the magic number, version and cart hash.
+pub fn write_header(w: &mut StateWriter, cart_hash: u64) {
+  w.write_bytes(&MAGIC);
+  VERSION.save_state(w);
+  cart_hash.save_state(w);
+}
+
+/// Checks the magic number, version and cart hash.
+pub fn read_header(r: &mut StateReader, cart_hash: u64) -> Result<(), &'static str> {
+  if r.read_bytes(MAGIC.len())? != MAGIC {
+    return Err("Not a save state");
+  }
+
+  let mut version: u16 = 0;
+  version.load_state(r)?;
+  if version != VERSION {
+    return Err("Save state was made by an incompatible version");
+  }
+
+  let mut hash: u64 = 0;
+  hash.load_state(r)?;
+  if hash != cart_hash {
+    return Err("Save state was made with a different cart");
+  }
+
+  Ok(())
+}
+
+/// FNV-1a; we only need something cheap to tell carts apart, not anything
+/// cryptographic.
+pub fn hash(data: &[u8]) -> u64 {
+  let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+  for byte in data {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x0100_0000_01b3);
+  }
+  hash
+}
+
+macro_rules! impl_save_state_for_number {
+  ($($t:ty),*) => {
+    $(
+      impl SaveState for $t {
+        fn save_state(&self, w: &mut StateWriter) {
+          w.write_bytes(&self.to_le_bytes());
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+          let mut bytes = [0x00; std::mem::size_of::<$t>()];
+          bytes.copy_from_slice(r.read_bytes(std::mem::size_of::<$t>())?);
+          *self = <$t>::from_le_bytes(bytes);
+          Ok(())
+        }
+      }
+    )*
+  };
+}
+
+impl_save_state_for_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+// `usize`/`isize` are always stored as 64 bits so states are portable between
+// 32-bit and 64-bit builds.
+impl SaveState for usize {
+  fn save_state(&self, w: &mut StateWriter) {
+    (*self as u64).save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut v: u64 = 0;
+    v.load_state(r)?;
+    *self = v as usize;
+    Ok(())
+  }
+}
+
+impl SaveState for isize {
+  fn save_state(&self, w: &mut StateWriter) {
+    (*self as i64).save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut v: i64 = 0;
+    v.load_state(r)?;
+    *self = v as isize;
+    Ok(())
+  }
+}
+
+impl SaveState for bool {
+  fn save_state(&self, w: &mut StateWriter) {
+    (*self as u8).save_state(w);
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut v: u8 = 0;
+    v.load_state(r)?;
+    *self = v != 0;
+    Ok(())
+  }
+}
+
+impl<T: SaveState, const N: usize> SaveState for [T; N] {
+  fn save_state(&self, w: &mut StateWriter) {
+    for item in self.iter() {
+      item.save_state(w);
+    }
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    for item in self.iter_mut() {
+      item.load_state(r)?;
+    }
+    Ok(())
+  }
+}
+
+impl<T: SaveState + Default> SaveState for Vec<T> {
+  fn save_state(&self, w: &mut StateWriter) {
+    self.len().save_state(w);
+    for item in self.iter() {
+      item.save_state(w);
+    }
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut len: usize = 0;
+    len.load_state(r)?;
+    self.clear();
+    for _ in 0..len {
+      let mut item = T::default();
+      item.load_state(r)?;
+      self.push(item);
+    }
+    Ok(())
+  }
+}
+
+impl<T: SaveState + Default> SaveState for Option<T> {
+  fn save_state(&self, w: &mut StateWriter) {
+    match self {
+      Some(v) => {
+        true.save_state(w);
+        v.save_state(w);
+      }
+      None => false.save_state(w),
+    }
+  }
+
+  fn load_state(&mut self, r: &mut StateReader) -> Result<(), &'static str> {
+    let mut some = false;
+    some.load_state(r)?;
+    *self = if some {
+      let mut v = T::default();
+      v.load_state(r)?;
+      Some(v)
+    } else {
+      None
+    };
+    Ok(())
+  }
+}
+
+/// Loads a `Vec<u8>` whose length is fixed by the hardware (RAM chips and the
+/// like); a state with a different length is corrupt or for another cart.
+pub fn load_fixed_len(buf: &mut Vec<u8>, r: &mut StateReader) -> Result<(), &'static str> {
+  let len = buf.len();
+  buf.load_state(r)?;
+  if buf.len() != len {
+    return Err("Save state has the wrong memory size");
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn roundtrip() {
+    let mut w = StateWriter::new();
+    write_header(&mut w, 42);
+    0x12u8.save_state(&mut w);
+    0x3456u16.save_state(&mut w);
+    (-2isize).save_state(&mut w);
+    1.5f32.save_state(&mut w);
+    true.save_state(&mut w);
+    [1u8, 2, 3].save_state(&mut w);
+    vec![4u16, 5].save_state(&mut w);
+    Some(6u8).save_state(&mut w);
+    let data = w.finish();
+
+    let mut r = StateReader::new(&data);
+    read_header(&mut r, 42).unwrap();
+    let mut a = 0u8;
+    let mut b = 0u16;
+    let mut c = 0isize;
+    let mut d = 0f32;
+    let mut e = false;
+    let mut f = [0u8; 3];
+    let mut g: Vec<u16> = vec![];
+    let mut h: Option<u8> = None;
+    a.load_state(&mut r).unwrap();
+    b.load_state(&mut r).unwrap();
+    c.load_state(&mut r).unwrap();
+    d.load_state(&mut r).unwrap();
+    e.load_state(&mut r).unwrap();
+    f.load_state(&mut r).unwrap();
+    g.load_state(&mut r).unwrap();
+    h.load_state(&mut r).unwrap();
+    assert_eq!(a, 0x12);
+    assert_eq!(b, 0x3456);
+    assert_eq!(c, -2);
+    assert_eq!(d, 1.5);
+    assert!(e);
+    assert_eq!(f, [1, 2, 3]);
+    assert_eq!(g, vec![4, 5]);
+    assert_eq!(h, Some(6));
+    assert!(r.is_empty());
+  }
+
+  #[test]
+  fn header_mismatch() {
+    let mut w = StateWriter::new();
+    write_header(&mut w, 42);
+    let data = w.finish();
+
+    assert_eq!(
+      read_header(&mut StateReader::new(&data), 43),
+      Err("Save state was made with a different cart")
+    );
+    assert_eq!(
+      read_header(&mut StateReader::new(&data[..5]), 42),
+      Err("Save state is truncated")
+    );
+    assert_eq!(
+      read_header(&mut StateReader::new(&[0x00; 14]), 42),
+      Err("Not a save state")
+    );
+  }
+}
@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::apu::ExpansionChip;
+
+// https://vgmrips.net/wiki/VGM_Specification
+//
+// A VGM file is a header followed by a stream of chip register writes and
+// waits. Waits are counted in 44.1kHz samples no matter what rate it gets
+// played back at. The NES APU arrived in version 1.61.
+const VERSION: u32 = 0x0000_0161;
+const HEADER_SIZE: u32 = 0xC0;
+const SAMPLE_RATE: u64 = 44_100;
+// The master clock, 21.477272 MHz, over 12; kept as a ratio so cycle counts
+// turn into samples without drifting:
+const MASTER_CLOCK: u64 = 21_477_272;
+const CPU_CLOCK_DIVIDER: u64 = 12;
+const CPU_CLOCK: u32 = (MASTER_CLOCK / CPU_CLOCK_DIVIDER) as u32;
+
+// The Sunsoft 5B is a YM2149, which VGM logs as one of the AY-3-8910 family.
+// Its squares run at a 32nd of the CPU clock where an AY-3-8910's run at a
+// 16th of its own, so that's half the CPU clock:
+const AY8910_CLOCK: u32 = CPU_CLOCK / 2;
+const AY8910_TYPE_YM2149: u8 = 0x10;
+const AY8910_FLAGS_DEFAULT: u8 = 0x01;
+
+const CMD_AY8910_WRITE: u8 = 0xA0;
+const CMD_NES_APU_WRITE: u8 = 0xB4;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_END: u8 = 0x66;
+const CMD_DATA_BLOCK: u8 = 0x67;
+const DATA_BLOCK_NES_RAM: u8 = 0xC2;
+
+/// Logs APU register writes to a VGM file, so the music can be played back
+/// without the game. See `Nes::start_vgm_log`.
+///
+/// The DMC plays samples straight out of the CPU's address space, which a VGM
+/// player doesn't have, so anything the DMC might read goes in the log too, as
+/// data blocks; `write_memory` only bothers when it's something the player
+/// hasn't seen yet.
+pub struct VgmLog {
+  file: BufWriter<File>,
+  /// Bytes written so far, header and all
+  len: u32,
+  /// CPU cycles since the log started
+  cycle: u64,
+  /// How much waiting has made it into the file, in samples
+  samples: u64,
+  /// What the player has been told is at $8000-$FFFF
+  memory: Vec<Option<u8>>,
+  /// Whether the header needs to mention the 5B
+  has_ay8910: bool,
+  /// Register writes come from the APU mid-cycle, with nowhere to report a
+  /// failure; once one happens, logging stops and `finish` returns it
+  error: Option<io::Error>,
+}
+
+impl VgmLog {
+  pub fn create<P: AsRef<Path>>(path: P) -> io::Result<VgmLog> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_header(&mut file, HEADER_SIZE, 0, false)?;
+    Ok(VgmLog {
+      file,
+      len: HEADER_SIZE,
+      cycle: 0,
+      samples: 0,
+      memory: vec![None; 0x8000],
+      has_ay8910: false,
+      error: None,
+    })
+  }
+
+  /// Moves on to the next CPU cycle.
+  pub fn clock(&mut self) {
+    self.cycle += 1;
+  }
+
+  /// Logs a CPU write to an APU register, as of the current cycle. Anything
+  /// that isn't an APU register is ignored.
+  pub fn write_register(&mut self, addr: u16, data: u8) {
+    let register = match addr {
+      // OAM DMA and the controllers share the space, but aren't sound:
+      0x4014 | 0x4016 => return,
+      0x4000..=0x401F => addr - 0x4000,
+      _ => return,
+    };
+    self.catch_up();
+    self.write(&[CMD_NES_APU_WRITE, register as u8, data]);
+  }
+
+  /// Logs a write to one of a cart sound chip's registers, as of the current
+  /// cycle. Only the 5B is logged so far; VGM has commands for some of the
+  /// others, but nothing emulates them yet.
+  pub fn write_expansion_register(&mut self, chip: ExpansionChip, register: u8, data: u8) {
+    if chip != ExpansionChip::Sunsoft5B {
+      return;
+    }
+    self.has_ay8910 = true;
+    self.catch_up();
+    self.write(&[CMD_AY8910_WRITE, register, data]);
+  }
+
+  /// Tells the player what's at `addr..`, for the DMC's sake, unless it
+  /// already knows.
+  pub fn write_memory(&mut self, addr: u16, data: &[u8]) {
+    if addr < 0x8000 || addr as usize + data.len() > 0x1_0000 {
+      return;
+    }
+    let known = &mut self.memory[(addr - 0x8000) as usize..][..data.len()];
+    if known.iter().zip(data).all(|(k, d)| *k == Some(*d)) {
+      return;
+    }
+    for (k, d) in known.iter_mut().zip(data) {
+      *k = Some(*d);
+    }
+
+    self.catch_up();
+    self.write(&[CMD_DATA_BLOCK, CMD_END, DATA_BLOCK_NES_RAM]);
+    self.write(&(data.len() as u32 + 2).to_le_bytes());
+    self.write(&addr.to_le_bytes());
+    self.write(data);
+  }
+
+  /// Finishes off the file. Errors from writing along the way come out here.
+  pub fn finish(mut self) -> io::Result<()> {
+    self.catch_up();
+    self.write(&[CMD_END]);
+    if let Some(e) = self.error {
+      return Err(e);
+    }
+
+    self.file.seek(SeekFrom::Start(0))?;
+    write_header(
+      &mut self.file,
+      self.len,
+      self.samples as u32,
+      self.has_ay8910,
+    )?;
+    self.file.flush()
+  }
+
+  /// Waits until the current cycle, give or take rounding to a whole sample.
+  fn catch_up(&mut self) {
+    let target = self.cycle * SAMPLE_RATE * CPU_CLOCK_DIVIDER / MASTER_CLOCK;
+    while self.samples < target {
+      let wait = (target - self.samples).min(u16::MAX as u64);
+      if wait == 735 {
+        self.write(&[CMD_WAIT_NTSC_FRAME]);
+      } else {
+        self.write(&[CMD_WAIT]);
+        self.write(&(wait as u16).to_le_bytes());
+      }
+      self.samples += wait;
+    }
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    if self.error.is_some() {
+      return;
+    }
+    match self.file.write_all(bytes) {
+      Ok(()) => self.len += bytes.len() as u32,
+      Err(e) => self.error = Some(e),
+    }
+  }
+}
+
+fn write_header<W: Write>(
+  w: &mut W,
+  len: u32,
+  total_samples: u32,
+  has_ay8910: bool,
+) -> io::Result<()> {
+  let mut header = [0u8; HEADER_SIZE as usize];
+  let mut put = |offset: usize, value: u32| {
+    header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+  };
+  put(0x00, u32::from_le_bytes(*b"Vgm "));
+  // Offsets in the header are relative to where they're stored:
+  put(0x04, len - 0x04);
+  put(0x08, VERSION);
+  put(0x18, total_samples);
+  put(0x24, 60);
+  put(0x34, HEADER_SIZE - 0x34);
+  put(0x84, CPU_CLOCK);
+  if has_ay8910 {
+    put(0x74, AY8910_CLOCK);
+    header[0x78] = AY8910_TYPE_YM2149;
+    header[0x79] = AY8910_FLAGS_DEFAULT;
+  }
+  w.write_all(&header)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn writes_and_waits() {
+    let path = std::env::temp_dir().join(format!("nessers-vgm-{}.vgm", std::process::id()));
+    let mut log = VgmLog::create(&path).unwrap();
+    log.write_register(0x4015, 0x0F);
+    log.write_memory(0xC000, &[0xAA, 0x55]);
+    // Already there, so this is a no-op:
+    log.write_memory(0xC001, &[0x55]);
+    // One frame's worth of cycles, near enough:
+    for _ in 0..29_830 {
+      log.clock();
+    }
+    log.write_register(0x4000, 0xBF);
+    // Not the APU's:
+    log.write_register(0x4016, 0x01);
+    log.finish().unwrap();
+
+    let vgm = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&vgm[0..4], b"Vgm ");
+    assert_eq!(&vgm[0x04..0x08], &(vgm.len() as u32 - 4).to_le_bytes());
+    assert_eq!(&vgm[0x18..0x1C], &735u32.to_le_bytes());
+    assert_eq!(&vgm[0x84..0x88], &1_789_772u32.to_le_bytes());
+    assert_eq!(
+      &vgm[HEADER_SIZE as usize..],
+      &[
+        0xB4, 0x15, 0x0F, // $4015
+        0x67, 0x66, 0xC2, 0x04, 0x00, 0x00, 0x00, 0x00, 0xC0, 0xAA, 0x55, // DMC data
+        0x62, // A frame
+        0xB4, 0x00, 0xBF, // $4000
+        0x66,
+      ]
+    );
+    // No 5B:
+    assert_eq!(&vgm[0x74..0x78], &[0x00; 4]);
+  }
+
+  #[test]
+  fn sunsoft_5b() {
+    let path = std::env::temp_dir().join(format!("nessers-vgm-5b-{}.vgm", std::process::id()));
+    let mut log = VgmLog::create(&path).unwrap();
+    log.write_expansion_register(ExpansionChip::Sunsoft5B, 0x07, 0x38);
+    // Not logged:
+    log.write_expansion_register(ExpansionChip::Vrc6, 0x00, 0xFF);
+    log.finish().unwrap();
+
+    let vgm = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&vgm[0x74..0x78], &894_886u32.to_le_bytes());
+    assert_eq!(vgm[0x78], 0x10);
+    assert_eq!(&vgm[HEADER_SIZE as usize..], &[0xA0, 0x07, 0x38, 0x66]);
+  }
+}
@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// How samples are stored in a WAV file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+  /// 16-bit signed integers; anything outside of -1.0..=1.0 is clipped
+  Pcm16,
+  /// 32-bit floats, exactly as the APU produced them
+  Float32,
+}
+
+impl SampleFormat {
+  pub const ALL: [SampleFormat; 2] = [SampleFormat::Pcm16, SampleFormat::Float32];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      SampleFormat::Pcm16 => "pcm16",
+      SampleFormat::Float32 => "float32",
+    }
+  }
+
+  fn bytes_per_sample(&self) -> u32 {
+    match self {
+      SampleFormat::Pcm16 => 2,
+      SampleFormat::Float32 => 4,
+    }
+  }
+}
+
+impl std::str::FromStr for SampleFormat {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    SampleFormat::ALL
+      .iter()
+      .find(|format| format.name() == name.to_lowercase())
+      .copied()
+      .ok_or_else(|| format!("Unknown sample format \"{}\"; try pcm16 or float32", name))
+  }
+}
+
+/// Minimal streaming writer for mono WAV files.
+///
+/// The RIFF header needs to know how much data follows it, so we write a
+/// placeholder up-front and patch it in `finish`.
+///
+/// Samples arrive one at a time from deep inside the emulator, where there's
+/// nothing sensible to do with an error, so a failed write is kept for
+/// `finish` to return, and anything after it is dropped.
+pub struct WavWriter {
+  file: BufWriter<File>,
+  sample_rate: u32,
+  format: SampleFormat,
+  num_samples: u32,
+  error: Option<io::Error>,
+}
+
+impl WavWriter {
+  pub fn create<P: AsRef<Path>>(
+    path: P,
+    sample_rate: u32,
+    format: SampleFormat,
+  ) -> io::Result<WavWriter> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_header(&mut file, sample_rate, format, 0)?;
+    Ok(WavWriter {
+      file,
+      sample_rate,
+      format,
+      num_samples: 0,
+      error: None,
+    })
+  }
+
+  pub fn write_sample(&mut self, sample: f32) {
+    if self.error.is_some() {
+      return;
+    }
+
+    let result = match self.format {
+      SampleFormat::Pcm16 => {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.file.write_all(&sample.to_le_bytes())
+      }
+      SampleFormat::Float32 => self.file.write_all(&sample.to_le_bytes()),
+    };
+    match result {
+      Ok(()) => self.num_samples += 1,
+      Err(e) => self.error = Some(e),
+    }
+  }
+
+  pub fn finish(mut self) -> io::Result<()> {
+    if let Some(e) = self.error.take() {
+      return Err(e);
+    }
+    self.file.seek(SeekFrom::Start(0))?;
+    write_header(
+      &mut self.file,
+      self.sample_rate,
+      self.format,
+      self.num_samples,
+    )?;
+    self.file.flush()
+  }
+}
+
+fn write_header<W: Write>(
+  w: &mut W,
+  sample_rate: u32,
+  format: SampleFormat,
+  num_samples: u32,
+) -> io::Result<()> {
+  let bytes_per_sample = format.bytes_per_sample();
+  let data_size = num_samples * bytes_per_sample;
+  // Anything that isn't integer PCM needs the extension size in its "fmt "
+  // chunk, and a "fact" chunk after it:
+  let (format_tag, fmt_size, fact_size): (u16, u32, u32) = match format {
+    SampleFormat::Pcm16 => (1, 16, 0),
+    SampleFormat::Float32 => (3, 18, 12),
+  };
+
+  w.write_all(b"RIFF")?;
+  w.write_all(&(4 + (8 + fmt_size) + fact_size + (8 + data_size)).to_le_bytes())?;
+  w.write_all(b"WAVE")?;
+
+  w.write_all(b"fmt ")?;
+  w.write_all(&fmt_size.to_le_bytes())?;
+  w.write_all(&format_tag.to_le_bytes())?;
+  // Mono
+  w.write_all(&1u16.to_le_bytes())?;
+  w.write_all(&sample_rate.to_le_bytes())?;
+  // Byte rate
+  w.write_all(&(sample_rate * bytes_per_sample).to_le_bytes())?;
+  // Block align
+  w.write_all(&(bytes_per_sample as u16).to_le_bytes())?;
+  // Bits per sample
+  w.write_all(&((bytes_per_sample * 8) as u16).to_le_bytes())?;
+
+  if fact_size > 0 {
+    // No extension:
+    w.write_all(&0u16.to_le_bytes())?;
+
+    w.write_all(b"fact")?;
+    w.write_all(&4u32.to_le_bytes())?;
+    w.write_all(&num_samples.to_le_bytes())?;
+  }
+
+  w.write_all(b"data")?;
+  w.write_all(&data_size.to_le_bytes())?;
+  Ok(())
+}
@@ -0,0 +1,166 @@
+// Runs every test ROM listed in `blargg.txt` and checks it against the outcome
+// we expect; see that file for the format.
+//
+// This has its own `main` (`harness = false` in Cargo.toml) so that each ROM
+// shows up as its own case, the same way `#[test]`s do. Pass a substring to
+// only run matching ROMs:
+//
+// ```text
+// cargo test --test blargg -- ppu_vbl_nmi
+// ```
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nessers::blargg::{self, Status};
+use nessers::{Nes, Palette};
+
+const DEFAULT_FRAMES: u32 = 2 * 60 * 60;
+
+struct Entry {
+  rom: String,
+  expect_pass: bool,
+  frames: u32,
+}
+
+enum Outcome {
+  Ok,
+  Failed(String),
+  Ignored,
+}
+
+fn parse_manifest(manifest: &str) -> Vec<Entry> {
+  let mut entries = vec![];
+  for (line_idx, line) in manifest.lines().enumerate() {
+    let line = match line.find('#') {
+      Some(idx) => &line[..idx],
+      None => line,
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+      continue;
+    }
+
+    let line_num = line_idx + 1;
+    let expect_pass = match words.get(1) {
+      Some(&"pass") => true,
+      Some(&"fail") => false,
+      _ => bad_line(line_num, line),
+    };
+    let frames = match words.get(2) {
+      Some(frames) => frames.parse().unwrap_or_else(|_| bad_line(line_num, line)),
+      None => DEFAULT_FRAMES,
+    };
+    if words.len() > 3 {
+      bad_line(line_num, line);
+    }
+
+    entries.push(Entry {
+      rom: words[0].to_string(),
+      expect_pass,
+      frames,
+    });
+  }
+  entries
+}
+
+fn bad_line(line_num: usize, line: &str) -> ! {
+  panic!("Can't parse blargg.txt line {}: {}", line_num, line.trim())
+}
+
+fn run_entry(rom_dir: &Path, require_roms: bool, entry: &Entry) -> Outcome {
+  let path = rom_dir.join(&entry.rom);
+  let rom = match fs::read(&path) {
+    Ok(rom) => rom,
+    Err(e) if require_roms => {
+      return Outcome::Failed(format!("Failed to read {}: {}", path.display(), e))
+    }
+    Err(_) => return Outcome::Ignored,
+  };
+
+  let mut nes = match Nes::from_rom(44_100.0, &rom, Palette::new()) {
+    Ok(nes) => nes,
+    Err(msg) => return Outcome::Failed(format!("Failed to load ROM: {}", msg)),
+  };
+  nes.reset();
+
+  let result = blargg::run(&mut nes, entry.frames);
+  match (result.status, entry.expect_pass) {
+    (Status::Passed, true) => Outcome::Ok,
+    (Status::Passed, false) => Outcome::Failed(
+      "Passed, but is expected to fail; update blargg.txt if this is a fix".to_string(),
+    ),
+    (Status::Failed(_), false) | (Status::TimedOut, false) => Outcome::Ok,
+    (Status::Failed(code), true) => Outcome::Failed(format!(
+      "Failed with result code {}:\n{}",
+      code, result.message
+    )),
+    (Status::TimedOut, true) => Outcome::Failed(format!(
+      "No result after {} frames:\n{}",
+      entry.frames, result.message
+    )),
+  }
+}
+
+fn main() {
+  let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let rom_dir = match env::var_os("NESSERS_TEST_ROMS") {
+    Some(dir) => PathBuf::from(dir),
+    None => manifest_dir.join("tests/roms"),
+  };
+  let require_roms = env::var_os("NESSERS_REQUIRE_TEST_ROMS").is_some();
+  let manifest = fs::read_to_string(manifest_dir.join("tests/blargg.txt")).unwrap();
+
+  // Anything that isn't a flag from libtest's usual set is a filter:
+  let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+  let entries: Vec<Entry> = parse_manifest(&manifest)
+    .into_iter()
+    .filter(|e| match &filter {
+      Some(filter) => e.rom.contains(filter.as_str()),
+      None => true,
+    })
+    .collect();
+
+  println!("\nrunning {} tests", entries.len());
+
+  let mut passed = 0;
+  let mut ignored = 0;
+  let mut failures = vec![];
+  for entry in entries.iter() {
+    match run_entry(&rom_dir, require_roms, entry) {
+      Outcome::Ok => {
+        println!("test {} ... ok", entry.rom);
+        passed += 1;
+      }
+      Outcome::Ignored => {
+        println!("test {} ... ignored, ROM not found", entry.rom);
+        ignored += 1;
+      }
+      Outcome::Failed(msg) => {
+        println!("test {} ... FAILED", entry.rom);
+        failures.push((&entry.rom, msg));
+      }
+    }
+  }
+
+  if !failures.is_empty() {
+    println!("\nfailures:\n");
+    for (rom, msg) in failures.iter() {
+      println!("---- {} ----\n{}\n", rom, msg);
+    }
+  }
+
+  println!(
+    "\ntest result: {}. {} passed; {} failed; {} ignored\n",
+    if failures.is_empty() { "ok" } else { "FAILED" },
+    passed,
+    failures.len(),
+    ignored
+  );
+
+  if !failures.is_empty() {
+    process::exit(1);
+  }
+}